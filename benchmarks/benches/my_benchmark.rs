@@ -79,7 +79,7 @@ fn bench_pickle_eav_one_to_many(b: &mut Bencher) {
     b.iter(|| {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_one_to_many::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -92,7 +92,7 @@ fn bench_pickle_eav_many_to_one(b: &mut Bencher) {
     b.iter(|| {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_many_to_one::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -4,10 +4,15 @@
 //! A test suite for CAS is also implemented here.
 
 use crate::{
-    cas::content::{Address, AddressableContent, Content, ExampleAddressableContent},
+    cas::{
+        content::{Address, AddressableContent, Content, ExampleAddressableContent},
+        ttl::{expires_at, is_expired, MockTimeSource, SystemTimeSource, TimeSource},
+    },
     eav::{
-        Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
-        IndexFilter,
+        Attribute, Binding, Bindings, EavFilter, EaviQuery, EaviQuerySpec,
+        EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute, FilterSpec,
+        IndexFilter, JoinQuery, LiveChange, LiveEavStorage, Order, Pattern, RetractionFilter, Term,
+        Traversal,
     },
     error::{PersistenceError, PersistenceResult},
     holochain_json_api::{
@@ -19,10 +24,11 @@ use crate::{
 };
 use objekt;
 use std::{
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     convert::{TryFrom, TryInto},
     fmt::{self, Debug},
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 use uuid::Uuid;
 
@@ -40,8 +46,36 @@ pub trait ContentAddressableStorage: objekt::Clone + Send + Sync + Debug + Repor
     /// AddressableContent::from_content() can be used to allow the compiler to infer the type
     /// @see the fetch implementation for ExampleCas in the cas module tests
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>>;
+    /// adds AddressableContent that expires once the given time-to-live has elapsed
+    /// content that is already stored without a time-to-live keeps it that way
+    /// adding content again with add() removes its time-to-live
+    /// expired content stays visible until it is removed by sweep_expired()
+    fn add_with_ttl(
+        &mut self,
+        _content: &dyn AddressableContent,
+        _ttl: Duration,
+    ) -> PersistenceResult<()> {
+        Err(PersistenceError::ErrorGeneric(
+            "TTL not implemented for this storage type".into(),
+        ))
+    }
+    /// removes all content whose time-to-live has elapsed and returns how many were removed
+    /// content stored without a time-to-live is never touched
+    /// @see cas::ttl::TtlSweeper to run this periodically
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        Ok(0)
+    }
+    /// the addresses of all stored content, including expired content that is not swept yet
+    /// @see diff to compare them with another storage's
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        Err(PersistenceError::ErrorGeneric(
+            "listing addresses is not implemented for this storage type".into(),
+        ))
+    }
     //needed to find a way to compare two different CAS for partialord derives.
     //easiest solution was to just compare two ids which are based on uuids
+    /// the id must be the same for every handle on the same stored content
+    /// persistent backends keep it with their data so it also survives reopening
     fn get_id(&self) -> Uuid;
 }
 
@@ -58,15 +92,28 @@ impl PartialEq for dyn ContentAddressableStorage {
 /// this is a thread-safe wrapper around the non-thread-safe implementation below
 /// @see ExampleContentAddressableStorageActor
 pub struct ExampleContentAddressableStorage {
+    id: Uuid,
     content: Arc<RwLock<ExampleContentAddressableStorageContent>>,
+    time_source: Arc<dyn TimeSource>,
 }
 
 impl ExampleContentAddressableStorage {
     pub fn new() -> Result<ExampleContentAddressableStorage, JsonError> {
         Ok(ExampleContentAddressableStorage {
+            id: Uuid::new_v4(),
             content: Arc::new(RwLock::new(ExampleContentAddressableStorageContent::new())),
+            time_source: Arc::new(SystemTimeSource),
         })
     }
+
+    /// replaces the time source used to expire content added with a ttl
+    pub fn with_time_source(
+        mut self,
+        time_source: Arc<dyn TimeSource>,
+    ) -> ExampleContentAddressableStorage {
+        self.time_source = time_source;
+        self
+    }
 }
 
 pub fn test_content_addressable_storage() -> ExampleContentAddressableStorage {
@@ -97,8 +144,34 @@ impl ContentAddressableStorage for ExampleContentAddressableStorage {
         Ok(self.content.read()?.unthreadable_fetch(address)?)
     }
 
+    fn add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> PersistenceResult<()> {
+        let expiry = expires_at(&*self.time_source, ttl);
+        self.content.write()?.unthreadable_add_with_expiry(
+            &content.address(),
+            &content.content(),
+            expiry,
+        )?;
+        Ok(())
+    }
+
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        let time_source = self.time_source.clone();
+        Ok(self
+            .content
+            .write()?
+            .unthreadable_sweep(|expiry| is_expired(&*time_source, expiry))?)
+    }
+
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        Ok(self.content.read()?.storage.keys().cloned().collect())
+    }
+
     fn get_id(&self) -> Uuid {
-        Uuid::new_v4()
+        self.id
     }
 }
 
@@ -108,6 +181,7 @@ impl ReportStorage for ExampleContentAddressableStorage {}
 /// Not thread-safe CAS implementation with a HashMap
 pub struct ExampleContentAddressableStorageContent {
     storage: HashMap<Address, Content>,
+    expiries: HashMap<Address, SystemTime>,
 }
 
 impl ExampleContentAddressableStorageContent {
@@ -117,9 +191,42 @@ impl ExampleContentAddressableStorageContent {
 
     fn unthreadable_add(&mut self, address: &Address, content: &Content) -> Result<(), JsonError> {
         self.storage.insert(address.clone(), content.clone());
+        self.expiries.remove(address);
+        Ok(())
+    }
+
+    fn unthreadable_add_with_expiry(
+        &mut self,
+        address: &Address,
+        content: &Content,
+        expiry: SystemTime,
+    ) -> Result<(), JsonError> {
+        if self.storage.contains_key(address) && !self.expiries.contains_key(address) {
+            // already stored without a ttl so it must not expire
+            return Ok(());
+        }
+        self.storage.insert(address.clone(), content.clone());
+        self.expiries.insert(address.clone(), expiry);
         Ok(())
     }
 
+    fn unthreadable_sweep<F>(&mut self, expired: F) -> Result<usize, JsonError>
+    where
+        F: Fn(SystemTime) -> bool,
+    {
+        let expired_addresses: Vec<Address> = self
+            .expiries
+            .iter()
+            .filter(|(_, expiry)| expired(**expiry))
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in expired_addresses.iter() {
+            self.storage.remove(address);
+            self.expiries.remove(address);
+        }
+        Ok(expired_addresses.len())
+    }
+
     fn unthreadable_contains(&self, address: &Address) -> Result<bool, JsonError> {
         Ok(self.storage.contains_key(address))
     }
@@ -247,6 +354,115 @@ where
         handle.join().unwrap();
         */
     }
+
+    /// shows that content added with a ttl is swept once expired and other content is kept
+    /// the storage under test must have been built with the given time source
+    pub fn ttl_test(mut self, time_source: MockTimeSource, content: Content, cached: Content) {
+        let both_cas = [self.cas.clone(), self.cas_clone.clone()];
+
+        assert_eq!(Ok(()), self.cas.add(&content));
+        assert_eq!(
+            Ok(()),
+            self.cas.add_with_ttl(&cached, Duration::from_secs(10))
+        );
+        // content that is already stored without a ttl must not start expiring
+        assert_eq!(
+            Ok(()),
+            self.cas_clone
+                .add_with_ttl(&content, Duration::from_secs(1))
+        );
+
+        time_source.advance(Duration::from_secs(5));
+        assert_eq!(Ok(0), self.cas.sweep_expired());
+        for cas in both_cas.iter() {
+            assert_eq!(Ok(true), cas.contains(&content.address()));
+            assert_eq!(Ok(Some(cached.clone())), cas.fetch(&cached.address()));
+        }
+
+        time_source.advance(Duration::from_secs(5));
+        assert_eq!(Ok(1), self.cas_clone.sweep_expired());
+        assert_eq!(Ok(0), self.cas.sweep_expired());
+        for cas in both_cas.iter() {
+            assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+            assert_eq!(Ok(false), cas.contains(&cached.address()));
+            assert_eq!(Ok(None), cas.fetch(&cached.address()));
+        }
+
+        // adding without a ttl removes an earlier ttl
+        assert_eq!(
+            Ok(()),
+            self.cas.add_with_ttl(&cached, Duration::from_secs(10))
+        );
+        assert_eq!(Ok(()), self.cas.add(&cached));
+        time_source.advance(Duration::from_secs(20));
+        assert_eq!(Ok(0), self.cas.sweep_expired());
+        for cas in both_cas.iter() {
+            assert_eq!(Ok(Some(cached.clone())), cas.fetch(&cached.address()));
+        }
+    }
+
+    /// shows that every handle on the same content reports the same id and compares equal
+    /// reopened must be a handle opened separately on the same content, e.g. from the same path
+    pub fn id_test(self, reopened: T) {
+        let id = self.cas.get_id();
+        assert_eq!(id, self.cas_clone.get_id());
+        assert_eq!(id, self.cas.get_id());
+        assert_eq!(id, reopened.get_id());
+
+        let cas: &dyn ContentAddressableStorage = &self.cas;
+        let reopened: &dyn ContentAddressableStorage = &reopened;
+        assert!(cas == reopened);
+    }
+
+    /// shows that the addresses of all content are listed, by every clone
+    pub fn addresses_test(mut self, content: Content, other_content: Content) {
+        assert_eq!(Ok(BTreeSet::new()), self.cas.addresses());
+        assert_eq!(Ok(()), self.cas.add(&content));
+        assert_eq!(
+            Ok(()),
+            self.cas
+                .add_with_ttl(&other_content, Duration::from_secs(60))
+        );
+        // adding content again lists it once
+        assert_eq!(Ok(()), self.cas_clone.add(&content));
+
+        let expected: BTreeSet<Address> = vec![content.address(), other_content.address()]
+            .into_iter()
+            .collect();
+        assert_eq!(Ok(expected.clone()), self.cas.addresses());
+        assert_eq!(Ok(expected), self.cas_clone.addresses());
+    }
+
+    /// shows that a read-only handle sees what was written and refuses to write
+    /// every writable handle is dropped before the read-only one is opened
+    pub fn read_only_test<R, F>(mut self, content: Content, other_content: Content, open: F)
+    where
+        R: ContentAddressableStorage,
+        F: FnOnce() -> R,
+    {
+        assert_eq!(Ok(()), self.cas.add(&content));
+        drop(self);
+
+        let mut read_only = open();
+        assert_eq!(
+            Ok(Some(content.clone())),
+            read_only.fetch(&content.address())
+        );
+
+        for result in [
+            read_only.add(&other_content),
+            read_only.add_with_ttl(&other_content, Duration::from_secs(1)),
+            read_only.sweep_expired().map(|_| ()),
+        ]
+        .iter()
+        {
+            match result {
+                Err(PersistenceError::ReadOnlyError(_)) => (),
+                other => panic!("expected a read-only error, got {:?}", other),
+            }
+        }
+        assert_eq!(Ok(false), read_only.contains(&other_content.address()));
+    }
 }
 
 pub struct EavTestSuite;
@@ -398,6 +614,1189 @@ impl EavTestSuite {
             }
         }
     }
+    /// shows that handles on the same rows report the same id and compare equal as trait
+    /// objects, whatever rows other storages hold
+    /// reopened must be a handle opened separately on the same rows, e.g. from the same path
+    pub fn test_id<S>(mut eav_storage: S, reopened: S, mut other: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute> + 'static,
+    {
+        assert_eq!(eav_storage.get_id(), reopened.get_id());
+        assert_ne!(eav_storage.get_id(), other.get_id());
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &Content::from(RawString::from("entity")).address(),
+            &ExampleAttribute::default(),
+            &Content::from(RawString::from("value")).address(),
+            1,
+        )
+        .unwrap();
+        eav_storage.add_eavi(&eavi).expect("could not add eav");
+        other.add_eavi(&eavi).expect("could not add eav");
+
+        let eav: &dyn EntityAttributeValueStorage<ExampleAttribute> = &eav_storage;
+        let reopened: &dyn EntityAttributeValueStorage<ExampleAttribute> = &reopened;
+        let other: &dyn EntityAttributeValueStorage<ExampleAttribute> = &other;
+        assert!(eav == reopened);
+        assert!(eav != other);
+    }
+
+    /// shows that a read-only handle sees what was written and refuses to write
+    /// the writable handle is dropped before the read-only one is opened
+    pub fn test_read_only<S, R, F>(mut eav_storage: S, open: F)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+        R: EntityAttributeValueStorage<ExampleAttribute>,
+        F: FnOnce() -> R,
+    {
+        let entity = Content::from(RawString::from("entity")).address();
+        let value = Content::from(RawString::from("value")).address();
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &entity,
+            &ExampleAttribute::default(),
+            &value,
+            1,
+        )
+        .unwrap();
+        eav_storage.add_eavi(&eavi).expect("could not add eav");
+        drop(eav_storage);
+
+        let mut read_only = open();
+        let expected: BTreeSet<_> = vec![eavi.clone()].into_iter().collect();
+        assert_eq!(
+            Ok(expected.clone()),
+            read_only.fetch_eavi(&EaviQuery::default())
+        );
+
+        let other = EntityAttributeValueIndex::new_with_index(
+            &value,
+            &ExampleAttribute::default(),
+            &entity,
+            2,
+        )
+        .unwrap();
+        match read_only.add_eavi(&other) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+        assert_eq!(Ok(expected), read_only.fetch_eavi(&EaviQuery::default()));
+    }
+
+    /// retracts rows and checks queries hide, include or only return them as asked
+    pub fn test_retraction<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let entity = Content::from(RawString::from("entity")).address();
+        let value = Content::from(RawString::from("value")).address();
+        let other_value = Content::from(RawString::from("other value")).address();
+        let mut add = |value: &Address, index: i64| {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &entity,
+                &ExampleAttribute::default(),
+                value,
+                index,
+            )
+            .unwrap();
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let first = add(&value, 1);
+        let other = add(&other_value, 2);
+        let fetch = |storage: &S, retraction: RetractionFilter| {
+            let query = EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_retraction(retraction);
+            storage.fetch_eavi(&query).expect("could not fetch eavs")
+        };
+        let set = |eavis: Vec<&EntityAttributeValueIndex<ExampleAttribute>>| {
+            eavis.into_iter().cloned().collect::<BTreeSet<_>>()
+        };
+
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&first));
+        // retracting twice, or retracting what was never added, changes nothing
+        assert_eq!(Ok(false), eav_storage.retract_eavi(&first));
+        let never_added = EntityAttributeValueIndex::new_with_index(
+            &entity,
+            &ExampleAttribute::default(),
+            &value,
+            99,
+        )
+        .unwrap();
+        assert_eq!(Ok(false), eav_storage.retract_eavi(&never_added));
+
+        assert_eq!(
+            set(vec![&other]),
+            fetch(&eav_storage, RetractionFilter::Hide)
+        );
+        assert_eq!(
+            set(vec![&first, &other]),
+            fetch(&eav_storage, RetractionFilter::Include)
+        );
+        assert_eq!(
+            set(vec![&first]),
+            fetch(&eav_storage, RetractionFilter::Only)
+        );
+        // the default query hides retracted rows too
+        assert_eq!(
+            Ok(set(vec![&other])),
+            eav_storage.fetch_eavi(&EaviQuery::default())
+        );
+
+        // adding the same link again gives a new row that is not retracted
+        let again = eav_storage
+            .add_eavi(
+                &EntityAttributeValueIndex::new_with_index(
+                    &entity,
+                    &ExampleAttribute::default(),
+                    &value,
+                    3,
+                )
+                .unwrap(),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            set(vec![&other, &again]),
+            fetch(&eav_storage, RetractionFilter::Hide)
+        );
+    }
+
+    /// pages through rows in both orders, with and without offsets, and checks the pages join
+    /// up to all the matching rows
+    pub fn test_pagination<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let entity = Content::from(RawString::from("entity")).address();
+        let other_entity = Content::from(RawString::from("other entity")).address();
+        let other_attribute = ExampleAttribute::WithPayload("other".to_string());
+        let mut add = |entity: &Address, attribute: &ExampleAttribute, index: i64| {
+            let value = Content::from(RawString::from(format!("value {}", index))).address();
+            let eavi = EntityAttributeValueIndex::new_with_index(entity, attribute, &value, index)
+                .unwrap();
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let mut rows: Vec<_> = (0..10)
+            .map(|index| add(&entity, &ExampleAttribute::default(), index))
+            .collect();
+        let others: Vec<_> = (0..3)
+            .map(|index| add(&other_entity, &other_attribute, 100 + index))
+            .collect();
+        rows.extend(others.iter().cloned());
+        rows.push(add(&entity, &other_attribute, 200));
+
+        let query = |entity: Option<&Address>, attribute: Option<&ExampleAttribute>| {
+            EaviQuery::new(
+                entity.cloned().into(),
+                attribute.cloned().into(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+        };
+        let shapes = [
+            (Some(&entity), Some(ExampleAttribute::default())),
+            (Some(&entity), None),
+            (None, Some(other_attribute.clone())),
+            (None, None),
+        ];
+        for (shape_entity, shape_attribute) in shapes.iter() {
+            let mut expected: Vec<_> = rows
+                .iter()
+                .filter(|eavi| shape_entity.map(|e| &eavi.entity() == e).unwrap_or(true))
+                .filter(|eavi| {
+                    shape_attribute
+                        .as_ref()
+                        .map(|a| &eavi.attribute() == a)
+                        .unwrap_or(true)
+                })
+                .cloned()
+                .collect();
+            expected.sort();
+            for order in [Order::Ascending, Order::Descending].iter() {
+                if *order == Order::Descending {
+                    expected.reverse();
+                }
+                for limit in [1, 3, 4, 20].iter() {
+                    let mut paged = Vec::new();
+                    let mut cursor = None;
+                    loop {
+                        let mut page_query = query(*shape_entity, shape_attribute.as_ref())
+                            .with_order(*order)
+                            .with_limit(*limit);
+                        if let Some(cursor) = cursor.take() {
+                            page_query = page_query.after(cursor);
+                        }
+                        let page = eav_storage
+                            .fetch_eavi_page(&page_query)
+                            .expect("could not fetch page");
+                        assert!(page.rows.len() <= *limit);
+                        paged.extend(page.rows);
+                        match page.next {
+                            Some(next) => cursor = Some(next),
+                            None => break,
+                        }
+                    }
+                    assert_eq!(expected, paged);
+                }
+            }
+        }
+
+        // an offset skips rows, counted from the cursor when there is one
+        let in_order: Vec<_> = rows[..10].to_vec();
+        let page = eav_storage
+            .fetch_eavi_page(
+                &query(Some(&entity), Some(&ExampleAttribute::default()))
+                    .with_offset(2)
+                    .with_limit(3),
+            )
+            .unwrap();
+        assert_eq!(in_order[2..5].to_vec(), page.rows);
+        let page = eav_storage
+            .fetch_eavi_page(
+                &query(Some(&entity), Some(&ExampleAttribute::default()))
+                    .after(page.next.expect("more rows follow"))
+                    .with_offset(1)
+                    .with_limit(3),
+            )
+            .unwrap();
+        assert_eq!(in_order[6..9].to_vec(), page.rows);
+
+        // fetch_eavi gives the rows of the page too
+        assert_eq!(
+            Ok(in_order[7..].iter().cloned().collect::<BTreeSet<_>>()),
+            eav_storage.fetch_eavi(
+                &query(Some(&entity), Some(&ExampleAttribute::default()))
+                    .with_order(Order::Descending)
+                    .with_limit(3)
+            )
+        );
+
+        // LatestByAttribute is applied before paging
+        let latest = EaviQuery::new(
+            Some(other_entity.clone()).into(),
+            EavFilter::default(),
+            EavFilter::default(),
+            IndexFilter::LatestByAttribute,
+            None,
+        )
+        .with_order(Order::Descending)
+        .with_limit(2);
+        let page = eav_storage.fetch_eavi_page(&latest).unwrap();
+        assert_eq!(vec![others[2].clone(), others[1].clone()], page.rows);
+        assert!(page.next.is_some());
+    }
+
+    /// checks fetch_eavi_iter gives the rows fetch_eavi does, whether it streams them or not
+    pub fn test_fetch_iter<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let address = |name: String| Content::from(RawString::from(name)).address();
+        let other_attribute = ExampleAttribute::WithPayload("other".to_string());
+        let mut rows = Vec::new();
+        for index in 0..30 {
+            let attribute = if index % 2 == 0 {
+                ExampleAttribute::default()
+            } else {
+                other_attribute.clone()
+            };
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &address(format!("entity {}", index % 3)),
+                &attribute,
+                &address(format!("value {}", index % 5)),
+                index,
+            )
+            .unwrap();
+            rows.push(
+                eav_storage
+                    .add_eavi(&eavi)
+                    .expect("could not add eav")
+                    .expect("could not get eavi option"),
+            );
+        }
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&rows[4]));
+
+        let range = |entity: Option<Address>,
+                     attribute: Option<ExampleAttribute>,
+                     value: Option<Address>,
+                     index: IndexFilter| {
+            EaviQuery::new(entity.into(), attribute.into(), value.into(), index, None)
+        };
+        let all = IndexFilter::Range(None, None);
+        let queries = vec![
+            range(None, None, None, all.clone()),
+            range(Some(address("entity 1".into())), None, None, all.clone()),
+            range(None, Some(other_attribute.clone()), None, all.clone()),
+            range(None, None, Some(address("value 2".into())), all.clone()),
+            range(
+                Some(address("entity 0".into())),
+                Some(ExampleAttribute::default()),
+                None,
+                all.clone(),
+            ),
+            range(None, None, None, IndexFilter::Range(Some(3), Some(20)))
+                .with_retraction(RetractionFilter::Include),
+            range(None, None, None, all.clone()).with_retraction(RetractionFilter::Only),
+            range(None, None, None, all.clone()).after(
+                eav_storage
+                    .fetch_eavi_page(&range(None, None, None, all.clone()).with_limit(10))
+                    .unwrap()
+                    .next
+                    .expect("more rows follow"),
+            ),
+            // these can't be streamed
+            range(None, None, None, IndexFilter::LatestByAttribute),
+            range(None, None, None, all.clone())
+                .with_order(Order::Descending)
+                .with_limit(4),
+        ];
+        for query in queries.iter() {
+            let iterated = eav_storage
+                .fetch_eavi_iter(query)
+                .expect("could not fetch eavs")
+                .collect::<PersistenceResult<Vec<_>>>()
+                .expect("could not read eavs");
+            if query.can_stream() {
+                let fetched = eav_storage.fetch_eavi(query).expect("could not fetch eavs");
+                assert!(!fetched.is_empty());
+                assert_eq!(fetched.len(), iterated.len());
+                assert_eq!(fetched, iterated.into_iter().collect());
+            } else {
+                assert_eq!(eav_storage.fetch_eavi_page(query).unwrap().rows, iterated);
+            }
+        }
+    }
+
+    /// checks prefix and range filters on entities and values select the same rows as checking
+    /// each row, including entities that are prefixes of others
+    pub fn test_key_filters<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let entities = ["aa", "aa0", "aa1", "aa:", "ab1", "b1", "b2"];
+        let values = ["va", "vb1", "vb2", "w"];
+        let attributes = [
+            ExampleAttribute::default(),
+            ExampleAttribute::WithPayload("link".to_string()),
+        ];
+        let mut rows = Vec::new();
+        for index in 0..28 {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &entities[index as usize % entities.len()].into(),
+                &attributes[index as usize % 2],
+                &values[index as usize % values.len()].into(),
+                index,
+            )
+            .unwrap();
+            rows.push(
+                eav_storage
+                    .add_eavi(&eavi)
+                    .expect("could not add eav")
+                    .expect("could not get eavi option"),
+            );
+        }
+
+        let entity_filters = || {
+            vec![
+                EavFilter::default(),
+                EavFilter::prefix("aa"),
+                EavFilter::prefix("b"),
+                EavFilter::prefix("c"),
+                EavFilter::range(Some("aa0".to_string()), Some("ab".to_string())),
+                EavFilter::range(None, Some("aa!".to_string())),
+                EavFilter::range(Some("aa1".to_string()), None),
+            ]
+        };
+        let value_filters = || {
+            vec![
+                EavFilter::default(),
+                EavFilter::prefix("vb"),
+                EavFilter::range(Some("vb".to_string()), Some("w".to_string())),
+                EavFilter::single("w".into()),
+            ]
+        };
+        let selected = |entity: &EavFilter<Address>,
+                        attribute: &Option<ExampleAttribute>,
+                        value: &EavFilter<Address>| {
+            rows.iter()
+                .filter(|eavi| {
+                    entity.check(eavi.entity())
+                        && attribute.iter().all(|a| *a == eavi.attribute())
+                        && value.check(eavi.value())
+                })
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        };
+        for (e, entity) in entity_filters().iter().enumerate() {
+            for (v, value) in value_filters().iter().enumerate() {
+                for attribute in [None, Some(attributes[1].clone())].iter() {
+                    let expected = selected(entity, attribute, value);
+                    let query = EaviQuery::new(
+                        entity_filters().remove(e),
+                        attribute.clone().into(),
+                        value_filters().remove(v),
+                        IndexFilter::Range(None, None),
+                        None,
+                    );
+                    assert_eq!(
+                        expected,
+                        eav_storage
+                            .fetch_eavi(&query)
+                            .expect("could not fetch eavs")
+                    );
+                    let first = eav_storage
+                        .fetch_eavi_page(&query.with_limit(2))
+                        .expect("could not fetch eavs");
+                    assert_eq!(
+                        expected.iter().take(2).cloned().collect::<Vec<_>>(),
+                        first.rows
+                    );
+                }
+            }
+        }
+    }
+
+    /// checks traversals reach each linked address once, along the shortest path to it
+    pub fn test_traversal<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let other = ExampleAttribute::WithPayload("other".to_string());
+        let address = |name: &str| -> Address { name.into() };
+        // a -> b -> c -> a is a cycle, c -> d leads out of it and a -other-> e
+        let mut add = |entity: &str, attribute: &ExampleAttribute, value: &str| {
+            let eavi = EntityAttributeValueIndex::new(&address(entity), attribute, &address(value))
+                .unwrap();
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let a_b = add("a", &link, "b");
+        let b_c = add("b", &link, "c");
+        let c_a = add("c", &link, "a");
+        let c_d = add("c", &link, "d");
+        let a_e = add("a", &other, "e");
+        let b_f = add("b", &link, "f");
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&b_f));
+
+        let reached = |traversal: Traversal<ExampleAttribute>| {
+            eav_storage
+                .traverse(&traversal)
+                .expect("could not traverse")
+                .into_iter()
+                .map(|step| {
+                    assert_eq!(step.depth, step.path.len());
+                    assert_eq!(
+                        step.address,
+                        traversal.follows_to(step.path.last().unwrap())
+                    );
+                    (step.address, step.path)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                (address("b"), vec![a_b.clone()]),
+                (address("c"), vec![a_b.clone(), b_c.clone()]),
+                (address("d"), vec![a_b.clone(), b_c.clone(), c_d.clone()]),
+            ],
+            reached(Traversal::new(address("a")).follow(link.clone()))
+        );
+        assert_eq!(
+            vec![
+                (address("b"), vec![a_b.clone()]),
+                (address("e"), vec![a_e.clone()]),
+                (address("c"), vec![a_b.clone(), b_c.clone()]),
+            ],
+            reached(Traversal::new(address("a")).with_max_depth(2))
+        );
+        assert_eq!(
+            vec![(address("e"), vec![a_e.clone()])],
+            reached(Traversal::new(address("a")).follow(other.clone()))
+        );
+        assert_eq!(
+            vec![
+                (address("b"), vec![b_c.clone()]),
+                (address("a"), vec![b_c.clone(), a_b.clone()]),
+            ],
+            reached(
+                Traversal::new(address("c"))
+                    .follow(link.clone())
+                    .reverse()
+                    .with_max_depth(2)
+            )
+        );
+        assert_eq!(
+            vec![(address("c"), vec![c_a.clone()])],
+            reached(
+                Traversal::new(address("a"))
+                    .reverse()
+                    .follow(link.clone())
+                    .with_max_depth(1)
+            )
+        );
+        assert!(reached(Traversal::new(address("a")).with_max_depth(0)).is_empty());
+        assert!(reached(Traversal::new(address("x"))).is_empty());
+    }
+
+    /// checks join queries find the bindings that match every pattern, ignoring retracted rows
+    pub fn test_join<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let attribute = |name: &str| ExampleAttribute::WithPayload(name.to_string());
+        let address = |name: &str| -> Address { name.into() };
+        let mut add = |entity: &str, a: &str, value: &str| {
+            let eavi =
+                EntityAttributeValueIndex::new(&address(entity), &attribute(a), &address(value))
+                    .unwrap();
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        add("alice", "likes", "apple");
+        add("alice", "likes", "apple");
+        add("alice", "friend", "bob");
+        add("bob", "likes", "apple");
+        add("bob", "likes", "pear");
+        add("bob", "friend", "carol");
+        add("carol", "likes", "pear");
+        let retracted = add("carol", "friend", "alice");
+        add("dave", "likes", "apple");
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&retracted));
+
+        let var = Term::var;
+        let answers = |query: JoinQuery<ExampleAttribute>, var: &str| {
+            eav_storage
+                .fetch_join(&query)
+                .expect("could not join")
+                .into_iter()
+                .map(|bindings| bindings[var].clone())
+                .collect::<Vec<_>>()
+        };
+        let addresses = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Binding::Address(address(name)))
+                .collect::<Vec<_>>()
+        };
+
+        // who likes apples and has a friend
+        let query = JoinQuery::default()
+            .with(Pattern::new(
+                var("x"),
+                Term::val(attribute("likes")),
+                Term::val(address("apple")),
+            ))
+            .with(Pattern::new(
+                var("x"),
+                Term::val(attribute("friend")),
+                var("f"),
+            ));
+        assert_eq!(addresses(&["alice", "bob"]), answers(query, "x"));
+
+        // friends who like something in common, through a shared variable
+        let query = JoinQuery::default()
+            .with(Pattern::new(
+                var("x"),
+                Term::val(attribute("friend")),
+                var("y"),
+            ))
+            .with(Pattern::new(
+                var("x"),
+                Term::val(attribute("likes")),
+                var("fruit"),
+            ))
+            .with(Pattern::new(
+                var("y"),
+                Term::val(attribute("likes")),
+                var("fruit"),
+            ));
+        assert_eq!(
+            addresses(&["apple", "pear"]),
+            answers(query.clone(), "fruit")
+        );
+        assert_eq!(addresses(&["alice", "bob"]), answers(query, "x"));
+
+        // the retracted friendship doesn't count
+        let query = JoinQuery::default().with(Pattern::new(
+            Term::val(address("carol")),
+            Term::val(attribute("friend")),
+            var("y"),
+        ));
+        assert!(answers(query, "y").is_empty());
+
+        // attributes can be variables too
+        let query = JoinQuery::default().with(Pattern::new(
+            Term::val(address("alice")),
+            Term::var("a"),
+            Term::val(address("bob")),
+        ));
+        assert_eq!(
+            vec![Binding::Attribute(attribute("friend"))],
+            answers(query, "a")
+        );
+
+        assert_eq!(
+            Ok(vec![Bindings::new()].into_iter().collect()),
+            eav_storage.fetch_join(&JoinQuery::default())
+        );
+    }
+
+    /// checks a tombstone at or before the end of a range hides the rows of its entity and value
+    pub fn test_range_tombstone<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let removed = ExampleAttribute::WithPayload("removed".to_string());
+        let mut add = |entity: &str, attribute: &ExampleAttribute, value: &str, index| {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &entity.into(),
+                attribute,
+                &value.into(),
+                index,
+            )
+            .unwrap();
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let one = add("e", &link, "one", 1);
+        let two = add("e", &link, "two", 2);
+        let other = add("f", &link, "one", 3);
+        let one_removed = add("e", &removed, "one", 5);
+        // adding the link again doesn't undo the tombstone
+        add("e", &link, "one", 7);
+        let two_again = add("e", &link, "two", 8);
+        let two_removed = add("e", &removed, "two", 20);
+
+        let query = |start, end| {
+            EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::Range(start, end),
+                Some(EavFilter::single(removed.clone())),
+            )
+        };
+        let expect =
+            |eav_storage: &S, rows: &[&EntityAttributeValueIndex<ExampleAttribute>], start, end| {
+                let expected: BTreeSet<_> = rows.iter().cloned().cloned().collect();
+                let query = query(start, end);
+                let fetched = eav_storage
+                    .fetch_eavi(&query)
+                    .expect("could not fetch eavs");
+                assert_eq!(expected, fetched);
+                assert_eq!(Ok(expected.len()), eav_storage.count_eavi(&query));
+                let iterated = eav_storage
+                    .fetch_eavi_iter(&query)
+                    .expect("could not fetch eavs")
+                    .collect::<PersistenceResult<BTreeSet<_>>>();
+                assert_eq!(Ok(expected.clone()), iterated);
+                let page = eav_storage
+                    .fetch_eavi_page(&query.with_limit(2))
+                    .expect("could not fetch eavs");
+                assert_eq!(expected.into_iter().take(2).collect::<Vec<_>>(), page.rows);
+            };
+
+        // only the tombstone for one is at or before 10
+        expect(
+            &eav_storage,
+            &[&two, &other, &one_removed, &two_again],
+            None,
+            Some(10),
+        );
+        // the tombstone before the range still hides the row added after it
+        expect(&eav_storage, &[&two_again], Some(6), Some(10));
+        // neither tombstone is yet
+        expect(&eav_storage, &[&one, &two, &other], Some(0), Some(4));
+        expect(
+            &eav_storage,
+            &[&other, &one_removed, &two_removed],
+            None,
+            None,
+        );
+
+        // a retracted tombstone hides nothing
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&two_removed));
+        expect(
+            &eav_storage,
+            &[&two, &other, &one_removed, &two_again],
+            None,
+            None,
+        );
+    }
+
+    /// checks rows keep their sources and can be filtered by them
+    pub fn test_source<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let alice: Address = "alice".into();
+        let mallory: Address = "mallory".into();
+        let mut add = |value: &str, index, source: Option<&Address>| {
+            let mut eavi =
+                EntityAttributeValueIndex::new_with_index(&"e".into(), &link, &value.into(), index)
+                    .unwrap();
+            eavi.set_source(source.cloned());
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let from_alice = add("one", 1, Some(&alice));
+        let from_mallory = add("two", 2, Some(&mallory));
+        let unsourced = add("three", 3, None);
+        // a row added at an index its entity already has keeps its source
+        let collided = add("four", 1, Some(&alice));
+        assert_eq!(Some(alice.clone()), collided.source());
+
+        let fetch = |eav_storage: &S, source| {
+            let query = EaviQuery::new(
+                Some("e".into()).into(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_source(source);
+            eav_storage
+                .fetch_eavi(&query)
+                .expect("could not fetch eavs")
+        };
+        let expected = |rows: &[&EntityAttributeValueIndex<ExampleAttribute>]| {
+            rows.iter().cloned().cloned().collect::<BTreeSet<_>>()
+        };
+
+        assert_eq!(
+            expected(&[&from_alice, &from_mallory, &unsourced, &collided]),
+            fetch(&eav_storage, EavFilter::default())
+        );
+        assert_eq!(
+            expected(&[&from_alice, &collided]),
+            fetch(&eav_storage, EavFilter::single(Some(alice.clone())))
+        );
+        assert_eq!(
+            expected(&[&unsourced]),
+            fetch(&eav_storage, EavFilter::single(None))
+        );
+        // everything but what mallory asserted
+        let trusted = fetch(
+            &eav_storage,
+            EavFilter::predicate(move |source| source != Some(mallory.clone())),
+        );
+        assert_eq!(expected(&[&from_alice, &unsourced, &collided]), trusted);
+    }
+
+    /// checks a storage with a Lamport clock starting at zero stamps eavis with it
+    pub fn test_clock<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let add = |eav_storage: &mut S, eavi: EntityAttributeValueIndex<ExampleAttribute>| {
+            eav_storage
+                .add_eavi(&eavi)
+                .expect("could not add eav")
+                .expect("could not get eavi option")
+        };
+        let new_eavi = |eav_storage: &S, value: &str| {
+            eav_storage
+                .new_eavi(&"e".into(), &link, &value.into())
+                .expect("could not create eavi")
+        };
+
+        let first = new_eavi(&eav_storage, "one");
+        let second = new_eavi(&eav_storage, "two");
+        assert_eq!((1, 2), (first.index(), second.index()));
+        add(&mut eav_storage, second);
+        // the index of an added row is observed, so rows made after it come after it
+        let remote =
+            EntityAttributeValueIndex::new_with_index(&"e".into(), &link, &"r".into(), 10).unwrap();
+        add(&mut eav_storage, remote);
+        let three = new_eavi(&eav_storage, "three");
+        let later = add(&mut eav_storage, three);
+        assert_eq!(11, later.index());
+        assert_eq!(12, eav_storage.clock().now());
+        // rows made before others were added still keep their index
+        assert_eq!(1, add(&mut eav_storage, first).index());
+    }
+
+    /// checks rows added at an index their entity already has are kept by the policy all
+    /// storages share, @see resolve_collision
+    pub fn test_collisions<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let row = |entity: &str, value: &str| {
+            EntityAttributeValueIndex::new_with_index(&entity.into(), &link, &value.into(), 5)
+                .unwrap()
+        };
+        let rows = [
+            row("e", "a"),
+            row("e", "b"),
+            row("e", "c"),
+            // an index is only shared within its entity
+            row("f", "a"),
+            // rows with different sources are different rows
+            row("e", "a").with_source(&"author".into()),
+        ];
+        // every row is stored as it is, at its own index, and adding it again stores it once
+        for eavi in rows.iter().chain(rows.iter()) {
+            assert_eq!(
+                Some(eavi.clone()),
+                eav_storage.add_eavi(eavi).expect("could not add eav")
+            );
+        }
+
+        let query = |entity: Option<&str>, value: Option<&str>| {
+            EaviQuery::new(
+                entity.map(Address::from).into(),
+                EavFilter::single(link.clone()),
+                value.map(Address::from).into(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_retraction(RetractionFilter::Include)
+        };
+        let fetch = |eav_storage: &S, entity, value| {
+            eav_storage
+                .fetch_eavi(&query(entity, value))
+                .expect("could not fetch eavs")
+        };
+        let expected = |rows: &[&EntityAttributeValueIndex<ExampleAttribute>]| {
+            rows.iter().cloned().cloned().collect::<BTreeSet<_>>()
+        };
+        assert_eq!(
+            expected(&rows.iter().collect::<Vec<_>>()),
+            fetch(&eav_storage, None, None)
+        );
+        assert_eq!(
+            expected(&[&rows[0], &rows[1], &rows[2], &rows[4]]),
+            fetch(&eav_storage, Some("e"), None)
+        );
+        assert_eq!(
+            expected(&[&rows[0], &rows[4]]),
+            fetch(&eav_storage, Some("e"), Some("a"))
+        );
+
+        // each of the rows sharing an index is retracted on its own
+        assert!(eav_storage
+            .retract_eavi(&rows[1])
+            .expect("could not retract eav"));
+        assert!(!eav_storage
+            .retract_eavi(&rows[1])
+            .expect("could not retract eav"));
+        let retracted = eav_storage
+            .fetch_eavi(&query(Some("e"), None).with_retraction(RetractionFilter::Only))
+            .expect("could not fetch eavs");
+        assert_eq!(expected(&[&rows[1]]), retracted);
+    }
+
+    /// checks queries as of an index only see the rows up to it, for the latest rows, their
+    /// tombstones and ranges
+    pub fn test_as_of<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::default();
+        let removed = ExampleAttribute::WithPayload("removed".to_string());
+        let row = |attribute: &ExampleAttribute, value: &str, index| {
+            EntityAttributeValueIndex::new_with_index(
+                &"base".into(),
+                attribute,
+                &value.into(),
+                index,
+            )
+            .unwrap()
+        };
+        let rows = vec![
+            row(&link, "a", 10),
+            row(&link, "b", 20),
+            row(&removed, "a", 30),
+            row(&link, "c", 40),
+        ];
+        for eavi in &rows {
+            eav_storage.add_eavi(eavi).expect("could not add eav");
+        }
+
+        let fetch = |index: IndexFilter, entity: Option<&str>, as_of: Option<i64>| {
+            let mut query = EaviQuery::new(
+                entity
+                    .map(|entity| EavFilter::single(entity.into()))
+                    .unwrap_or_default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                index,
+                Some(EavFilter::single(removed.clone())),
+            );
+            query.as_of = as_of;
+            eav_storage
+                .fetch_eavi(&query)
+                .expect("could not fetch eavs")
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+        for entity in &[None, Some("base")] {
+            let latest = |as_of| fetch(IndexFilter::LatestByAttribute, *entity, as_of);
+            assert_eq!(Vec::<EntityAttributeValueIndex<_>>::new(), latest(Some(9)));
+            assert_eq!(rows[..2].to_vec(), latest(Some(29)));
+            // the tombstone replaces the link it removes once it is seen
+            assert_eq!(rows[1..3].to_vec(), latest(Some(30)));
+            assert_eq!(rows[1..].to_vec(), latest(None));
+
+            let range = |start, as_of| fetch(IndexFilter::Range(start, None), *entity, as_of);
+            assert_eq!(rows[..2].to_vec(), range(None, Some(25)));
+            assert_eq!(vec![rows[1].clone()], range(Some(15), Some(25)));
+            assert_eq!(
+                vec![rows[1].clone(), rows[2].clone()],
+                range(Some(15), Some(35))
+            );
+            assert_eq!(
+                vec![rows[1].clone()],
+                fetch(IndexFilter::Range(Some(15), Some(25)), *entity, Some(35))
+            );
+        }
+    }
+
+    /// checks a live query of the latest links of an entity gets the links that are added,
+    /// retracted or replaced by a tombstone through any clone, including clones of the
+    /// wrapped storage, @see LiveRegistry
+    pub fn test_live<S>(eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute> + Clone + 'static,
+    {
+        let link = ExampleAttribute::default();
+        let removed = ExampleAttribute::WithPayload("removed".to_string());
+        let row = |entity: &str, attribute: &ExampleAttribute, value: &str, index| {
+            EntityAttributeValueIndex::new_with_index(
+                &entity.into(),
+                attribute,
+                &value.into(),
+                index,
+            )
+            .unwrap()
+        };
+        let change = |added: Vec<_>, removed: Vec<_>| LiveChange {
+            added: added.into_iter().collect(),
+            removed: removed.into_iter().collect(),
+        };
+
+        let mut live = LiveEavStorage::new(eav_storage);
+        live.add_eavi(&row("base", &link, "a", 1))
+            .expect("could not add eav");
+        let query = live
+            .subscribe(&EaviQuerySpec {
+                entity: FilterSpec::Exact("base".into()),
+                tombstone: Some(FilterSpec::Exact(removed.clone())),
+                ..Default::default()
+            })
+            .expect("could not subscribe");
+        let next = || query.changes().try_recv().expect("expected a change");
+        assert_eq!(change(vec![row("base", &link, "a", 1)], vec![]), next());
+
+        // the wrapped storage shares the live queries, so writing to it directly updates them
+        let mut clone = live.storage().clone();
+        clone
+            .add_eavi(&row("base", &link, "b", 2))
+            .expect("could not add eav");
+        assert_eq!(change(vec![row("base", &link, "b", 2)], vec![]), next());
+
+        // the tombstone becomes the latest row of the link it removes
+        live.add_eavi(&row("base", &removed, "a", 3))
+            .expect("could not add eav");
+        assert_eq!(
+            change(
+                vec![row("base", &removed, "a", 3)],
+                vec![row("base", &link, "a", 1)]
+            ),
+            next()
+        );
+        // a later link doesn't come back over the tombstone
+        clone
+            .add_eavi(&row("base", &link, "a", 4))
+            .expect("could not add eav");
+        assert!(query.changes().try_recv().is_err());
+
+        clone
+            .retract_eavi(&row("base", &link, "b", 2))
+            .expect("could not retract eav");
+        assert_eq!(change(vec![], vec![row("base", &link, "b", 2)]), next());
+
+        live.clone()
+            .add_eavi(&row("other", &link, "c", 5))
+            .expect("could not add eav");
+        assert!(query.changes().try_recv().is_err());
+    }
+
+    /// checks two storages merging each other's rows end up with the same rows, retractions
+    /// and latest rows, and that merging again changes nothing, @see merge
+    pub fn test_merge<S>(mut a: S, mut b: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let link = ExampleAttribute::WithPayload("link".to_string());
+        let removed = ExampleAttribute::WithPayload("removed".to_string());
+        let row = |attribute: &ExampleAttribute, value: &str, index| {
+            EntityAttributeValueIndex::new_with_index(&"e".into(), attribute, &value.into(), index)
+                .unwrap()
+        };
+        let shared = row(&link, "shared", 1);
+        // x and y are different rows of e at the same index, so they collide when merged
+        let (x, y) = (row(&link, "x", 6), row(&link, "y", 6));
+        for eavi in &[
+            shared.clone(),
+            row(&link, "a", 2),
+            row(&link, "a", 4),
+            x.clone(),
+        ] {
+            a.add_eavi(eavi).expect("could not add eav");
+        }
+        for eavi in &[
+            shared.clone(),
+            row(&link, "b", 3),
+            row(&removed, "a", 5),
+            y.clone(),
+        ] {
+            b.add_eavi(eavi).expect("could not add eav");
+        }
+        a.retract_eavi(&shared).expect("could not retract eav");
+
+        let report = a.merge_from(&b).expect("could not merge");
+        assert_eq!((3, 0), (report.added, report.retracted));
+        let report = b.merge_from(&a).expect("could not merge");
+        assert_eq!((3, 1), (report.added, report.retracted));
+
+        let all = |retraction| {
+            EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_retraction(retraction)
+        };
+        let latest = || {
+            EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::LatestByAttribute,
+                Some(EavFilter::single(removed.clone())),
+            )
+        };
+        for query in &[
+            all(RetractionFilter::Include),
+            all(RetractionFilter::Only),
+            latest(),
+        ] {
+            assert_eq!(
+                a.fetch_eavi(query).expect("could not fetch eavs"),
+                b.fetch_eavi(query).expect("could not fetch eavs")
+            );
+        }
+        // the tombstone is the latest of the value "a", the shared row is retracted and both
+        // colliding rows are kept
+        assert_eq!(
+            vec![row(&link, "b", 3), row(&removed, "a", 5), x, y]
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+            a.fetch_eavi(&latest()).expect("could not fetch eavs")
+        );
+
+        assert!(a.merge_from(&b).expect("could not merge").is_empty());
+        assert!(b.merge_from(&a).expect("could not merge").is_empty());
+    }
+
+    /// checks the counts of rows, in total and by attribute and entity, match what is fetched
+    pub fn test_counts<S>(mut eav_storage: S)
+    where
+        S: EntityAttributeValueStorage<ExampleAttribute>,
+    {
+        let address = |name: String| Content::from(RawString::from(name)).address();
+        let attributes = [
+            ExampleAttribute::default(),
+            ExampleAttribute::WithPayload("a".to_string()),
+            ExampleAttribute::WithPayload("b".to_string()),
+        ];
+        let mut rows = Vec::new();
+        for index in 0..24 {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &address(format!("entity {}", index % 4)),
+                &attributes[index as usize % 3],
+                &address(format!("value {}", index % 2)),
+                index,
+            )
+            .unwrap();
+            rows.push(
+                eav_storage
+                    .add_eavi(&eavi)
+                    .expect("could not add eav")
+                    .expect("could not get eavi option"),
+            );
+        }
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&rows[0]));
+
+        let query = |entity: Option<Address>, attribute: Option<ExampleAttribute>| {
+            EaviQuery::new(
+                entity.into(),
+                attribute.into(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+        };
+        let queries = [
+            query(None, None),
+            query(None, None).with_retraction(RetractionFilter::Include),
+            query(Some(address("entity 1".into())), None),
+            query(None, Some(attributes[1].clone())),
+            query(None, None).with_limit(5),
+            EaviQuery::default(),
+        ];
+        for query in queries.iter() {
+            let fetched = eav_storage.fetch_eavi(query).expect("could not fetch eavs");
+            let mut by_attribute = BTreeMap::new();
+            let mut by_entity = BTreeMap::new();
+            for eavi in fetched.iter() {
+                *by_attribute.entry(eavi.attribute()).or_insert(0) += 1;
+                *by_entity.entry(eavi.entity()).or_insert(0) += 1;
+            }
+            assert_eq!(Ok(fetched.len()), eav_storage.count_eavi(query));
+            assert_eq!(Ok(by_attribute), eav_storage.count_eavi_by_attribute(query));
+            assert_eq!(Ok(by_entity), eav_storage.count_eavi_by_entity(query));
+        }
+
+        // one row of the 24 is retracted, so the attribute it has counts one less
+        let counts = eav_storage
+            .count_eavi_by_attribute(&query(None, None))
+            .unwrap();
+        assert_eq!(Some(&7), counts.get(&attributes[0]));
+        assert_eq!(Some(&8), counts.get(&attributes[1]));
+        assert_eq!(Some(&8), counts.get(&attributes[2]));
+        assert_eq!(Ok(23), eav_storage.count_eavi(&query(None, None)));
+    }
+
     pub fn test_one_to_many<A, AT: Attribute, S>(mut eav_storage: S, attribute: &AT)
     where
         A: AddressableContent + Clone,
@@ -906,9 +2305,11 @@ impl CasBencher {
 pub mod tests {
     use crate::cas::{
         content::{ExampleAddressableContent, OtherExampleAddressableContent},
-        storage::{test_content_addressable_storage, StorageTestSuite},
+        storage::{test_content_addressable_storage, ContentAddressableStorage, StorageTestSuite},
+        ttl::MockTimeSource,
     };
     use holochain_json_api::json::{JsonString, RawString};
+    use std::sync::Arc;
 
     /// show that content of different types can round trip through the same storage
     #[test]
@@ -919,4 +2320,31 @@ pub mod tests {
             JsonString::from(RawString::from("bar")),
         );
     }
+
+    #[test]
+    fn example_content_id_test() {
+        let cas = test_content_addressable_storage();
+        assert_ne!(cas.get_id(), test_content_addressable_storage().get_id());
+        StorageTestSuite::new(cas.clone()).id_test(cas);
+    }
+
+    #[test]
+    fn example_content_addresses_test() {
+        StorageTestSuite::new(test_content_addressable_storage()).addresses_test(
+            JsonString::from(RawString::from("foo")),
+            JsonString::from(RawString::from("bar")),
+        );
+    }
+
+    #[test]
+    fn example_content_ttl_test() {
+        let time_source = MockTimeSource::default();
+        let cas =
+            test_content_addressable_storage().with_time_source(Arc::new(time_source.clone()));
+        StorageTestSuite::new(cas).ttl_test(
+            time_source,
+            JsonString::from(RawString::from("foo")),
+            JsonString::from(RawString::from("bar")),
+        );
+    }
 }
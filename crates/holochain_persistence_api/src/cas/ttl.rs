@@ -0,0 +1,176 @@
+//! Time-to-live support for content held in a ContentAddressableStorage.
+//! Content added with `add_with_ttl` expires once its time-to-live has elapsed and is removed
+//! by `sweep_expired`, either on demand or periodically by a TtlSweeper running in the background.
+//! Content added without a time-to-live never expires.
+//! Storage implementations read the current time from a TimeSource so that tests can control it.
+
+use crate::cas::storage::ContentAddressableStorage;
+use holochain_logging::prelude::*;
+use std::{
+    fmt::Debug,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// provides the current time to storage implementations that support expiry
+pub trait TimeSource: Send + Sync + Debug {
+    fn now(&self) -> SystemTime;
+}
+
+/// the default TimeSource, reads the system clock
+#[derive(Clone, Debug, Default)]
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// a TimeSource that only moves when told to, for deterministic tests
+/// all clones share the same time
+#[derive(Clone, Debug)]
+pub struct MockTimeSource {
+    now: Arc<RwLock<SystemTime>>,
+}
+
+impl Default for MockTimeSource {
+    fn default() -> MockTimeSource {
+        MockTimeSource::new(UNIX_EPOCH)
+    }
+}
+
+impl MockTimeSource {
+    pub fn new(start: SystemTime) -> MockTimeSource {
+        MockTimeSource {
+            now: Arc::new(RwLock::new(start)),
+        }
+    }
+
+    /// moves the time forward by the given duration
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.write().unwrap();
+        *now += duration;
+    }
+
+    pub fn set(&self, time: SystemTime) {
+        *self.now.write().unwrap() = time;
+    }
+}
+
+impl TimeSource for MockTimeSource {
+    fn now(&self) -> SystemTime {
+        *self.now.read().unwrap()
+    }
+}
+
+/// the point in time at which content added now with the given ttl expires
+pub fn expires_at(time_source: &dyn TimeSource, ttl: Duration) -> SystemTime {
+    time_source.now() + ttl
+}
+
+/// true if the given expiry time has been reached according to the time source
+pub fn is_expired(time_source: &dyn TimeSource, expiry: SystemTime) -> bool {
+    expiry <= time_source.now()
+}
+
+/// converts an expiry time to milliseconds since the unix epoch
+/// persistent storage implementations store expiry times in this form
+pub fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// converts milliseconds since the unix epoch back to a point in time
+pub fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// periodically sweeps expired content from a ContentAddressableStorage on a background thread
+/// the thread is stopped when the sweeper is dropped
+pub struct TtlSweeper {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TtlSweeper {
+    /// spawns a thread that calls sweep_expired on the given storage every interval
+    /// the storage is usually a clone of the one content is added to
+    pub fn spawn<CAS>(mut cas: CAS, interval: Duration) -> TtlSweeper
+    where
+        CAS: ContentAddressableStorage + 'static,
+    {
+        let (stop, stopped) = channel();
+        let handle = thread::spawn(move || {
+            // anything but a timeout means the sweeper was dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(err) = cas.sweep_expired() {
+                    warn!("could not sweep expired content: {}", err);
+                }
+            }
+        });
+        TtlSweeper {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for TtlSweeper {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cas::{content::AddressableContent, storage::ExampleContentAddressableStorage};
+    use holochain_json_api::json::{JsonString, RawString};
+
+    #[test]
+    fn mock_time_source_only_moves_when_told() {
+        let time_source = MockTimeSource::default();
+        let expiry = expires_at(&time_source, Duration::from_secs(10));
+        assert!(!is_expired(&time_source, expiry));
+
+        time_source.advance(Duration::from_secs(9));
+        assert!(!is_expired(&time_source, expiry));
+
+        time_source.clone().advance(Duration::from_secs(1));
+        assert!(is_expired(&time_source, expiry));
+    }
+
+    #[test]
+    fn millis_round_trip() {
+        let time = from_millis(1_234_567);
+        assert_eq!(1_234_567, to_millis(time));
+    }
+
+    #[test]
+    fn sweeper_removes_expired_content() {
+        let time_source = MockTimeSource::default();
+        let mut cas = ExampleContentAddressableStorage::new()
+            .unwrap()
+            .with_time_source(Arc::new(time_source.clone()));
+        let content = JsonString::from(RawString::from("cached"));
+        cas.add_with_ttl(&content, Duration::from_secs(1)).unwrap();
+        time_source.advance(Duration::from_secs(2));
+
+        let sweeper = TtlSweeper::spawn(cas.clone(), Duration::from_millis(1));
+        while cas.contains(&content.address()).unwrap() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(sweeper);
+
+        assert_eq!(Ok(None), cas.fetch(&content.address()));
+    }
+}
@@ -0,0 +1,365 @@
+//! Buckets of items, their summaries and the comparison of the buckets of two peers.
+
+use cas::{
+    content::{Address, AddressableContent},
+    storage::ContentAddressableStorage,
+};
+use eav::{
+    Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
+    IndexFilter, RetractionFilter,
+};
+use error::{PersistenceError, PersistenceResult};
+use hash::HashString;
+use multihash::{encode, Hash};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+/// how many items a bucket may hold on either side for its items to be compared, rather than
+/// the summaries of its children
+pub const LEAF_SIZE: usize = 16;
+
+/// the digits of digests, a bucket has a child for each
+const DIGITS: &str = "0123456789abcdef";
+
+/// the length of a digest, which no prefix goes beyond
+const DIGEST_LEN: usize = 64;
+
+/// something that can be in either of two storages
+pub trait DiffItem: Clone + Ord {
+    /// identifies the item, the same way in every storage
+    fn key(&self) -> String;
+}
+
+impl DiffItem for Address {
+    fn key(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// a row of an EAV storage and whether it is retracted there, so peers holding the same row
+/// but only one of them retracting it differ
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct EavRow<A: Attribute> {
+    pub eavi: EntityAttributeValueIndex<A>,
+    pub retracted: bool,
+}
+
+impl<A> DiffItem for EavRow<A>
+where
+    A: Attribute + DeserializeOwned,
+{
+    fn key(&self) -> String {
+        if self.retracted {
+            format!("{}::retracted", self.eavi.address())
+        } else {
+            self.eavi.address().to_string()
+        }
+    }
+}
+
+/// the hex sha256 of a key, which spreads keys evenly over buckets
+fn digest(key: &str) -> String {
+    let hash = encode(Hash::SHA2256, key.as_bytes()).expect("sha256 hashes any bytes");
+    // the first two bytes name the hash function and the length
+    hash[2..].iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).expect("writing to a string never fails");
+        hex
+    })
+}
+
+/// what a peer holds in the bucket of a prefix
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub prefix: String,
+    pub count: usize,
+    /// a hash of the digests of the items, in order
+    pub hash: HashString,
+}
+
+/// the items of a storage by their digests
+#[derive(Clone, Debug)]
+pub struct Buckets<T: DiffItem> {
+    items: BTreeMap<String, T>,
+}
+
+impl<T: DiffItem> Buckets<T> {
+    pub fn new<I: IntoIterator<Item = T>>(items: I) -> Buckets<T> {
+        Buckets {
+            items: items
+                .into_iter()
+                .map(|item| (digest(&item.key()), item))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// the items whose digests start with the prefix
+    fn bucket<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a String, &'a T)> {
+        self.items
+            .range(prefix.to_string()..)
+            .take_while(move |(digest, _)| digest.starts_with(prefix))
+    }
+
+    pub fn summary(&self, prefix: &str) -> Summary {
+        let mut count = 0;
+        let mut digests = String::new();
+        for (digest, _) in self.bucket(prefix) {
+            count += 1;
+            digests.push_str(digest);
+        }
+        Summary {
+            prefix: prefix.to_string(),
+            count,
+            hash: HashString::encode_from_str(&digests, Hash::SHA2256),
+        }
+    }
+
+    pub fn items(&self, prefix: &str) -> Vec<T> {
+        self.bucket(prefix).map(|(_, item)| item.clone()).collect()
+    }
+}
+
+impl Buckets<Address> {
+    /// the addresses of all the content in the storage
+    pub fn of_cas(cas: &dyn ContentAddressableStorage) -> PersistenceResult<Buckets<Address>> {
+        Ok(Buckets::new(cas.addresses()?))
+    }
+}
+
+impl<A> Buckets<EavRow<A>>
+where
+    A: Attribute + DeserializeOwned,
+{
+    /// all the rows of the storage, retracted ones included and marked as such
+    pub fn of_eav(
+        eav: &dyn EntityAttributeValueStorage<A>,
+    ) -> PersistenceResult<Buckets<EavRow<A>>> {
+        let query = |retraction| {
+            EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                IndexFilter::Range(None, None),
+                None,
+            )
+            .with_retraction(retraction)
+        };
+        let retracted = eav.fetch_eavi(&query(RetractionFilter::Only))?;
+        let rows = eav
+            .fetch_eavi_iter(&query(RetractionFilter::Include))?
+            .map(|eavi| {
+                eavi.map(|eavi| EavRow {
+                    retracted: retracted.contains(&eavi),
+                    eavi,
+                })
+            })
+            .collect::<PersistenceResult<Vec<_>>>()?;
+        Ok(Buckets::new(rows))
+    }
+}
+
+/// The other side of a comparison, which answers for its buckets.
+/// Local buckets are peers themselves, @see StreamPeer for one at the other end of a stream.
+pub trait Peer<T: DiffItem> {
+    /// the summaries of the buckets of the prefixes, in the same order
+    fn summaries(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<Summary>>;
+
+    /// the items of the buckets of the prefixes
+    fn items(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<T>>;
+}
+
+impl<T: DiffItem> Peer<T> for Buckets<T> {
+    fn summaries(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<Summary>> {
+        Ok(prefixes.iter().map(|prefix| self.summary(prefix)).collect())
+    }
+
+    fn items(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<T>> {
+        Ok(prefixes
+            .iter()
+            .flat_map(|prefix| Buckets::items(self, prefix))
+            .collect())
+    }
+}
+
+/// what is only held locally and what only by the peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diff<T: DiffItem> {
+    pub only_local: BTreeSet<T>,
+    pub only_remote: BTreeSet<T>,
+    /// how many requests were made of the peer
+    pub round_trips: usize,
+    /// how many items the peer sent, which is all it sent besides summaries
+    pub received: usize,
+}
+
+impl<T: DiffItem> Default for Diff<T> {
+    fn default() -> Diff<T> {
+        Diff {
+            only_local: BTreeSet::new(),
+            only_remote: BTreeSet::new(),
+            round_trips: 0,
+            received: 0,
+        }
+    }
+}
+
+impl<T: DiffItem> Diff<T> {
+    /// whether both hold the same items
+    pub fn is_empty(&self) -> bool {
+        self.only_local.is_empty() && self.only_remote.is_empty()
+    }
+}
+
+/// Compares the local buckets with the peer's, a level of buckets per round trip.
+/// Buckets with the same summary are skipped, those that differ have their items compared
+/// once either side holds at most LEAF_SIZE of them and their children compared otherwise.
+pub fn diff<T, P>(local: &Buckets<T>, peer: &mut P) -> PersistenceResult<Diff<T>>
+where
+    T: DiffItem,
+    P: Peer<T> + ?Sized,
+{
+    let mut diff = Diff::default();
+    let mut pending = vec![String::new()];
+    while !pending.is_empty() {
+        let summaries = peer.summaries(&pending)?;
+        diff.round_trips += 1;
+        if summaries.len() != pending.len() {
+            return Err(PersistenceError::ErrorGeneric(format!(
+                "asked the peer for {} summaries but got {}",
+                pending.len(),
+                summaries.len()
+            )));
+        }
+
+        let mut leaves = Vec::new();
+        let mut children = Vec::new();
+        for (prefix, theirs) in pending.iter().zip(summaries) {
+            let ours = local.summary(prefix);
+            if ours == theirs {
+                continue;
+            }
+            if ours.count.min(theirs.count) <= LEAF_SIZE || prefix.len() == DIGEST_LEN {
+                leaves.push(prefix.clone());
+            } else {
+                children.extend(DIGITS.chars().map(|digit| format!("{}{}", prefix, digit)));
+            }
+        }
+
+        if !leaves.is_empty() {
+            let remote: BTreeSet<T> = peer.items(&leaves)?.into_iter().collect();
+            diff.round_trips += 1;
+            diff.received += remote.len();
+            let ours: BTreeSet<T> = leaves
+                .iter()
+                .flat_map(|prefix| local.items(prefix))
+                .collect();
+            diff.only_local.extend(ours.difference(&remote).cloned());
+            diff.only_remote.extend(remote.difference(&ours).cloned());
+        }
+        pending = children;
+    }
+    Ok(diff)
+}
+
+/// the addresses only held by either of two content storages
+pub fn diff_cas(
+    local: &dyn ContentAddressableStorage,
+    remote: &dyn ContentAddressableStorage,
+) -> PersistenceResult<Diff<Address>> {
+    diff(&Buckets::of_cas(local)?, &mut Buckets::of_cas(remote)?)
+}
+
+/// the rows only held by either of two EAV storages, a row retracted by only one of them being
+/// only held by both, once as retracted and once not
+pub fn diff_eav<A>(
+    local: &dyn EntityAttributeValueStorage<A>,
+    remote: &dyn EntityAttributeValueStorage<A>,
+) -> PersistenceResult<Diff<EavRow<A>>>
+where
+    A: Attribute + DeserializeOwned,
+{
+    diff(&Buckets::of_eav(local)?, &mut Buckets::of_eav(remote)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    fn addresses(range: std::ops::Range<usize>) -> Vec<Address> {
+        range
+            .map(|n| Address::from(format!("address {}", n)))
+            .collect()
+    }
+
+    #[test]
+    fn digests_are_hex_sha256() {
+        let digest = digest("foo");
+        assert_eq!(DIGEST_LEN, digest.len());
+        assert!(digest.starts_with("2c26b46b"));
+    }
+
+    #[test]
+    fn the_same_items_have_the_same_summaries() {
+        let items = addresses(0..100);
+        let mut shuffled = items.clone();
+        shuffled.shuffle(&mut StdRng::seed_from_u64(48));
+        let (a, b) = (Buckets::new(items), Buckets::new(shuffled));
+        assert_eq!(a.summary(""), b.summary(""));
+        assert_eq!(100, a.summary("").count);
+        let children: usize = DIGITS
+            .chars()
+            .map(|digit| a.summary(&digit.to_string()).count)
+            .sum();
+        assert_eq!(100, children);
+
+        let fewer = Buckets::new(addresses(0..99));
+        assert_ne!(a.summary(""), fewer.summary(""));
+        assert!(diff(&a, &mut fewer.clone()).unwrap().only_remote.is_empty());
+        assert!(diff(&fewer, &mut a.clone()).unwrap().only_local.is_empty());
+        let same = diff(&a, &mut b.clone()).unwrap();
+        assert!(same.is_empty());
+        assert_eq!((1, 0), (same.round_trips, same.received));
+    }
+
+    #[test]
+    fn diffs_find_what_either_side_lacks_without_sending_everything() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..20 {
+            let all = addresses(0..2000);
+            let (mut local, mut remote) = (Vec::new(), Vec::new());
+            let mut expected = Diff::default();
+            for address in all {
+                match rng.gen_range(0, 200) {
+                    0 => {
+                        expected.only_local.insert(address.clone());
+                        local.push(address);
+                    }
+                    1 => {
+                        expected.only_remote.insert(address.clone());
+                        remote.push(address);
+                    }
+                    _ => {
+                        local.push(address.clone());
+                        remote.push(address);
+                    }
+                }
+            }
+
+            let found = diff(&Buckets::new(local), &mut Buckets::new(remote)).unwrap();
+            assert_eq!(expected.only_local, found.only_local);
+            assert_eq!(expected.only_remote, found.only_remote);
+            assert!(found.received < 500, "received {}", found.received);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Finding what differs between two storages, such as ours and a peer's before syncing, without
+//! sending either one's contents.
+//! Items are spread over buckets by the digits of a hash of their key, the bucket of a prefix
+//! holding the items whose digest starts with it. Peers compare the summaries of buckets, a
+//! count and a hash of the digests, from the one holding every item down to the buckets that
+//! differ, and only send the items of those once they are small.
+//! CAS items are addresses and EAV items are rows, retracted ones included and marked as such.
+
+pub mod buckets;
+pub mod protocol;
+
+pub use self::{buckets::*, protocol::*};
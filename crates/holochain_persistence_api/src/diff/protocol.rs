@@ -0,0 +1,285 @@
+//! Comparing buckets with a peer over a byte stream, such as a TCP or Unix socket.
+//! Each request and each response is a line of JSON. One side serves its buckets and the other
+//! compares its own with a StreamPeer reading the answers.
+
+use diff::buckets::{Buckets, DiffItem, Peer, Summary};
+use error::{PersistenceError, PersistenceResult};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Request {
+    Summaries(Vec<String>),
+    Items(Vec<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response<T> {
+    Summaries(Vec<Summary>),
+    Items(Vec<T>),
+    /// the request could not be read
+    Error(String),
+}
+
+fn send<W: Write, M: Serialize>(writer: &mut W, message: &M) -> PersistenceResult<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Answers the requests read from the stream with what the buckets hold, until it ends.
+pub fn serve<T, R, W>(buckets: &Buckets<T>, reader: R, mut writer: W) -> PersistenceResult<()>
+where
+    T: DiffItem + Serialize,
+    R: BufRead,
+    W: Write,
+{
+    for line in reader.lines() {
+        let response = match serde_json::from_str(&line?) {
+            Ok(Request::Summaries(prefixes)) => Response::Summaries(
+                prefixes
+                    .iter()
+                    .map(|prefix| buckets.summary(prefix))
+                    .collect(),
+            ),
+            Ok(Request::Items(prefixes)) => Response::Items(
+                prefixes
+                    .iter()
+                    .flat_map(|prefix| buckets.items(prefix))
+                    .collect(),
+            ),
+            Err(e) => Response::Error(e.to_string()),
+        };
+        send(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// the peer serving its buckets at the other end of a stream, @see serve
+pub struct StreamPeer<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: BufRead, W: Write> StreamPeer<R, W> {
+    pub fn new(reader: R, writer: W) -> StreamPeer<R, W> {
+        StreamPeer { reader, writer }
+    }
+
+    fn request<T: DeserializeOwned>(
+        &mut self,
+        request: &Request,
+    ) -> PersistenceResult<Response<T>> {
+        send(&mut self.writer, request)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(PersistenceError::IoError(
+                "the peer closed the stream".to_string(),
+            ));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+fn unexpected<T>(response: Response<T>) -> PersistenceError {
+    match response {
+        Response::Error(e) => PersistenceError::ErrorGeneric(format!("the peer failed: {}", e)),
+        _ => PersistenceError::ErrorGeneric("the peer sent an unexpected response".to_string()),
+    }
+}
+
+impl<T, R, W> Peer<T> for StreamPeer<R, W>
+where
+    T: DiffItem + DeserializeOwned,
+    R: BufRead,
+    W: Write,
+{
+    fn summaries(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<Summary>> {
+        match self.request::<T>(&Request::Summaries(prefixes.to_vec()))? {
+            Response::Summaries(summaries) => Ok(summaries),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn items(&mut self, prefixes: &[String]) -> PersistenceResult<Vec<T>> {
+        match self.request(&Request::Items(prefixes.to_vec()))? {
+            Response::Items(items) => Ok(items),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cas::{
+        content::{Address, AddressableContent, ExampleAddressableContent},
+        storage::{test_content_addressable_storage, ContentAddressableStorage},
+    };
+    use diff::buckets::{diff, diff_cas, diff_eav, Diff, EavRow};
+    use eav::{
+        EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute,
+        ExampleEntityAttributeValueStorage,
+    };
+    use holochain_json_api::json::RawString;
+    use std::{
+        collections::BTreeSet,
+        io::BufReader,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    /// compares the local buckets with remote ones served over a local TCP connection
+    fn diff_over_tcp<T>(local: &Buckets<T>, remote: Buckets<T>) -> PersistenceResult<Diff<T>>
+    where
+        T: DiffItem + Serialize + DeserializeOwned + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(&remote, BufReader::new(stream.try_clone().unwrap()), stream)
+        });
+        let stream = TcpStream::connect(address).unwrap();
+        let mut peer = StreamPeer::new(BufReader::new(stream.try_clone().unwrap()), stream);
+        let found = diff(local, &mut peer);
+        drop(peer);
+        server.join().unwrap()?;
+        found
+    }
+
+    fn content(n: usize) -> ExampleAddressableContent {
+        ExampleAddressableContent::try_from_content(&RawString::from(n.to_string()).into()).unwrap()
+    }
+
+    #[test]
+    fn cas_diffs_over_a_stream() {
+        let mut local = test_content_addressable_storage();
+        let mut remote = test_content_addressable_storage();
+        for n in 0..300 {
+            if n != 7 {
+                local.add(&content(n)).unwrap();
+            }
+            if n != 100 && n != 200 {
+                remote.add(&content(n)).unwrap();
+            }
+        }
+
+        let found = diff_over_tcp(
+            &Buckets::of_cas(&local).unwrap(),
+            Buckets::of_cas(&remote).unwrap(),
+        )
+        .unwrap();
+        let only_local: Vec<Address> = vec![content(100).address(), content(200).address()];
+        assert_eq!(
+            only_local.into_iter().collect::<BTreeSet<_>>(),
+            found.only_local
+        );
+        assert_eq!(
+            vec![content(7).address()],
+            found.only_remote.iter().cloned().collect::<Vec<_>>()
+        );
+        assert!(found.received < 100);
+        let in_process = diff_cas(&local, &remote).unwrap();
+        assert_eq!(
+            (found.only_local, found.only_remote),
+            (in_process.only_local, in_process.only_remote)
+        );
+    }
+
+    #[test]
+    fn eav_diffs_over_a_stream() {
+        let mut local = ExampleEntityAttributeValueStorage::new();
+        let mut remote = ExampleEntityAttributeValueStorage::new();
+        let row = |n: i64| {
+            EntityAttributeValueIndex::new_with_index(
+                &format!("entity {}", n % 10).into(),
+                &ExampleAttribute::default(),
+                &format!("value {}", n).into(),
+                n,
+            )
+            .unwrap()
+        };
+        for n in 0..100 {
+            local.add_eavi(&row(n)).unwrap();
+            remote.add_eavi(&row(n)).unwrap();
+        }
+        let live = |n| EavRow {
+            eavi: row(n),
+            retracted: false,
+        };
+        let retracted = |n| EavRow {
+            eavi: row(n),
+            retracted: true,
+        };
+        local.add_eavi(&row(100)).unwrap();
+        remote.add_eavi(&row(101)).unwrap();
+        remote.add_eavi(&row(102)).unwrap();
+        remote.retract_eavi(&row(102)).unwrap();
+
+        let found = diff_over_tcp(
+            &Buckets::of_eav(&local).unwrap(),
+            Buckets::of_eav(&remote).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![live(100)],
+            found.only_local.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![live(101), retracted(102)],
+            found.only_remote.into_iter().collect::<Vec<_>>()
+        );
+        assert!(diff_eav(&local, &local.clone()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn eav_diffs_find_rows_only_one_side_retracted() {
+        let row = EntityAttributeValueIndex::new_with_index(
+            &"entity".into(),
+            &ExampleAttribute::default(),
+            &"value".into(),
+            1,
+        )
+        .unwrap();
+        let mut local = ExampleEntityAttributeValueStorage::new();
+        let mut remote = ExampleEntityAttributeValueStorage::new();
+        local.add_eavi(&row).unwrap();
+        remote.add_eavi(&row).unwrap();
+        remote.retract_eavi(&row).unwrap();
+
+        let found = diff_eav(&local, &remote).unwrap();
+        assert_eq!(
+            vec![EavRow {
+                eavi: row.clone(),
+                retracted: false
+            }],
+            found.only_local.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![EavRow {
+                eavi: row,
+                retracted: true
+            }],
+            found.only_remote.into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn peers_report_requests_they_cannot_read() {
+        let buckets = Buckets::new(vec![Address::from("foo")]);
+        let mut answers = Vec::new();
+        serve(&buckets, "not json\n".as_bytes(), &mut answers).unwrap();
+        let mut peer = StreamPeer::new(answers.as_slice(), Vec::new());
+        match Peer::<Address>::summaries(&mut peer, &[String::new()]) {
+            Err(PersistenceError::ErrorGeneric(message)) => {
+                assert!(message.starts_with("the peer failed"))
+            }
+            other => panic!("expected the peer to fail, got {:?}", other),
+        }
+        // nothing more to read
+        assert!(Peer::<Address>::items(&mut peer, &[String::new()]).is_err());
+    }
+}
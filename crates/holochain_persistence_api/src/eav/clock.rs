@@ -0,0 +1,225 @@
+//! Clocks that stamp new EAVIs with their index.
+//! Indexes order the rows of one entity and decide which is the latest, so with the wall clock
+//! that order depends on the skew between the agents' clocks. Logical clocks instead order
+//! every row after the rows they have seen, wherever those came from.
+//! Each storage has its own clock, @see EntityAttributeValueStorage::new_eavi, which observes
+//! the index of every row added to it. Storages hold it as an Arc<dyn Clock> so that clones
+//! share it.
+
+use chrono::offset::Utc;
+use eav::eavi::Index;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+
+/// gives the indexes of new EAVIs
+pub trait Clock: Send + Sync + Debug {
+    /// the index for a row added now
+    fn now(&self) -> Index;
+
+    /// learns of a row with the index, so that later rows come after it
+    fn observe(&self, _index: Index) {}
+}
+
+/// the nanoseconds since the epoch, which is how EAVIs have always been stamped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> Index {
+        Utc::now().timestamp_nanos()
+    }
+}
+
+/// a counter that goes up by one for each row and past every index it observes
+#[derive(Debug, Default)]
+pub struct LamportClock {
+    last: AtomicI64,
+}
+
+impl LamportClock {
+    pub fn new() -> LamportClock {
+        Default::default()
+    }
+
+    /// a clock whose next index is after last
+    pub fn starting_after(last: Index) -> LamportClock {
+        LamportClock {
+            last: AtomicI64::new(last),
+        }
+    }
+}
+
+impl Clock for LamportClock {
+    fn now(&self) -> Index {
+        self.last.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn observe(&self, index: Index) {
+        raise_to(&self.last, index);
+    }
+}
+
+/// raises last to index if it is behind it
+fn raise_to(last: &AtomicI64, index: Index) {
+    let mut current = last.load(Ordering::SeqCst);
+    while current < index {
+        match last.compare_exchange(current, index, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+/// A hybrid logical clock: the physical time, unless it is not after the last index given or
+/// observed, in which case the one after that.
+/// Indexes stay close to the physical time but always go up, even when the physical clock
+/// goes back or a row from an agent whose clock is ahead is observed.
+#[derive(Debug)]
+pub struct HybridLogicalClock<C: Clock = WallClock> {
+    physical: C,
+    last: AtomicI64,
+}
+
+impl HybridLogicalClock {
+    /// a clock on the wall clock
+    pub fn new() -> HybridLogicalClock {
+        Default::default()
+    }
+}
+
+impl<C: Clock> HybridLogicalClock<C> {
+    /// a clock on the given physical clock
+    pub fn with_physical(physical: C) -> HybridLogicalClock<C> {
+        HybridLogicalClock {
+            physical,
+            last: AtomicI64::new(std::i64::MIN),
+        }
+    }
+}
+
+impl<C: Clock + Default> Default for HybridLogicalClock<C> {
+    fn default() -> HybridLogicalClock<C> {
+        HybridLogicalClock::with_physical(C::default())
+    }
+}
+
+impl<C: Clock> Clock for HybridLogicalClock<C> {
+    fn now(&self) -> Index {
+        let physical = self.physical.now();
+        let mut last = self.last.load(Ordering::SeqCst);
+        loop {
+            let next = physical.max(last.saturating_add(1));
+            match self
+                .last
+                .compare_exchange(last, next, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return next,
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    fn observe(&self, index: Index) {
+        raise_to(&self.last, index);
+    }
+}
+
+/// A clock for tests, giving the indexes from a start onwards by a fixed step.
+/// Observing an index doesn't move it, so the indexes are always the same.
+/// all clones share the same next index
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    next: Arc<AtomicI64>,
+    step: Index,
+}
+
+impl MockClock {
+    /// a clock giving start, start + 1 and so on
+    pub fn new(start: Index) -> MockClock {
+        MockClock::with_step(start, 1)
+    }
+
+    pub fn with_step(start: Index, step: Index) -> MockClock {
+        MockClock {
+            next: Arc::new(AtomicI64::new(start)),
+            step,
+        }
+    }
+
+    /// makes the next index the given one
+    pub fn set(&self, next: Index) {
+        self.next.store(next, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Index {
+        self.next.fetch_add(self.step, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lamport_clocks_count_past_what_they_observe() {
+        let clock = LamportClock::new();
+        assert_eq!(1, clock.now());
+        assert_eq!(2, clock.now());
+        clock.observe(10);
+        assert_eq!(11, clock.now());
+        // observing the past changes nothing
+        clock.observe(3);
+        assert_eq!(12, clock.now());
+        assert_eq!(101, LamportClock::starting_after(100).now());
+    }
+
+    #[test]
+    fn hybrid_clocks_follow_physical_time_but_never_go_back() {
+        let clock = HybridLogicalClock::with_physical(MockClock::with_step(100, 10));
+        assert_eq!(100, clock.now());
+        assert_eq!(110, clock.now());
+
+        // a row from a clock that is ahead, until the physical clock catches up
+        clock.observe(135);
+        assert_eq!(136, clock.now());
+        assert_eq!(137, clock.now());
+        assert_eq!(140, clock.now());
+
+        // a physical clock going back
+        let clock = HybridLogicalClock::with_physical(MockClock::with_step(100, -10));
+        assert_eq!(100, clock.now());
+        assert_eq!(101, clock.now());
+
+        let wall = HybridLogicalClock::new();
+        let first = wall.now();
+        assert!(wall.now() > first);
+    }
+
+    #[test]
+    fn mock_clocks_are_deterministic() {
+        let clock = MockClock::new(5);
+        assert_eq!(5, clock.now());
+        assert_eq!(6, clock.now());
+        clock.observe(100);
+        assert_eq!(7, clock.now());
+        clock.clone().set(-3);
+        assert_eq!(-3, clock.now());
+    }
+
+    #[test]
+    fn shared_clocks_are_shared_between_clones() {
+        let clock: Arc<dyn Clock> = Arc::new(LamportClock::new());
+        let clone = clock.clone();
+        assert_eq!(1, clock.now());
+        assert_eq!(2, clone.now());
+        clone.observe(7);
+        assert_eq!(8, clock.now());
+    }
+}
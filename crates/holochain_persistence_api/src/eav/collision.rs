@@ -0,0 +1,129 @@
+//! The policy every storage follows when an eavi is added at an index another eavi of its
+//! entity already has.
+//! Nothing is ever re-indexed. Eavis are unique by entity, index and address, so an eavi only
+//! collides with itself: it is stored at its own index, next to the other eavis of its entity
+//! there, and adding it again stores it once. What a storage holds only depends on which eavis
+//! were added to it, not on the order they were added in or on which was added first, so
+//! storages that merge each other's rows converge, @see merge.
+//! Storages that key eavis by entity and index, as the LMDB and pickle ones do, key the first
+//! eavi stored at an index that way and the others by their address as well, @see
+//! resolve_collision.
+
+use eav::eavi::{Attribute, EntityAttributeValueIndex};
+
+/// what is stored under a key an eavi could be stored under
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// nothing
+    Free,
+    /// the same eavi
+    Same,
+    /// another eavi
+    Taken,
+}
+
+impl Slot {
+    /// the slot of the candidate, given the eavi stored under the key
+    pub fn of<A: Attribute>(
+        stored: Option<&EntityAttributeValueIndex<A>>,
+        candidate: &EntityAttributeValueIndex<A>,
+    ) -> Slot {
+        match stored {
+            None => Slot::Free,
+            Some(stored) if stored == candidate => Slot::Same,
+            Some(_) => Slot::Taken,
+        }
+    }
+}
+
+/// what adding an eavi does
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Placement<K> {
+    /// stores the eavi under the key, which holds nothing
+    Add(K),
+    /// nothing, as the eavi is already stored under the key
+    Stored(K),
+}
+
+impl<K> Placement<K> {
+    /// the key the eavi is stored under
+    pub fn key(&self) -> &K {
+        match self {
+            Placement::Add(key) | Placement::Stored(key) => key,
+        }
+    }
+
+    pub fn into_key(self) -> K {
+        match self {
+            Placement::Add(key) | Placement::Stored(key) => key,
+        }
+    }
+}
+
+/// Finds the key to add an eavi under, given the slot of the eavi under each of the keys it
+/// could be stored under, in the order they are tried.
+/// The eavi goes under the first key that holds nothing, unless it is stored under an earlier
+/// one. Eavis are only ever added under the first key free at the time, so an eavi that isn't
+/// under the keys tried before a free one isn't stored.
+/// The last key should be one no other eavi can have, such as one with the eavi's address, and
+/// is where the eavi goes if every key is taken.
+pub fn resolve_collision<K, E, F>(keys: Vec<K>, mut slot: F) -> Result<Placement<K>, E>
+where
+    F: FnMut(&K) -> Result<Slot, E>,
+{
+    let last = keys.len().saturating_sub(1);
+    for (i, key) in keys.into_iter().enumerate() {
+        match slot(&key)? {
+            Slot::Free => return Ok(Placement::Add(key)),
+            Slot::Same => return Ok(Placement::Stored(key)),
+            Slot::Taken if i == last => return Ok(Placement::Add(key)),
+            Slot::Taken => (),
+        }
+    }
+    panic!("an eavi needs at least one key to be stored under")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::ExampleAttribute;
+    use error::PersistenceError;
+    use std::collections::BTreeMap;
+
+    fn eavi(value: &str, index: i64) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new_with_index(
+            &"entity".into(),
+            &ExampleAttribute::default(),
+            &value.into(),
+            index,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn collisions_take_the_next_free_key() {
+        // keyed by index, and by index and value once another eavi has the index
+        let mut stored = BTreeMap::new();
+        let mut add = |row: EntityAttributeValueIndex<ExampleAttribute>| {
+            let keys = vec![
+                row.index().to_string(),
+                format!("{}::{}", row.index(), row.value()),
+            ];
+            let placement = resolve_collision::<_, PersistenceError, _>(keys, |key| {
+                Ok(Slot::of(stored.get(key), &row))
+            })
+            .unwrap();
+            if let Placement::Add(key) = &placement {
+                stored.insert(key.clone(), row);
+            }
+            placement
+        };
+
+        assert_eq!(Placement::Add("1".to_string()), add(eavi("a", 1)));
+        assert_eq!(Placement::Add("1::b".to_string()), add(eavi("b", 1)));
+        assert_eq!(Placement::Add("2".to_string()), add(eavi("b", 2)));
+        // adding an eavi again finds it where it was added
+        assert_eq!(Placement::Stored("1".to_string()), add(eavi("a", 1)));
+        assert_eq!(Placement::Stored("1::b".to_string()), add(eavi("b", 1)));
+    }
+}
@@ -8,9 +8,9 @@ use crate::{
     error::PersistenceResult,
 };
 
-use chrono::offset::Utc;
 use eav::{
-    query::{EaviQuery, IndexFilter},
+    clock::{Clock, WallClock},
+    query::{EaviQuery, FilterKey, IndexFilter},
     storage::{EntityAttributeValueStorage, ExampleEntityAttributeValueStorage},
 };
 use holochain_json_api::{
@@ -60,6 +60,12 @@ impl Default for ExampleAttribute {
     }
 }
 
+impl FilterKey for ExampleAttribute {
+    fn filter_key(&self) -> String {
+        self.to_string()
+    }
+}
+
 impl From<String> for ExampleAttribute {
     fn from(str: String) -> Self {
         if str == "without-payload" {
@@ -102,9 +108,9 @@ pub type Value = Address;
 // @see https://papers.radixdlt.com/tempo/#logical-clocks
 pub type Index = i64;
 
-// @TODO do we need this?
-// source agent asserting the meta
-// type Source ...
+/// Address of the agent asserting the meta
+pub type Source = Address;
+
 /// The basic struct for EntityAttributeValue triple, implemented as AddressableContent
 /// including the necessary serialization inherited.
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize, PartialOrd, Ord)]
@@ -113,7 +119,10 @@ pub struct EntityAttributeValueIndex<A: Attribute> {
     entity: Entity,
     value: Value,
     attribute: A,
-    // source: Source,
+    /// left out of the JSON when None, so eavis without a source keep their content and
+    /// address, and JSON stored before there were sources still loads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
 }
 
 impl<A: Attribute> From<&EntityAttributeValueIndex<A>> for JsonString
@@ -186,18 +195,23 @@ fn validate_attribute<A: Attribute>(_attribute: &A) -> JsonResult<()> {
 }
 
 impl<A: Attribute> EntityAttributeValueIndex<A> {
+    /// an eavi stamped by the wall clock, @see new_with_clock
     pub fn new(
         entity: &Entity,
         attribute: &A,
         value: &Value,
     ) -> PersistenceResult<EntityAttributeValueIndex<A>> {
-        validate_attribute(attribute)?;
-        Ok(EntityAttributeValueIndex {
-            entity: entity.clone(),
-            attribute: attribute.clone(),
-            value: value.clone(),
-            index: Utc::now().timestamp_nanos(),
-        })
+        EntityAttributeValueIndex::new_with_clock(entity, attribute, value, &WallClock)
+    }
+
+    /// an eavi stamped with the clock's index for now
+    pub fn new_with_clock(
+        entity: &Entity,
+        attribute: &A,
+        value: &Value,
+        clock: &dyn Clock,
+    ) -> PersistenceResult<EntityAttributeValueIndex<A>> {
+        EntityAttributeValueIndex::new_with_index(entity, attribute, value, clock.now())
     }
 
     pub fn new_with_index(
@@ -212,6 +226,7 @@ impl<A: Attribute> EntityAttributeValueIndex<A> {
             attribute: attribute.clone(),
             value: value.clone(),
             index: timestamp,
+            source: None,
         })
     }
 
@@ -234,6 +249,21 @@ impl<A: Attribute> EntityAttributeValueIndex<A> {
     pub fn set_index(&mut self, new_index: i64) {
         self.index = new_index
     }
+
+    /// the agent that asserted the eavi, if known
+    pub fn source(&self) -> Option<Source> {
+        self.source.clone()
+    }
+
+    pub fn set_source(&mut self, source: Option<Source>) {
+        self.source = source
+    }
+
+    /// the eavi as asserted by the source
+    pub fn with_source(mut self, source: &Source) -> Self {
+        self.source = Some(source.clone());
+        self
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, DefaultJson)]
@@ -339,10 +369,11 @@ pub mod tests {
                 test_content_addressable_storage, EavTestSuite, ExampleContentAddressableStorage,
             },
         },
-        eav::EntityAttributeValueIndex,
+        eav::{eavi_stream, EavFilter, EntityAttributeValueIndex, LamportClock},
     };
     use fixture::{test_eav, test_eav_address, test_eav_content, test_eav_entity};
     use holochain_json_api::json::RawString;
+    use std::sync::Arc;
 
     pub fn test_eav_storage<A: Attribute>() -> ExampleEntityAttributeValueStorage<A>
     where
@@ -408,6 +439,93 @@ pub mod tests {
         });
     }
 
+    #[test]
+    fn example_eav_retraction() {
+        EavTestSuite::test_retraction(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_pagination() {
+        EavTestSuite::test_pagination(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_fetch_iter() {
+        EavTestSuite::test_fetch_iter(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_counts() {
+        EavTestSuite::test_counts(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_key_filters() {
+        EavTestSuite::test_key_filters(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_traversal() {
+        EavTestSuite::test_traversal(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_join() {
+        EavTestSuite::test_join(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_range_tombstone() {
+        EavTestSuite::test_range_tombstone(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_source() {
+        EavTestSuite::test_source(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_clock() {
+        EavTestSuite::test_clock(test_eav_storage().with_clock(Arc::new(LamportClock::new())));
+    }
+
+    #[test]
+    fn example_eav_collisions() {
+        EavTestSuite::test_collisions(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_merge() {
+        EavTestSuite::test_merge(test_eav_storage(), test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_live() {
+        EavTestSuite::test_live(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_as_of() {
+        EavTestSuite::test_as_of(test_eav_storage());
+    }
+
+    #[test]
+    fn example_eav_stream() {
+        let mut eav_storage = test_eav_storage();
+        let eavi = test_eav();
+        eav_storage.add_eavi(&eavi).unwrap();
+        let query = EaviQuery::new(
+            Some(eavi.entity()).into(),
+            EavFilter::default(),
+            EavFilter::default(),
+            IndexFilter::Range(None, None),
+            None,
+        );
+        let rows = eavi_stream(eav_storage.fetch_eavi_iter(&query).unwrap());
+        let streamed: Vec<_> = futures::executor::block_on_stream(rows).collect();
+        assert_eq!(vec![Ok(eavi)], streamed);
+    }
+
     #[test]
     /// show AddressableContent implementation
     fn addressable_content_test() {
@@ -427,6 +545,23 @@ pub mod tests {
         >(addressable_contents, test_content_addressable_storage());
     }
 
+    #[test]
+    fn sources_are_optional_in_json() {
+        let eavi = test_eav();
+        let json = eavi.content().to_string();
+        // rows without a source serialize as they did before sources existed
+        assert!(!json.contains("source"));
+        let parsed: EntityAttributeValueIndex<ExampleAttribute> =
+            serde_json::from_str(&json).unwrap();
+        assert_eq!(None, parsed.source());
+
+        let sourced = eavi.clone().with_source(&"author".into());
+        assert_ne!(eavi.address(), sourced.address());
+        let parsed: EntityAttributeValueIndex<ExampleAttribute> =
+            serde_json::from_str(&sourced.content().to_string()).unwrap();
+        assert_eq!(Some("author".into()), parsed.source());
+    }
+
     #[test]
     fn validate_attribute_paths() {
         assert!(EntityAttributeValueIndex::new(
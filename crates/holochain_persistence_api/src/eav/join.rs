@@ -0,0 +1,283 @@
+//! Conjunctive queries over EAV triples, Datalog style.
+//! A JoinQuery is a list of patterns whose terms are values or variables, and it finds every
+//! way to bind the variables so that each pattern matches a stored row. Patterns are run one
+//! at a time, most selective first, each once for every binding found so far.
+
+use cas::content::Address;
+use eav::{
+    eavi::{Attribute, EntityAttributeValueIndex},
+    query::{EavFilter, EaviQuery, IndexFilter},
+};
+use error::PersistenceResult;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// a place in a pattern, which is either a value or a variable to bind
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Term<T> {
+    Var(String),
+    Val(T),
+}
+
+impl<T> Term<T> {
+    pub fn var<S: Into<String>>(name: S) -> Term<T> {
+        Term::Var(name.into())
+    }
+
+    pub fn val(value: T) -> Term<T> {
+        Term::Val(value)
+    }
+}
+
+/// what a variable is bound to
+/// A variable used both for an attribute and for an address never matches.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Binding<A> {
+    Address(Address),
+    Attribute(A),
+}
+
+/// the value of every variable in one answer to a JoinQuery
+pub type Bindings<A> = BTreeMap<String, Binding<A>>;
+
+/// One triple a row has to match.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pattern<A: Attribute> {
+    pub entity: Term<Address>,
+    pub attribute: Term<A>,
+    pub value: Term<Address>,
+}
+
+impl<A: Attribute> Pattern<A> {
+    pub fn new(entity: Term<Address>, attribute: Term<A>, value: Term<Address>) -> Pattern<A> {
+        Pattern {
+            entity,
+            attribute,
+            value,
+        }
+    }
+
+    /// the variables the pattern binds
+    pub fn vars(&self) -> BTreeSet<&str> {
+        let mut vars = BTreeSet::new();
+        for term in [&self.entity, &self.value].iter() {
+            if let Term::Var(var) = term {
+                vars.insert(var.as_str());
+            }
+        }
+        if let Term::Var(var) = &self.attribute {
+            vars.insert(var.as_str());
+        }
+        vars
+    }
+
+    /// How few rows the pattern should match once the bound variables are known, the higher
+    /// the fewer. A known entity narrows the most, then a known value, then a known attribute.
+    pub fn selectivity(&self, bound: &BTreeSet<&str>) -> u8 {
+        let known = |var: Option<&String>| var.into_iter().all(|var| bound.contains(var.as_str()));
+        (known(var_of(&self.entity)) as u8) * 4
+            + (known(var_of(&self.value)) as u8) * 2
+            + (known(var_of(&self.attribute)) as u8)
+    }
+
+    /// the query for the rows matching the pattern under the bindings
+    pub fn query<'a>(&self, bindings: &Bindings<A>) -> EaviQuery<'a, A>
+    where
+        A: 'a,
+    {
+        let address = |term: &Term<Address>| match term {
+            Term::Val(address) => EavFilter::single(address.clone()),
+            Term::Var(var) => match bindings.get(var) {
+                Some(Binding::Address(address)) => EavFilter::single(address.clone()),
+                // an attribute can't match an address
+                Some(Binding::Attribute(_)) => EavFilter::predicate(|_| false),
+                None => EavFilter::default(),
+            },
+        };
+        let attribute = match &self.attribute {
+            Term::Val(attribute) => EavFilter::single(attribute.clone()),
+            Term::Var(var) => match bindings.get(var) {
+                Some(Binding::Attribute(attribute)) => EavFilter::single(attribute.clone()),
+                Some(Binding::Address(_)) => EavFilter::predicate(|_| false),
+                None => EavFilter::default(),
+            },
+        };
+        EaviQuery::new(
+            address(&self.entity),
+            attribute,
+            address(&self.value),
+            IndexFilter::Range(None, None),
+            None,
+        )
+    }
+
+    /// the bindings extended by the row, or None if the row contradicts them
+    pub fn unify(
+        &self,
+        bindings: &Bindings<A>,
+        row: &EntityAttributeValueIndex<A>,
+    ) -> Option<Bindings<A>> {
+        let mut bindings = bindings.clone();
+        let terms = [
+            (var_of(&self.entity), Binding::Address(row.entity())),
+            (var_of(&self.attribute), Binding::Attribute(row.attribute())),
+            (var_of(&self.value), Binding::Address(row.value())),
+        ];
+        for (var, binding) in terms.iter() {
+            let var = match var {
+                Some(var) => var,
+                None => continue,
+            };
+            match bindings.get(*var) {
+                Some(bound) if bound != binding => return None,
+                Some(_) => (),
+                None => {
+                    bindings.insert(var.to_string(), binding.clone());
+                }
+            }
+        }
+        Some(bindings)
+    }
+}
+
+fn var_of<T>(term: &Term<T>) -> Option<&String> {
+    match term {
+        Term::Var(var) => Some(var),
+        Term::Val(_) => None,
+    }
+}
+
+/// Patterns that must all match, sharing variables between them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JoinQuery<A: Attribute> {
+    patterns: Vec<Pattern<A>>,
+}
+
+impl<A: Attribute> JoinQuery<A> {
+    pub fn new(patterns: Vec<Pattern<A>>) -> JoinQuery<A> {
+        JoinQuery { patterns }
+    }
+
+    /// adds a pattern that must also match
+    pub fn with(mut self, pattern: Pattern<A>) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    pub fn patterns(&self) -> &[Pattern<A>] {
+        &self.patterns
+    }
+
+    /// The order to run the patterns in, as indexes into patterns.
+    /// Each step takes the most selective pattern given the variables bound by the ones before
+    /// it, the earliest of them on a tie.
+    pub fn plan(&self) -> Vec<usize> {
+        let mut bound = BTreeSet::new();
+        let mut remaining: Vec<usize> = (0..self.patterns.len()).collect();
+        let mut order = Vec::new();
+        while !remaining.is_empty() {
+            let (position, _) = remaining
+                .iter()
+                .enumerate()
+                .max_by_key(|(position, i)| {
+                    // max_by_key takes the last of equals, so earlier patterns rank higher
+                    (
+                        self.patterns[**i].selectivity(&bound),
+                        std::usize::MAX - position,
+                    )
+                })
+                .expect("remaining is not empty");
+            let next = remaining.remove(position);
+            bound.extend(self.patterns[next].vars());
+            order.push(next);
+        }
+        order
+    }
+
+    /// Finds every binding of the variables under which all the patterns match a row, using
+    /// fetch to get the rows matching each pattern's query.
+    /// No patterns have one answer, binding nothing.
+    pub fn run<F>(&self, mut fetch: F) -> PersistenceResult<BTreeSet<Bindings<A>>>
+    where
+        F: FnMut(&EaviQuery<A>) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>,
+    {
+        let mut answers = BTreeSet::new();
+        answers.insert(Bindings::new());
+        for i in self.plan() {
+            let pattern = &self.patterns[i];
+            let mut extended = BTreeSet::new();
+            for bindings in answers.iter() {
+                for row in fetch(&pattern.query(bindings))? {
+                    extended.extend(pattern.unify(bindings, &row));
+                }
+            }
+            if extended.is_empty() {
+                return Ok(extended);
+            }
+            answers = extended;
+        }
+        Ok(answers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::ExampleAttribute;
+
+    fn pattern(entity: &str, attribute: &str, value: &str) -> Pattern<ExampleAttribute> {
+        let address = |term: &str| {
+            if term.starts_with('?') {
+                Term::var(&term[1..])
+            } else {
+                Term::val(term.into())
+            }
+        };
+        let attribute = if attribute.starts_with('?') {
+            Term::var(&attribute[1..])
+        } else {
+            Term::val(ExampleAttribute::WithPayload(attribute.to_string()))
+        };
+        Pattern::new(address(entity), attribute, address(value))
+    }
+
+    #[test]
+    fn plans_most_selective_patterns_first() {
+        let query = JoinQuery::new(vec![
+            pattern("?e", "?a", "?v"),
+            pattern("?e", "likes", "?v"),
+            pattern("?x", "likes", "apple"),
+            pattern("?x", "friend", "?e"),
+        ]);
+        // the pattern with a value goes first, then its entity binds ?x, which binds ?e
+        assert_eq!(vec![2, 3, 1, 0], query.plan());
+        assert!(JoinQuery::<ExampleAttribute>::default().plan().is_empty());
+    }
+
+    #[test]
+    fn unifies_rows_with_bindings() {
+        let row = EntityAttributeValueIndex::new(
+            &"a".into(),
+            &ExampleAttribute::WithPayload("link".into()),
+            &"a".into(),
+        )
+        .unwrap();
+        let bindings = pattern("?x", "?l", "?x")
+            .unify(&Bindings::new(), &row)
+            .unwrap();
+        assert_eq!(Some(&Binding::Address("a".into())), bindings.get("x"));
+        assert_eq!(
+            Some(&Binding::Attribute(ExampleAttribute::WithPayload(
+                "link".into()
+            ))),
+            bindings.get("l")
+        );
+        // the entity and value differ so ?x can't be both
+        let mut b = Bindings::new();
+        b.insert("x".into(), Binding::Address("b".into()));
+        assert_eq!(None, pattern("?x", "link", "?y").unify(&b, &row));
+        assert_eq!(
+            None,
+            pattern("?x", "?x", "?y").unify(&Bindings::new(), &row)
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! Live queries, which keep the rows of a query up to date as rows are written.
+//! Storages hold a LiveRegistry shared by their clones, and run its live queries again after
+//! every row added or retracted through any of them, sending the rows that came and went.
+//! Queries are run in full, so a tombstone that replaces the LatestByAttribute of its entity
+//! and value shows up as the one row going and the other coming. Rows written by another
+//! process, or through another handle onto the same files, are only seen by the next change.
+
+use eav::{
+    clock::Clock,
+    eavi::{Attribute, Entity, EntityAttributeValueIndex},
+    join::{Bindings, JoinQuery},
+    query::{EaviPage, EaviQuery, FilterKey},
+    spec::EaviQuerySpec,
+    storage::{EaviIter, EntityAttributeValueStorage},
+    traverse::{Traversal, TraversalStep},
+};
+use error::PersistenceResult;
+use holochain_logging::prelude::*;
+use reporting::{ReportStorage, StorageReport};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+use uuid::Uuid;
+
+/// the rows that came into the results of a live query and those that left them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveChange<A: Attribute> {
+    pub added: BTreeSet<EntityAttributeValueIndex<A>>,
+    pub removed: BTreeSet<EntityAttributeValueIndex<A>>,
+}
+
+impl<A: Attribute> LiveChange<A> {
+    /// what changed from the old rows to the new ones
+    fn between(
+        old: &BTreeSet<EntityAttributeValueIndex<A>>,
+        new: &BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> LiveChange<A> {
+        LiveChange {
+            added: new.difference(old).cloned().collect(),
+            removed: old.difference(new).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub type LiveQueryId = u64;
+
+/// The receiving end of a live query. Its first change adds the rows the query returned when it
+/// was subscribed, each change after that is from a write.
+/// Dropping it ends the query.
+#[derive(Debug)]
+pub struct LiveQuery<A: Attribute> {
+    id: LiveQueryId,
+    changes: Receiver<LiveChange<A>>,
+}
+
+impl<A: Attribute> LiveQuery<A> {
+    pub fn id(&self) -> LiveQueryId {
+        self.id
+    }
+
+    pub fn changes(&self) -> &Receiver<LiveChange<A>> {
+        &self.changes
+    }
+}
+
+/// a spec kept for running its live query again, which storages can do without their
+/// attributes being FilterKeys
+trait LiveSpec<A: Attribute>: Debug + Send + Sync {
+    fn to_query<'a>(&self) -> PersistenceResult<EaviQuery<'a, A>>
+    where
+        A: 'a;
+}
+
+impl<A: Attribute + FilterKey + Send + Sync> LiveSpec<A> for EaviQuerySpec<A> {
+    fn to_query<'a>(&self) -> PersistenceResult<EaviQuery<'a, A>>
+    where
+        A: 'a,
+    {
+        EaviQuerySpec::to_query(self)
+    }
+}
+
+#[derive(Debug)]
+struct Subscription<A: Attribute> {
+    spec: Arc<dyn LiveSpec<A>>,
+    /// the rows last sent
+    rows: BTreeSet<EntityAttributeValueIndex<A>>,
+    /// the update the rows were fetched for, None until the first change is sent
+    seen: Option<u64>,
+    sender: Sender<LiveChange<A>>,
+}
+
+impl<A: Attribute> Subscription<A> {
+    /// Sends what changed from the rows last sent to rows fetched for the update, unless they
+    /// were fetched for an earlier update than those. Returns false if the receiver was dropped.
+    fn apply(&mut self, update: u64, rows: BTreeSet<EntityAttributeValueIndex<A>>) -> bool {
+        if self.seen >= Some(update) {
+            return true;
+        }
+        let first = self.seen.is_none();
+        self.seen = Some(update);
+        let change = LiveChange::between(&self.rows, &rows);
+        self.rows = rows;
+        (change.is_empty() && !first) || self.sender.send(change).is_ok()
+    }
+}
+
+#[derive(Debug)]
+struct Subscriptions<A: Attribute> {
+    next: LiveQueryId,
+    /// counts updates, so that rows fetched for an update are never replaced by rows fetched
+    /// earlier for an earlier one
+    updates: u64,
+    live: BTreeMap<LiveQueryId, Subscription<A>>,
+}
+
+/// The live queries of a storage. Storages keep it next to their rows, so all their clones
+/// share it, and update it with every row they add or retract.
+#[derive(Clone, Debug)]
+pub struct LiveRegistry<A: Attribute> {
+    subscriptions: Arc<Mutex<Subscriptions<A>>>,
+}
+
+impl<A: Attribute> Default for LiveRegistry<A> {
+    fn default() -> LiveRegistry<A> {
+        LiveRegistry {
+            subscriptions: Arc::new(Mutex::new(Subscriptions {
+                next: 0,
+                updates: 0,
+                live: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl<A: Attribute> LiveRegistry<A> {
+    pub fn new() -> LiveRegistry<A> {
+        Default::default()
+    }
+
+    /// Starts a live query of the storage, failing if the spec doesn't convert into a query.
+    /// Every row added or retracted that the query's filters let through runs the whole query
+    /// again, so each live query costs writes like it matching them costs a fetch_eavi.
+    pub fn subscribe<S>(
+        &self,
+        storage: &S,
+        spec: &EaviQuerySpec<A>,
+    ) -> PersistenceResult<LiveQuery<A>>
+    where
+        A: FilterKey + Send + Sync + 'static,
+        S: EntityAttributeValueStorage<A> + ?Sized,
+    {
+        let query = spec.to_query()?;
+        let (sender, changes) = channel();
+        // subscribed before its rows are fetched, so no write in between is missed
+        let (id, update) = {
+            let mut subscriptions = self.subscriptions.lock()?;
+            subscriptions.updates += 1;
+            let id = subscriptions.next;
+            subscriptions.next += 1;
+            subscriptions.live.insert(
+                id,
+                Subscription {
+                    spec: Arc::new(spec.clone()),
+                    rows: BTreeSet::new(),
+                    seen: None,
+                    sender,
+                },
+            );
+            (id, subscriptions.updates)
+        };
+        match storage.fetch_eavi(&query) {
+            Ok(rows) => {
+                self.apply(update, vec![(id, Ok(rows))]);
+                Ok(LiveQuery { id, changes })
+            }
+            Err(e) => {
+                self.unsubscribe(id)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Ends a live query, returning false if it had already ended.
+    pub fn unsubscribe(&self, id: LiveQueryId) -> PersistenceResult<bool> {
+        Ok(self.subscriptions.lock()?.live.remove(&id).is_some())
+    }
+
+    /// how many live queries are running
+    pub fn count(&self) -> PersistenceResult<usize> {
+        Ok(self.subscriptions.lock()?.live.len())
+    }
+
+    /// Runs the live queries the row written to the storage can affect again and sends what
+    /// changed. Queries are run without holding the registry, so writers don't wait on each
+    /// other's queries. Queries whose receiver was dropped are ended, as are queries that fail,
+    /// which are logged, since the row has been written whatever becomes of them.
+    /// Storages call it once the row is written and their locks are released.
+    pub fn update<S>(&self, storage: &S, written: &EntityAttributeValueIndex<A>)
+    where
+        S: EntityAttributeValueStorage<A> + ?Sized,
+    {
+        let (update, specs) = match self.subscriptions.lock() {
+            Ok(mut subscriptions) => {
+                subscriptions.updates += 1;
+                let specs: Vec<_> = subscriptions
+                    .live
+                    .iter()
+                    .map(|(id, subscription)| (*id, subscription.spec.clone()))
+                    .collect();
+                (subscriptions.updates, specs)
+            }
+            Err(e) => {
+                warn!("live queries not updated: {}", e);
+                return;
+            }
+        };
+        let mut fetched = Vec::new();
+        for (id, spec) in specs {
+            let rows = spec.to_query().and_then(|query| {
+                if query.is_affected_by(written) {
+                    storage.fetch_eavi(&query).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+            match rows {
+                Ok(Some(rows)) => fetched.push((id, Ok(rows))),
+                Ok(None) => (),
+                Err(e) => fetched.push((id, Err(e))),
+            }
+        }
+        self.apply(update, fetched);
+    }
+
+    /// sends the changes in the rows fetched for an update, ending the queries that failed
+    fn apply(
+        &self,
+        update: u64,
+        fetched: Vec<(
+            LiveQueryId,
+            PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>,
+        )>,
+    ) {
+        let mut subscriptions = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions,
+            Err(e) => {
+                warn!("live queries not updated: {}", e);
+                return;
+            }
+        };
+        for (id, rows) in fetched {
+            let live = match (subscriptions.live.get_mut(&id), rows) {
+                // unsubscribed since
+                (None, _) => continue,
+                (Some(subscription), Ok(rows)) => subscription.apply(update, rows),
+                (Some(_), Err(e)) => {
+                    warn!("live query {} ended, it failed to run: {}", id, e);
+                    false
+                }
+            };
+            if !live {
+                subscriptions.live.remove(&id);
+            }
+        }
+    }
+}
+
+/// A storage to subscribe live queries to, which are kept up to date with the rows written
+/// through it, the storage it wraps, or any of their clones, @see LiveRegistry
+/// Storages without a registry of their own only have the rows written through the
+/// LiveEavStorage and its clones seen.
+#[derive(Clone, Debug)]
+pub struct LiveEavStorage<A: Attribute, S> {
+    storage: S,
+    live: LiveRegistry<A>,
+}
+
+impl<A, S> LiveEavStorage<A, S>
+where
+    A: Attribute + FilterKey + Send + Sync + 'static,
+    S: EntityAttributeValueStorage<A>,
+{
+    pub fn new(storage: S) -> LiveEavStorage<A, S> {
+        let live = storage.live_registry().cloned().unwrap_or_default();
+        LiveEavStorage { storage, live }
+    }
+
+    /// the wrapped storage, which shares its live queries if it keeps a registry
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Starts a live query, failing if the spec doesn't convert into a query.
+    pub fn subscribe(&self, spec: &EaviQuerySpec<A>) -> PersistenceResult<LiveQuery<A>> {
+        self.live.subscribe(&self.storage, spec)
+    }
+
+    /// Ends a live query, returning false if it had already ended.
+    pub fn unsubscribe(&self, id: LiveQueryId) -> PersistenceResult<bool> {
+        self.live.unsubscribe(id)
+    }
+
+    /// how many live queries are running
+    pub fn live_queries(&self) -> PersistenceResult<usize> {
+        self.live.count()
+    }
+}
+
+impl<A, S> EntityAttributeValueStorage<A> for LiveEavStorage<A, S>
+where
+    A: Attribute + FilterKey + Send + Sync + 'static,
+    S: EntityAttributeValueStorage<A> + Clone + 'static,
+{
+    fn add_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
+        let stored = self.storage.add_eavi(eav)?;
+        if self.storage.live_registry().is_none() {
+            self.live
+                .update(&self.storage, stored.as_ref().unwrap_or(eav));
+        }
+        Ok(stored)
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.storage.clock()
+    }
+
+    fn fetch_eavi(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        self.storage.fetch_eavi(query)
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        self.storage.fetch_eavi_page(query)
+    }
+
+    fn fetch_eavi_iter<'s>(
+        &'s self,
+        query: &'s EaviQuery<A>,
+    ) -> PersistenceResult<EaviIter<'s, A>> {
+        self.storage.fetch_eavi_iter(query)
+    }
+
+    fn count_eavi(&self, query: &EaviQuery<A>) -> PersistenceResult<usize> {
+        self.storage.count_eavi(query)
+    }
+
+    fn count_eavi_by_attribute(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<A, usize>> {
+        self.storage.count_eavi_by_attribute(query)
+    }
+
+    fn count_eavi_by_entity(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<Entity, usize>> {
+        self.storage.count_eavi_by_entity(query)
+    }
+
+    fn traverse(&self, traversal: &Traversal<A>) -> PersistenceResult<Vec<TraversalStep<A>>> {
+        self.storage.traverse(traversal)
+    }
+
+    fn fetch_join(&self, query: &JoinQuery<A>) -> PersistenceResult<BTreeSet<Bindings<A>>> {
+        self.storage.fetch_join(query)
+    }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        let retracted = self.storage.retract_eavi(eav)?;
+        if retracted && self.storage.live_registry().is_none() {
+            self.live.update(&self.storage, eav);
+        }
+        Ok(retracted)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.storage.get_id()
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
+    }
+}
+
+impl<A: Attribute, S: ReportStorage> ReportStorage for LiveEavStorage<A, S> {
+    fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
+        self.storage.get_storage_report()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::{spec::FilterSpec, storage::ExampleEntityAttributeValueStorage, ExampleAttribute};
+    use error::PersistenceError;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// a storage without a registry of its own, whose fetches fail once it is told to
+    #[derive(Clone, Debug)]
+    struct FailingStorage {
+        rows: ExampleEntityAttributeValueStorage<ExampleAttribute>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl ReportStorage for FailingStorage {}
+
+    impl EntityAttributeValueStorage<ExampleAttribute> for FailingStorage {
+        fn add_eavi(
+            &mut self,
+            eav: &EntityAttributeValueIndex<ExampleAttribute>,
+        ) -> PersistenceResult<Option<EntityAttributeValueIndex<ExampleAttribute>>> {
+            self.rows.add_eavi(eav)
+        }
+
+        fn fetch_eavi(
+            &self,
+            query: &EaviQuery<ExampleAttribute>,
+        ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<ExampleAttribute>>> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(PersistenceError::ErrorGeneric("failing".to_string()));
+            }
+            self.rows.fetch_eavi(query)
+        }
+
+        fn get_id(&self) -> Uuid {
+            self.rows.get_id()
+        }
+    }
+
+    fn row(entity: &str, value: &str, index: i64) -> EntityAttributeValueIndex<ExampleAttribute> {
+        EntityAttributeValueIndex::new_with_index(
+            &entity.into(),
+            &ExampleAttribute::default(),
+            &value.into(),
+            index,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn live_queries_skip_rows_they_filter_out_and_end_when_dropped() {
+        let mut storage = LiveEavStorage::new(ExampleEntityAttributeValueStorage::new());
+        let spec = EaviQuerySpec {
+            entity: FilterSpec::Exact("a".into()),
+            ..EaviQuerySpec::range(None, None)
+        };
+        let live = storage.subscribe(&spec).unwrap();
+        assert!(live.changes().try_recv().unwrap().is_empty());
+
+        storage.add_eavi(&row("b", "x", 1)).unwrap();
+        assert!(live.changes().try_recv().is_err());
+        storage.add_eavi(&row("a", "x", 1)).unwrap();
+        assert_eq!(
+            vec![row("a", "x", 1)],
+            live.changes()
+                .try_recv()
+                .unwrap()
+                .added
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        // adding a row again changes nothing
+        storage.add_eavi(&row("a", "x", 1)).unwrap();
+        assert!(live.changes().try_recv().is_err());
+
+        let other = storage.subscribe(&spec).unwrap();
+        assert_eq!(2, storage.live_queries().unwrap());
+        assert!(storage.unsubscribe(other.id()).unwrap());
+        assert!(!storage.unsubscribe(other.id()).unwrap());
+        drop(live);
+        storage.add_eavi(&row("a", "y", 2)).unwrap();
+        assert_eq!(0, storage.live_queries().unwrap());
+    }
+
+    #[test]
+    fn subscribing_fails_for_specs_that_do_not_convert() {
+        let storage = LiveEavStorage::new(ExampleEntityAttributeValueStorage::new());
+        let spec = EaviQuerySpec::<ExampleAttribute> {
+            value: FilterSpec::Regex("(".into()),
+            ..Default::default()
+        };
+        assert!(storage.subscribe(&spec).is_err());
+        assert_eq!(0, storage.live_queries().unwrap());
+    }
+
+    #[test]
+    fn failing_live_queries_end_without_failing_the_write() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let mut storage = LiveEavStorage::new(FailingStorage {
+            rows: ExampleEntityAttributeValueStorage::new(),
+            failing: failing.clone(),
+        });
+        let live = storage
+            .subscribe(&EaviQuerySpec::range(None, None))
+            .unwrap();
+        assert!(live.changes().try_recv().unwrap().is_empty());
+
+        // storages without a registry have the writes through the wrapper seen
+        storage.add_eavi(&row("a", "x", 1)).unwrap();
+        assert_eq!(1, live.changes().try_recv().unwrap().added.len());
+
+        failing.store(true, Ordering::SeqCst);
+        assert_eq!(
+            Ok(Some(row("a", "y", 2))),
+            storage.add_eavi(&row("a", "y", 2))
+        );
+        assert!(live.changes().try_recv().is_err());
+        assert_eq!(0, storage.live_queries().unwrap());
+
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(2, storage.fetch_eavi(&EaviQuery::default()).unwrap().len());
+    }
+}
@@ -0,0 +1,213 @@
+//! Reconciling the rows of two EAV storages, such as the rows received from a peer.
+//! Merging one storage into another adds the rows it lacks and retracts the rows retracted in
+//! the other, so afterwards its rows and its retractions are the unions of both. Unions don't
+//! depend on the order they are taken in and taking one again changes nothing, so storages
+//! that merge each other's rows converge, and so does what every query returns, tombstones and
+//! LatestByAttribute included.
+//! Rows are added at the index they have even if another row of their entity has it, @see
+//! resolve_collision, so that holds for peers that give rows the same index too.
+
+use eav::{
+    eavi::Attribute,
+    query::{EavFilter, EaviQuery, IndexFilter, RetractionFilter},
+    storage::EntityAttributeValueStorage,
+};
+use error::PersistenceResult;
+
+/// what merging a storage changed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// how many rows were added
+    pub added: usize,
+    /// how many rows were retracted
+    pub retracted: usize,
+}
+
+impl MergeReport {
+    /// whether the merge changed nothing, as when merging the same rows again
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.retracted == 0
+    }
+}
+
+/// every row, retracted or not
+fn all_rows<'a, A: Attribute + 'a>(retraction: RetractionFilter) -> EaviQuery<'a, A> {
+    EaviQuery::new(
+        EavFilter::default(),
+        EavFilter::default(),
+        EavFilter::default(),
+        IndexFilter::Range(None, None),
+        None,
+    )
+    .with_retraction(retraction)
+}
+
+/// Adds the rows of from that into lacks and retracts the rows retracted in from, @see the
+/// module docs.
+pub fn merge<A, S>(
+    into: &mut S,
+    from: &dyn EntityAttributeValueStorage<A>,
+) -> PersistenceResult<MergeReport>
+where
+    A: Attribute,
+    S: EntityAttributeValueStorage<A> + ?Sized,
+{
+    let mut report = MergeReport::default();
+    let count = |storage: &S| storage.count_eavi(&all_rows(RetractionFilter::Include));
+    let before = count(into)?;
+    let rows = all_rows(RetractionFilter::Include);
+    for row in from.fetch_eavi_iter(&rows)? {
+        into.add_eavi(&row?)?;
+    }
+    report.added = count(into)? - before;
+
+    let retracted = all_rows(RetractionFilter::Only);
+    for row in from.fetch_eavi_iter(&retracted)? {
+        if into.retract_eavi(&row?)? {
+            report.retracted += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::{
+        storage::ExampleEntityAttributeValueStorage, EntityAttributeValueIndex, ExampleAttribute,
+        Index,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeSet;
+
+    type Rows = BTreeSet<EntityAttributeValueIndex<ExampleAttribute>>;
+
+    fn removed() -> ExampleAttribute {
+        ExampleAttribute::WithPayload("removed".into())
+    }
+
+    /// The rows and retractions of a peer, from few enough entities, attributes, values and
+    /// indexes that rows of different peers overlap, with tombstones among them, and that
+    /// different rows of one entity often share an index.
+    fn peer(rng: &mut StdRng) -> ExampleEntityAttributeValueStorage<ExampleAttribute> {
+        let mut storage = ExampleEntityAttributeValueStorage::new();
+        let attributes = [ExampleAttribute::default(), removed()];
+        for _ in 0..rng.gen_range(0, 12) {
+            let index: Index = rng.gen_range(0, 8);
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &format!("e{}", rng.gen_range(0, 3)).into(),
+                &attributes[rng.gen_range(0, 2)],
+                &format!("v{}", rng.gen_range(0, 3)).into(),
+                index,
+            )
+            .unwrap();
+            storage.add_eavi(&eavi).unwrap();
+            if rng.gen_bool(0.2) {
+                storage.retract_eavi(&eavi).unwrap();
+            }
+        }
+        storage
+    }
+
+    /// the rows of a storage, the retracted ones and what LatestByAttribute with tombstones
+    /// returns
+    fn state(storage: &dyn EntityAttributeValueStorage<ExampleAttribute>) -> (Rows, Rows, Rows) {
+        let latest = EaviQuery::new(
+            EavFilter::default(),
+            EavFilter::default(),
+            EavFilter::default(),
+            IndexFilter::LatestByAttribute,
+            Some(EavFilter::single(removed())),
+        );
+        (
+            storage
+                .fetch_eavi(&all_rows(RetractionFilter::Include))
+                .unwrap(),
+            storage
+                .fetch_eavi(&all_rows(RetractionFilter::Only))
+                .unwrap(),
+            storage.fetch_eavi(&latest).unwrap(),
+        )
+    }
+
+    fn merged(
+        stores: &[&ExampleEntityAttributeValueStorage<ExampleAttribute>],
+    ) -> (Rows, Rows, Rows) {
+        let mut into = ExampleEntityAttributeValueStorage::new();
+        for from in stores {
+            merge(&mut into, *from).unwrap();
+        }
+        state(&into)
+    }
+
+    /// whether different rows of one entity share an index
+    fn collide(rows: &Rows) -> bool {
+        let slots: BTreeSet<_> = rows.iter().map(|row| (row.entity(), row.index())).collect();
+        slots.len() < rows.len()
+    }
+
+    #[test]
+    fn merges_are_commutative_associative_and_idempotent() {
+        let mut rng = StdRng::seed_from_u64(47);
+        let mut collisions = 0;
+        for _ in 0..100 {
+            let a = peer(&mut rng);
+            let b = peer(&mut rng);
+            let c = peer(&mut rng);
+            if collide(&merged(&[&a, &b, &c]).0) {
+                collisions += 1;
+            }
+
+            assert_eq!(merged(&[&a, &b]), merged(&[&b, &a]));
+            assert_eq!(merged(&[&a, &b, &c]), merged(&[&c, &b, &a]));
+            assert_eq!(merged(&[&a, &b, &c]), merged(&[&b, &c, &a]));
+            assert_eq!(merged(&[&a]), merged(&[&a, &a]));
+            assert_eq!(state(&a), merged(&[&a]));
+
+            let mut into = a.clone();
+            merge(&mut into, &b).unwrap();
+            let once = state(&into);
+            assert!(merge(&mut into, &b).unwrap().is_empty());
+            assert!(merge(&mut into, &a).unwrap().is_empty());
+            assert_eq!(once, state(&into));
+        }
+        // the generated peers really do put different rows at one index
+        assert!(collisions > 0);
+    }
+
+    #[test]
+    fn reports_what_changed() {
+        let row = |value: &str, index| {
+            EntityAttributeValueIndex::new_with_index(
+                &"e".into(),
+                &ExampleAttribute::default(),
+                &value.into(),
+                index,
+            )
+            .unwrap()
+        };
+        let mut into = ExampleEntityAttributeValueStorage::new();
+        into.add_eavi(&row("a", 1)).unwrap();
+        into.add_eavi(&row("x", 2)).unwrap();
+        let mut from = ExampleEntityAttributeValueStorage::new();
+        from.add_eavi(&row("a", 1)).unwrap();
+        from.add_eavi(&row("b", 2)).unwrap();
+        from.retract_eavi(&row("a", 1)).unwrap();
+        from.retract_eavi(&row("b", 2)).unwrap();
+
+        let report = merge(&mut into, &from).unwrap();
+        assert_eq!(1, report.added);
+        assert_eq!(2, report.retracted);
+        // b is added next to the other row at its index
+        let retracted = into.fetch_eavi(&all_rows(RetractionFilter::Only)).unwrap();
+        assert_eq!(
+            vec![row("a", 1), row("b", 2)],
+            retracted.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            3,
+            into.count_eavi(&all_rows(RetractionFilter::Include))
+                .unwrap()
+        );
+    }
+}
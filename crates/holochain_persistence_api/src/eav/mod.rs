@@ -1,5 +1,15 @@
+pub mod clock;
+pub mod collision;
 pub mod eavi;
+pub mod join;
+pub mod live;
+pub mod merge;
 pub mod query;
+pub mod spec;
 pub mod storage;
+pub mod traverse;
 
-pub use self::{eavi::*, query::*, storage::*};
+pub use self::{
+    clock::*, collision::*, eavi::*, join::*, live::*, merge::*, query::*, spec::*, storage::*,
+    traverse::*,
+};
@@ -1,5 +1,6 @@
-use eav::eavi::{Attribute, Entity, EntityAttributeValueIndex, Value};
-use std::collections::BTreeSet;
+use eav::eavi::{Attribute, Entity, EntityAttributeValueIndex, Index, Source, Value};
+use hash::HashString;
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
 
 /// Represents a set of filtering operations on the EAVI store.
 pub struct EaviQuery<'a, A: Attribute> {
@@ -9,18 +10,36 @@ pub struct EaviQuery<'a, A: Attribute> {
     pub attribute: AttributeFilter<'a, A>,
     ///represents a filter for the Value
     pub value: ValueFilter<'a>,
+    ///represents a filter for the Source, which is None for eavis without one
+    pub source: SourceFilter<'a>,
     ///For this query system we are able to provide a tombstone set on the query level which allows us to specify which Attribute match should take precedent over the others.
     ///This is useful for some of the Link CRDT operations we are doing. Note that if no tombstone is found, the latest entry is returned
     ///from the subset that is obtained. The tombstone is optional so if it is not supplied, no tombstone check will be done.
-    ///Currently the Tombstone does not work on an IndexByRange IndexFilter and will operate as if the tombstone was not set
+    ///On an IndexFilter::Range a tombstone at or before the end of the range hides the other rows of its entity and value
+    ///in the range, as the link was deleted by then. The tombstone rows in the range are returned.
     pub tombstone: Option<AttributeFilter<'a, A>>,
     ///represents a filter for the Index
     pub index: IndexFilter,
+    ///Only rows with an index at or before it are seen, so LatestByAttribute, tombstones and ranges
+    ///are evaluated as they were at that index. Retractions aren't indexed, so rows retracted since
+    ///are still retracted.
+    pub as_of: Option<Index>,
+    ///whether retracted rows are hidden, included or the only rows returned
+    pub retraction: RetractionFilter,
+    ///the order rows are paged in
+    pub order: Order,
+    ///the most rows returned, or all of them if None
+    pub limit: Option<usize>,
+    ///how many rows to skip, counted from the cursor if there is one
+    pub offset: usize,
+    ///only rows after the cursor in the query's order are returned
+    pub cursor: Option<Cursor<A>>,
 }
 
 type EntityFilter<'a> = EavFilter<'a, Entity>;
 type AttributeFilter<'a, A> = EavFilter<'a, A>;
 type ValueFilter<'a> = EavFilter<'a, Value>;
+type SourceFilter<'a> = EavFilter<'a, Option<Source>>;
 
 impl<'a, A: Attribute> Default for EaviQuery<'a, A> {
     fn default() -> EaviQuery<'a, A> {
@@ -47,77 +66,284 @@ impl<'a, A: Attribute> EaviQuery<'a, A> {
             entity,
             attribute,
             value,
+            source: EavFilter::default(),
             tombstone,
             index,
+            as_of: None,
+            retraction: RetractionFilter::default(),
+            order: Order::default(),
+            limit: None,
+            offset: 0,
+            cursor: None,
         }
     }
 
+    /// only returns rows whose source passes the filter
+    pub fn with_source(mut self, source: SourceFilter<'a>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// only sees rows with an index at or before as_of, @see EaviQuery::as_of
+    pub fn with_as_of(mut self, as_of: Index) -> Self {
+        self.as_of = Some(as_of);
+        self
+    }
+
+    /// sets which rows the query returns with regard to retraction
+    pub fn with_retraction(mut self, retraction: RetractionFilter) -> Self {
+        self.retraction = retraction;
+        self
+    }
+
+    /// sets the order rows are paged in
+    pub fn with_order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// returns at most limit rows
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// skips the first offset rows
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// returns only the rows after the cursor, which comes from the previous page
+    pub fn after(mut self, cursor: Cursor<A>) -> Self {
+        self.cursor = Some(cursor);
+        self
+    }
+
+    /// Runs the query over rows that may have been retracted, first dropping the rows the
+    /// retraction filter excludes. is_retracted says whether a row has been retracted.
+    pub fn run_with_retractions<I, F>(
+        &self,
+        iter: I,
+        is_retracted: F,
+    ) -> BTreeSet<EntityAttributeValueIndex<A>>
+    where
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
+        F: Fn(&EntityAttributeValueIndex<A>) -> bool,
+    {
+        self.run_page_with_retractions(iter, is_retracted)
+            .rows
+            .into_iter()
+            .collect()
+    }
+
+    /// Like run_with_retractions, but keeps the page in order along with where it ended.
+    pub fn run_page_with_retractions<I, F>(&self, iter: I, is_retracted: F) -> EaviPage<A>
+    where
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
+        F: Fn(&EntityAttributeValueIndex<A>) -> bool,
+    {
+        self.run_page(iter.filter(|eavi| self.retraction.keeps(is_retracted(eavi))))
+    }
+
     /// This runs the query based the query configuration we have given.
     pub fn run<I>(&self, iter: I) -> BTreeSet<EntityAttributeValueIndex<A>>
     where
-        I: Clone + Iterator<Item = EntityAttributeValueIndex<A>> + 'a,
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
+    {
+        self.run_page(iter).rows.into_iter().collect()
+    }
+
+    /// Like run, but keeps the page in order along with where it ended.
+    pub fn run_page<I>(&self, iter: I) -> EaviPage<A>
+    where
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
+    {
+        let filtered = iter.filter(|eavi| self.matches(eavi));
+        let rows = match (&self.index, &self.tombstone) {
+            (IndexFilter::LatestByAttribute, _) => self.latest_by_attribute(filtered),
+            (IndexFilter::Range(_, end), Some(tombstone)) => {
+                self.range_with_tombstone(filtered, *end, tombstone)
+            }
+            (IndexFilter::Range(..), None) => filtered.filter(|eavi| self.in_range(eavi)).collect(),
+        };
+        self.page(rows)
+    }
+
+    /// Keeps the rows in the range that no tombstone at or before its end shadows.
+    /// A tombstone shadows the other rows with its entity and value, even those after it.
+    fn range_with_tombstone<I>(
+        &self,
+        rows: I,
+        end: Option<Index>,
+        tombstone: &AttributeFilter<'a, A>,
+    ) -> BTreeSet<EntityAttributeValueIndex<A>>
+    where
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
     {
-        let iter2 = iter.clone();
-        let filtered = iter
-            .filter(|eavi| EaviQuery::eav_check(&eavi, &self.entity, &self.attribute, &self.value));
+        let mut shadowed = BTreeSet::new();
+        let mut in_range = Vec::new();
+        for eavi in rows {
+            let is_tombstone = tombstone.check(eavi.attribute());
+            if is_tombstone && end.map(|hi| eavi.index() <= hi).unwrap_or(true) {
+                shadowed.insert((eavi.entity(), eavi.value()));
+            }
+            if self.in_range(&eavi) {
+                in_range.push((eavi, is_tombstone));
+            }
+        }
+        in_range
+            .into_iter()
+            .filter(|(eavi, is_tombstone)| {
+                *is_tombstone || !shadowed.contains(&(eavi.entity(), eavi.value()))
+            })
+            .map(|(eavi, _)| eavi)
+            .collect()
+    }
+
+    /// Whether the query can be answered a row at a time in any order, with selects and the
+    /// retraction filter. It can't when it keeps the LatestByAttribute of the rows or checks
+    /// them for tombstones, which compares them, or when it orders or pages them.
+    pub fn can_stream(&self) -> bool {
         match self.index {
-            IndexFilter::LatestByAttribute => filtered
-                .filter_map(|eavi| {
-                    // this fold reduces a set of matched (e,a,v) values but makes sure the tombstone value takes priority.
-                    //the starting point is a tuple (Option,bool) which translates to a cnodition in which we have found our tombstone value and it also matches our tombstone
-                    let reduced_value =
-                        iter2.clone().fold((None, false), |eavi_option, eavi_fold| {
-                            //if tombstone match is found, do not check the rest
-                            if eavi_option.1 {
-                                eavi_option
-                            } else {
-                                //create eavi query without tombstone set, they are two levels here. we have to make sure that the values match our eav set but later on we check if that value given also matches our tombstone condition
-                                let fold_query = EaviQuery::new(
-                                    Some(eavi.entity()).into(),
-                                    Some(eavi.attribute()).into(),
-                                    Some(eavi.value()).into(),
-                                    IndexFilter::LatestByAttribute,
-                                    None,
-                                );
-                                //check if the value matches our initial condition
-                                if EaviQuery::eav_check(
-                                    &eavi_fold,
-                                    &fold_query.entity,
-                                    &self.attribute,
-                                    &fold_query.value,
-                                ) {
-                                    //check if tombstone condition is met at
-                                    //if a tombstone is not found it should default to false
-                                    if self
-                                        .tombstone()
-                                        .as_ref()
-                                        .map(|s| s.check(eavi_fold.attribute()))
-                                        .unwrap_or_default()
-                                    {
-                                        //if attrribute is found return the value plus the tombstone boolean set to true
-                                        (Some(eavi_fold), true)
-                                    } else {
-                                        //return value that signifies value has been found but tombstone has not been found
-                                        (Some(eavi_fold), false)
-                                    }
-                                } else {
-                                    //if set does not match, just return last value of eavi_option
-                                    eavi_option
-                                }
-                            }
-                        });
-
-                    //at the end just return initial value of tombstone
-                    reduced_value.0
-                })
-                .collect(),
-            IndexFilter::Range(start, end) => filtered
-                .filter(|eavi| {
-                    start.map(|lo| lo <= eavi.index()).unwrap_or(true)
-                        && end.map(|hi| eavi.index() <= hi).unwrap_or(true)
-                })
-                .collect(),
+            IndexFilter::Range(..) => {
+                self.tombstone.is_none()
+                    && self.order == Order::Ascending
+                    && self.limit.is_none()
+                    && self.offset == 0
+            }
+            IndexFilter::LatestByAttribute => false,
+        }
+    }
+
+    /// Whether a row passes the entity, attribute, value, source and index range filters and
+    /// comes after the cursor. LatestByAttribute and tombstones depend on the other rows so
+    /// they aren't checked.
+    /// Storages that can read rows in order use this to page without reading them all.
+    pub fn selects(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        self.matches(eavi) && self.in_range(eavi) && self.is_after_cursor(eavi)
+    }
+
+    /// Whether adding or retracting the row can change what the query returns. Rows the
+    /// entity, attribute, value or source filters reject never do, whatever their index.
+    pub fn is_affected_by(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        self.matches(eavi)
+    }
+
+    /// whether a row passes the entity, attribute, value and source filters and is seen as of
+    /// the query's as_of
+    fn matches(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        EaviQuery::eav_check(eavi, &self.entity, &self.attribute, &self.value)
+            && self.source.check(eavi.source())
+            && self
+                .as_of
+                .map(|as_of| eavi.index() <= as_of)
+                .unwrap_or(true)
+    }
+
+    /// The least and greatest indexes of the rows that can decide what the query returns,
+    /// which storages keeping rows in index order can limit their scans to. Tombstones before
+    /// the start of a range still hide rows in it, so a range with tombstones has no least.
+    pub fn index_span(&self) -> (Option<Index>, Option<Index>) {
+        let (start, end) = match (&self.index, &self.tombstone) {
+            (IndexFilter::Range(start, end), None) => (*start, *end),
+            (IndexFilter::Range(_, end), Some(_)) => (None, *end),
+            (IndexFilter::LatestByAttribute, _) => (None, None),
+        };
+        let end = match (end, self.as_of) {
+            (Some(end), Some(as_of)) => Some(end.min(as_of)),
+            (end, as_of) => end.or(as_of),
+        };
+        (start, end)
+    }
+
+    fn in_range(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        match self.index {
+            IndexFilter::Range(start, end) => {
+                start.map(|lo| lo <= eavi.index()).unwrap_or(true)
+                    && end.map(|hi| eavi.index() <= hi).unwrap_or(true)
+            }
+            IndexFilter::LatestByAttribute => true,
+        }
+    }
+
+    fn is_after_cursor(&self, eavi: &EntityAttributeValueIndex<A>) -> bool {
+        match (&self.cursor, self.order) {
+            (None, _) => true,
+            (Some(cursor), Order::Ascending) => eavi > &cursor.last,
+            (Some(cursor), Order::Descending) => eavi < &cursor.last,
+        }
+    }
+
+    /// Cuts the page the query asks for out of the rows that matched it.
+    /// Rows are ordered by index, then by entity, value and attribute to break ties.
+    pub fn page(&self, rows: BTreeSet<EntityAttributeValueIndex<A>>) -> EaviPage<A> {
+        let mut rows: Vec<_> = rows.into_iter().collect();
+        if self.order == Order::Descending {
+            rows.reverse();
+        }
+        let mut rest = rows
+            .into_iter()
+            .filter(|eavi| self.is_after_cursor(eavi))
+            .skip(self.offset);
+        let rows: Vec<_> = match self.limit {
+            Some(limit) => rest.by_ref().take(limit).collect(),
+            None => rest.by_ref().collect(),
+        };
+        let next = match rest.next() {
+            Some(_) => rows.last().cloned().map(|last| Cursor { last }),
+            None => None,
+        };
+        EaviPage { rows, next }
+    }
+
+    /// Orders rows that are already the page the query asks for. As it can't know whether
+    /// more rows follow, it gives a cursor whenever the page is full.
+    pub(crate) fn page_of_fetched(
+        &self,
+        rows: BTreeSet<EntityAttributeValueIndex<A>>,
+    ) -> EaviPage<A> {
+        let full = self.limit.map(|limit| rows.len() >= limit).unwrap_or(false);
+        let mut rows: Vec<_> = rows.into_iter().collect();
+        if self.order == Order::Descending {
+            rows.reverse();
         }
+        let next = if full {
+            rows.last().cloned().map(|last| Cursor { last })
+        } else {
+            None
+        };
+        EaviPage { rows, next }
+    }
+
+    /// Reduces the rows that passed the filters to one for each (entity, value) in a single
+    /// pass. That is the first row whose attribute matches the tombstone or, if none does, the
+    /// last row, which is the one with the highest index when rows come in order.
+    fn latest_by_attribute<I>(&self, rows: I) -> BTreeSet<EntityAttributeValueIndex<A>>
+    where
+        I: Iterator<Item = EntityAttributeValueIndex<A>>,
+    {
+        // the bool is whether the row kept for the group matched the tombstone
+        let mut latest: BTreeMap<(Entity, Value), (EntityAttributeValueIndex<A>, bool)> =
+            BTreeMap::new();
+        for eavi in rows {
+            let is_tombstone = self
+                .tombstone()
+                .as_ref()
+                .map(|tombstone| tombstone.check(eavi.attribute()))
+                .unwrap_or_default();
+            match latest.entry((eavi.entity(), eavi.value())) {
+                Entry::Occupied(mut kept) => {
+                    if !kept.get().1 {
+                        kept.insert((eavi, is_tombstone));
+                    }
+                }
+                Entry::Vacant(group) => {
+                    group.insert((eavi, is_tombstone));
+                }
+            }
+        }
+        latest.into_iter().map(|(_, (eavi, _))| eavi).collect()
     }
 
     fn eav_check(
@@ -138,18 +364,83 @@ impl<'a, A: Attribute> EaviQuery<'a, A> {
     pub fn value(&self) -> &ValueFilter<'a> {
         &self.value
     }
+    pub fn source(&self) -> &SourceFilter<'a> {
+        &self.source
+    }
     pub fn index(&self) -> &IndexFilter {
         &self.index
     }
     pub fn tombstone(&self) -> &Option<AttributeFilter<'a, A>> {
         &self.tombstone
     }
+    pub fn as_of(&self) -> Option<Index> {
+        self.as_of
+    }
+    pub fn retraction(&self) -> RetractionFilter {
+        self.retraction
+    }
+    pub fn order(&self) -> Order {
+        self.order
+    }
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    pub fn cursor(&self) -> &Option<Cursor<A>> {
+        &self.cursor
+    }
+}
+
+/// How a value appears to prefix and range filters, which compare it as a string.
+pub trait FilterKey {
+    fn filter_key(&self) -> String;
+}
+
+impl FilterKey for HashString {
+    fn filter_key(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// a missing value has an empty key, so it sorts first and every prefix but "" excludes it
+impl<T: FilterKey> FilterKey for Option<T> {
+    fn filter_key(&self) -> String {
+        self.as_ref().map(FilterKey::filter_key).unwrap_or_default()
+    }
+}
+
+/// gets the key of a value that prefix and range filters compare
+pub type KeyFn<T> = fn(&T) -> String;
+
+/// A lexicographic range of keys, from start inclusive to end exclusive.
+/// Either end can be left open.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+impl KeyRange {
+    pub fn new(start: Option<String>, end: Option<String>) -> KeyRange {
+        KeyRange { start, end }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.start.iter().all(|start| key >= start.as_str())
+            && self.end.iter().all(|end| key < end.as_str())
+    }
 }
 
 /// Represents a filter type which takes in a function to match on
 // pub struct EavFilter<'a, T: 'a + Eq>(Box<dyn Fn(T) -> bool + 'a>);
 pub enum EavFilter<'a, T: 'a + Eq> {
     Exact(T),
+    /// matches values whose key starts with the prefix
+    Prefix(String, KeyFn<T>),
+    /// matches values whose key is in the range
+    Range(KeyRange, KeyFn<T>),
     Predicate(Box<dyn Fn(T) -> bool + 'a>),
 }
 
@@ -169,12 +460,54 @@ impl<'a, T: 'a + Eq> EavFilter<'a, T> {
         Self::Predicate(Box::new(predicate))
     }
 
+    /// matches values whose key starts with the prefix, such as the addresses in a DHT shard
+    pub fn prefix<S: Into<String>>(prefix: S) -> Self
+    where
+        T: FilterKey,
+    {
+        Self::Prefix(prefix.into(), T::filter_key)
+    }
+
+    /// matches values whose key is at least start and less than end
+    pub fn range(start: Option<String>, end: Option<String>) -> Self
+    where
+        T: FilterKey,
+    {
+        Self::Range(KeyRange::new(start, end), T::filter_key)
+    }
+
     pub fn check(&self, b: T) -> bool {
         match self {
             Self::Exact(a) => a == &b,
+            Self::Prefix(prefix, key) => key(&b).starts_with(prefix.as_str()),
+            Self::Range(range, key) => range.contains(&key(&b)),
             Self::Predicate(f) => f(b),
         }
     }
+
+    /// the value an Exact filter matches, which storages can use to seek instead of scan
+    pub fn exact(&self) -> Option<&T> {
+        match self {
+            Self::Exact(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// the prefix of a Prefix filter, which storages keyed by value can scan from
+    pub fn key_prefix(&self) -> Option<&str> {
+        match self {
+            Self::Prefix(prefix, _) => Some(prefix),
+            _ => None,
+        }
+    }
+
+    /// the range of a Range filter, which storages keyed by value can scan
+    pub fn key_range(&self) -> Option<&KeyRange> {
+        match self {
+            Self::Range(range, _) => Some(range),
+            _ => None,
+        }
+    }
 }
 
 impl<'a, T: Eq> Default for EavFilter<'a, T> {
@@ -200,8 +533,183 @@ impl<'a, T: Eq> From<Vec<T>> for EavFilter<'a, T> {
 /// LatestByAttribute is more complex. It first does a normal filter by E, A, and V.
 /// Then, for each group of items which differ *only* by Attribute and Index, only the item with
 /// highest Index is retained for that grouping.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum IndexFilter {
     LatestByAttribute,
     Range(Option<i64>, Option<i64>),
 }
+
+/// Specifies which rows a query returns with regard to retraction.
+/// Retracted rows are filtered out before the index filter is applied, so hiding them is the
+/// same as if they had never been added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetractionFilter {
+    /// only rows that have not been retracted
+    Hide,
+    /// retracted rows along with the rest
+    Include,
+    /// only rows that have been retracted
+    Only,
+}
+
+impl Default for RetractionFilter {
+    fn default() -> RetractionFilter {
+        RetractionFilter::Hide
+    }
+}
+
+impl RetractionFilter {
+    /// whether a row that has or hasn't been retracted passes the filter
+    pub fn keeps(self, retracted: bool) -> bool {
+        match self {
+            RetractionFilter::Hide => !retracted,
+            RetractionFilter::Include => true,
+            RetractionFilter::Only => retracted,
+        }
+    }
+}
+
+/// The order of the rows in a page: by index, then by entity, value and attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+impl Default for Order {
+    fn default() -> Order {
+        Order::Ascending
+    }
+}
+
+/// Marks where a page ended. Pass it to EaviQuery::after to get the next page.
+/// Cursors serialize so that clients can hold on to them, but what is in them is not part of
+/// the API.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor<A: Attribute> {
+    last: EntityAttributeValueIndex<A>,
+}
+
+impl<A: Attribute> Cursor<A> {
+    /// the index the page ended at, which storages keyed by index can seek to
+    pub fn index(&self) -> Index {
+        self.last.index()
+    }
+}
+
+/// One page of the rows a query matched, in the query's order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EaviPage<A: Attribute> {
+    pub rows: Vec<EntityAttributeValueIndex<A>>,
+    /// where to carry on from, or None if this is the last page
+    pub next: Option<Cursor<A>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::ExampleAttribute;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    /// LatestByAttribute as it was first written, folding over every row for each row
+    fn legacy_latest_by_attribute<'a>(
+        query: &EaviQuery<'a, ExampleAttribute>,
+        rows: &[EntityAttributeValueIndex<ExampleAttribute>],
+    ) -> BTreeSet<EntityAttributeValueIndex<ExampleAttribute>> {
+        rows.iter()
+            .filter(|eavi| {
+                EaviQuery::eav_check(eavi, &query.entity, &query.attribute, &query.value)
+            })
+            .filter_map(|eavi| {
+                rows.iter()
+                    .fold((None, false), |eavi_option, eavi_fold| {
+                        if eavi_option.1 {
+                            eavi_option
+                        } else if eavi_fold.entity() == eavi.entity()
+                            && query.attribute.check(eavi_fold.attribute())
+                            && eavi_fold.value() == eavi.value()
+                        {
+                            let is_tombstone = query
+                                .tombstone()
+                                .as_ref()
+                                .map(|s| s.check(eavi_fold.attribute()))
+                                .unwrap_or_default();
+                            (Some(eavi_fold.clone()), is_tombstone)
+                        } else {
+                            eavi_option
+                        }
+                    })
+                    .0
+            })
+            .collect()
+    }
+
+    fn random_attribute(rng: &mut StdRng) -> ExampleAttribute {
+        match rng.gen_range(0, 4) {
+            0 => ExampleAttribute::WithoutPayload,
+            n => ExampleAttribute::WithPayload(format!("attribute-{}", n)),
+        }
+    }
+
+    fn random_address(rng: &mut StdRng, prefix: &str) -> Entity {
+        format!("{}-{}", prefix, rng.gen_range(0, 3)).into()
+    }
+
+    fn random_filter<'a, T: Eq + 'a>(
+        rng: &mut StdRng,
+        mut random: impl FnMut(&mut StdRng) -> T,
+    ) -> EavFilter<'a, T> {
+        match rng.gen_range(0, 3) {
+            0 => EavFilter::default(),
+            1 => EavFilter::single(random(rng)),
+            _ => EavFilter::multiple(vec![random(rng), random(rng)]),
+        }
+    }
+
+    #[test]
+    fn latest_by_attribute_matches_legacy_evaluation() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        for _ in 0..500 {
+            let rows: Vec<EntityAttributeValueIndex<ExampleAttribute>> = (0..rng.gen_range(0, 40))
+                .map(|_| {
+                    EntityAttributeValueIndex::new_with_index(
+                        &random_address(&mut rng, "entity"),
+                        &random_attribute(&mut rng),
+                        &random_address(&mut rng, "value"),
+                        rng.gen_range(-5, 20),
+                    )
+                    .unwrap()
+                })
+                .collect();
+            // backends give rows in order, but the evaluation must agree on any order
+            let mut rows: Vec<_> = rows
+                .into_iter()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            if rng.gen() {
+                rows.shuffle(&mut rng);
+            }
+
+            let tombstone = if rng.gen() {
+                Some(random_filter(&mut rng, random_attribute))
+            } else {
+                None
+            };
+            let query = EaviQuery::new(
+                random_filter(&mut rng, |rng| random_address(rng, "entity")),
+                random_filter(&mut rng, random_attribute),
+                random_filter(&mut rng, |rng| random_address(rng, "value")),
+                IndexFilter::LatestByAttribute,
+                tombstone,
+            );
+
+            assert_eq!(
+                legacy_latest_by_attribute(&query, &rows),
+                query.run(rows.iter().cloned()),
+                "rows: {:?}",
+                rows
+            );
+        }
+    }
+}
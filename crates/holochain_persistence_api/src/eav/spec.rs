@@ -0,0 +1,368 @@
+//! Queries as plain data. An EaviQuery can hold closures, so it can't be sent to another
+//! process, logged or cached, but an EaviQuerySpec can and converts into an EaviQuery to run.
+
+use eav::{
+    eavi::{Attribute, Entity, Index, Source, Value},
+    query::{
+        Cursor, EavFilter, EaviQuery, FilterKey, IndexFilter, KeyRange, Order, RetractionFilter,
+    },
+};
+use error::{PersistenceError, PersistenceResult};
+use regex::Regex;
+
+/// A filter on the entity, attribute or value of a row that is only data.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterSpec<T> {
+    /// matches everything
+    Any,
+    Exact(T),
+    OneOf(Vec<T>),
+    /// matches values whose filter key starts with the string
+    Prefix(String),
+    /// matches values whose filter key is in the range
+    Range(KeyRange),
+    /// matches values whose filter key the regex matches somewhere in
+    Regex(String),
+    Not(Box<FilterSpec<T>>),
+    /// matches what all of the filters match, so everything if there are none
+    And(Vec<FilterSpec<T>>),
+    /// matches what any of the filters match, so nothing if there are none
+    Or(Vec<FilterSpec<T>>),
+}
+
+impl<T> Default for FilterSpec<T> {
+    fn default() -> FilterSpec<T> {
+        FilterSpec::Any
+    }
+}
+
+/// a FilterSpec with its regexes compiled
+enum Compiled<T> {
+    Any,
+    OneOf(Vec<T>),
+    Prefix(String),
+    Range(KeyRange),
+    Regex(Regex),
+    Not(Box<Compiled<T>>),
+    And(Vec<Compiled<T>>),
+    Or(Vec<Compiled<T>>),
+}
+
+impl<T: Eq + FilterKey> Compiled<T> {
+    fn check(&self, value: &T) -> bool {
+        match self {
+            Compiled::Any => true,
+            Compiled::OneOf(values) => values.contains(value),
+            Compiled::Prefix(prefix) => value.filter_key().starts_with(prefix.as_str()),
+            Compiled::Range(range) => range.contains(&value.filter_key()),
+            Compiled::Regex(regex) => regex.is_match(&value.filter_key()),
+            Compiled::Not(filter) => !filter.check(value),
+            Compiled::And(filters) => filters.iter().all(|filter| filter.check(value)),
+            Compiled::Or(filters) => filters.iter().any(|filter| filter.check(value)),
+        }
+    }
+}
+
+impl<T: Clone + Eq + FilterKey> FilterSpec<T> {
+    /// The value this filter matches if it matches only one.
+    /// Storages can seek to it instead of scanning, so it converts to EavFilter::Exact, as
+    /// prefixes and ranges convert to the EavFilter variants storages can scan.
+    pub fn exact(&self) -> Option<&T> {
+        match self {
+            FilterSpec::Exact(value) => Some(value),
+            FilterSpec::OneOf(values) if values.len() == 1 => values.first(),
+            FilterSpec::And(filters) if filters.len() == 1 => filters[0].exact(),
+            FilterSpec::Or(filters) if filters.len() == 1 => filters[0].exact(),
+            _ => None,
+        }
+    }
+
+    /// Converts into the filter queries run with, failing if a regex doesn't compile.
+    pub fn to_filter<'a>(&self) -> PersistenceResult<EavFilter<'a, T>>
+    where
+        T: 'a,
+    {
+        if let Some(value) = self.exact() {
+            return Ok(EavFilter::single(value.clone()));
+        }
+        match self {
+            FilterSpec::Prefix(prefix) => return Ok(EavFilter::prefix(prefix.clone())),
+            FilterSpec::Range(range) => {
+                return Ok(EavFilter::range(range.start.clone(), range.end.clone()))
+            }
+            _ => (),
+        }
+        match self.compile()? {
+            Compiled::Any => Ok(EavFilter::default()),
+            compiled => Ok(EavFilter::predicate(move |value| compiled.check(&value))),
+        }
+    }
+
+    fn compile(&self) -> PersistenceResult<Compiled<T>> {
+        let all = |filters: &[FilterSpec<T>]| {
+            filters
+                .iter()
+                .map(FilterSpec::compile)
+                .collect::<PersistenceResult<Vec<_>>>()
+        };
+        Ok(match self {
+            FilterSpec::Any => Compiled::Any,
+            FilterSpec::Exact(value) => Compiled::OneOf(vec![value.clone()]),
+            FilterSpec::OneOf(values) => Compiled::OneOf(values.clone()),
+            FilterSpec::Prefix(prefix) => Compiled::Prefix(prefix.clone()),
+            FilterSpec::Range(range) => Compiled::Range(range.clone()),
+            FilterSpec::Regex(pattern) => Compiled::Regex(Regex::new(pattern).map_err(|e| {
+                PersistenceError::ErrorGeneric(format!("invalid regex {}: {}", pattern, e))
+            })?),
+            FilterSpec::Not(filter) => Compiled::Not(Box::new(filter.compile()?)),
+            FilterSpec::And(filters) => Compiled::And(all(filters)?),
+            FilterSpec::Or(filters) => Compiled::Or(all(filters)?),
+        })
+    }
+}
+
+/// An EaviQuery as plain data, which serializes with serde.
+/// Fields left out when deserializing take their defaults, as in EaviQuery::default.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EaviQuerySpec<A: Attribute> {
+    pub entity: FilterSpec<Entity>,
+    pub attribute: FilterSpec<A>,
+    pub value: FilterSpec<Value>,
+    /// matches the source of rows, which is None for rows without one
+    pub source: FilterSpec<Option<Source>>,
+    pub index: IndexFilter,
+    /// only rows with an index at or before it are seen, @see EaviQuery::as_of
+    pub as_of: Option<Index>,
+    pub tombstone: Option<FilterSpec<A>>,
+    pub retraction: RetractionFilter,
+    pub order: Order,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub cursor: Option<Cursor<A>>,
+}
+
+impl<A: Attribute> Default for EaviQuerySpec<A> {
+    fn default() -> EaviQuerySpec<A> {
+        EaviQuerySpec {
+            entity: FilterSpec::Any,
+            attribute: FilterSpec::Any,
+            value: FilterSpec::Any,
+            source: FilterSpec::Any,
+            index: IndexFilter::LatestByAttribute,
+            as_of: None,
+            tombstone: None,
+            retraction: RetractionFilter::default(),
+            order: Order::default(),
+            limit: None,
+            offset: 0,
+            cursor: None,
+        }
+    }
+}
+
+impl<A: Attribute + FilterKey> EaviQuerySpec<A> {
+    /// a spec for rows with indexes in the range, inclusive at both ends
+    pub fn range(start: Option<Index>, end: Option<Index>) -> EaviQuerySpec<A> {
+        EaviQuerySpec {
+            index: IndexFilter::Range(start, end),
+            ..Default::default()
+        }
+    }
+
+    /// Converts into the query storages run, failing if a regex doesn't compile.
+    pub fn to_query<'a>(&self) -> PersistenceResult<EaviQuery<'a, A>>
+    where
+        A: 'a,
+    {
+        let tombstone = match &self.tombstone {
+            Some(tombstone) => Some(tombstone.to_filter()?),
+            None => None,
+        };
+        let mut query = EaviQuery::new(
+            self.entity.to_filter()?,
+            self.attribute.to_filter()?,
+            self.value.to_filter()?,
+            self.index.clone(),
+            tombstone,
+        )
+        .with_source(self.source.to_filter()?)
+        .with_retraction(self.retraction)
+        .with_order(self.order)
+        .with_offset(self.offset);
+        query.as_of = self.as_of;
+        query.limit = self.limit;
+        query.cursor = self.cursor.clone();
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eav::storage::EntityAttributeValueStorage;
+    use eav::{
+        storage::ExampleEntityAttributeValueStorage, EntityAttributeValueIndex, ExampleAttribute,
+    };
+
+    fn link(payload: &str) -> ExampleAttribute {
+        ExampleAttribute::WithPayload(payload.to_string())
+    }
+
+    #[test]
+    fn filters_match_as_specified() {
+        let values: Vec<Value> = vec!["apple".into(), "apricot".into(), "banana".into()];
+        let matching = |spec: FilterSpec<Value>| {
+            let filter = spec.to_filter().unwrap();
+            values
+                .iter()
+                .filter(|value| filter.check((*value).clone()))
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+        };
+        let any: Vec<_> = values.iter().map(|value| value.to_string()).collect();
+
+        assert_eq!(any, matching(FilterSpec::Any));
+        assert_eq!(vec!["banana"], matching(FilterSpec::Exact("banana".into())));
+        assert_eq!(
+            vec!["apple", "banana"],
+            matching(FilterSpec::OneOf(vec!["apple".into(), "banana".into()]))
+        );
+        assert_eq!(
+            vec!["apple", "apricot"],
+            matching(FilterSpec::Prefix("ap".into()))
+        );
+        assert_eq!(
+            vec!["apricot"],
+            matching(FilterSpec::Range(KeyRange::new(
+                Some("apr".into()),
+                Some("b".into())
+            )))
+        );
+        assert_eq!(
+            vec!["apricot", "banana"],
+            matching(FilterSpec::Regex("(cot|nan)".into()))
+        );
+        assert_eq!(
+            vec!["banana"],
+            matching(FilterSpec::Not(Box::new(FilterSpec::Prefix("ap".into()))))
+        );
+        assert_eq!(
+            vec!["apricot"],
+            matching(FilterSpec::And(vec![
+                FilterSpec::Prefix("ap".into()),
+                FilterSpec::Regex("t$".into()),
+            ]))
+        );
+        assert_eq!(any, matching(FilterSpec::And(vec![])));
+        assert_eq!(
+            vec!["apple", "banana"],
+            matching(FilterSpec::Or(vec![
+                FilterSpec::Exact("apple".into()),
+                FilterSpec::Prefix("b".into()),
+            ]))
+        );
+        assert!(matching(FilterSpec::Or(vec![])).is_empty());
+    }
+
+    #[test]
+    fn exact_specs_convert_to_exact_filters() {
+        let apple: Value = "apple".into();
+        let exact = |spec: FilterSpec<Value>| spec.to_filter().unwrap().exact().cloned();
+        assert_eq!(Some(apple.clone()), exact(FilterSpec::Exact(apple.clone())));
+        assert_eq!(
+            Some(apple.clone()),
+            exact(FilterSpec::OneOf(vec![apple.clone()]))
+        );
+        assert_eq!(
+            Some(apple.clone()),
+            exact(FilterSpec::And(vec![FilterSpec::Exact(apple.clone())]))
+        );
+        assert_eq!(None, exact(FilterSpec::Prefix("apple".into())));
+        assert_eq!(
+            Some("app"),
+            FilterSpec::<Value>::Prefix("app".into())
+                .to_filter()
+                .unwrap()
+                .key_prefix()
+        );
+        assert!(FilterSpec::<Value>::Range(KeyRange::default())
+            .to_filter()
+            .unwrap()
+            .key_range()
+            .is_some());
+        assert_eq!(None, exact(FilterSpec::Any));
+    }
+
+    #[test]
+    fn invalid_regexes_are_errors() {
+        assert!(FilterSpec::<Value>::Regex("(".into()).to_filter().is_err());
+        let spec = EaviQuerySpec::<ExampleAttribute> {
+            attribute: FilterSpec::Not(Box::new(FilterSpec::Regex("[".into()))),
+            ..Default::default()
+        };
+        assert!(spec.to_query().is_err());
+    }
+
+    #[test]
+    fn specs_round_trip_through_json() {
+        let spec = EaviQuerySpec {
+            entity: FilterSpec::Exact("entity".into()),
+            attribute: FilterSpec::Or(vec![
+                FilterSpec::Prefix("li".into()),
+                FilterSpec::Not(Box::new(FilterSpec::Regex("^x".into()))),
+            ]),
+            value: FilterSpec::OneOf(vec!["a".into(), "b".into()]),
+            tombstone: Some(FilterSpec::Exact(link("removed"))),
+            retraction: RetractionFilter::Include,
+            order: Order::Descending,
+            limit: Some(10),
+            as_of: Some(5),
+            ..EaviQuerySpec::range(Some(-1), None)
+        };
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(spec, serde_json::from_str(&json).unwrap());
+
+        // anything left out takes its default
+        let spec: EaviQuerySpec<ExampleAttribute> =
+            serde_json::from_str(r#"{"value": {"prefix": "Qm"}}"#).unwrap();
+        assert_eq!(FilterSpec::Prefix("Qm".into()), spec.value);
+        assert_eq!(FilterSpec::Any, spec.entity);
+        assert_eq!(None, spec.limit);
+    }
+
+    #[test]
+    fn specs_run_as_queries() {
+        let mut eav_storage = ExampleEntityAttributeValueStorage::new();
+        let mut add = |attribute: &str, value: &str, index: i64| {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &"entity".into(),
+                &link(attribute),
+                &value.into(),
+                index,
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap().unwrap()
+        };
+        let likes = add("likes", "apple", 1);
+        add("likes", "banana", 2);
+        let lists = add("lists", "apple", 3);
+        add("hates", "apple", 4);
+
+        let spec = EaviQuerySpec {
+            attribute: FilterSpec::Prefix("li".into()),
+            value: FilterSpec::Exact("apple".into()),
+            ..EaviQuerySpec::range(None, None)
+        };
+        let query = spec.to_query().unwrap();
+        assert_eq!(
+            vec![likes, lists],
+            eav_storage
+                .fetch_eavi(&query)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+}
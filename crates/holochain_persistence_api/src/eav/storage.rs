@@ -1,18 +1,25 @@
 use crate::holochain_json_api::json::RawString;
 use cas::content::{AddressableContent, ExampleAddressableContent};
 use eav::{
-    eavi::{EntityAttributeValueIndex, ExampleAttribute},
-    query::EaviQuery,
+    clock::{Clock, WallClock},
+    eavi::{Entity, EntityAttributeValueIndex, ExampleAttribute, Value},
+    join::{Bindings, JoinQuery},
+    live::LiveRegistry,
+    merge::{merge, MergeReport},
+    query::{EaviPage, EaviQuery},
+    traverse::{Traversal, TraversalStep},
     Attribute, EavFilter, IndexFilter,
 };
-use error::PersistenceResult;
+use error::{PersistenceError, PersistenceResult};
 use objekt;
 use reporting::ReportStorage;
 use std::{
-    collections::BTreeSet,
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
     fmt::Debug,
     sync::{Arc, RwLock},
 };
+use uuid::Uuid;
 
 /// This provides a simple and flexible interface to define relationships between AddressableContent.
 /// It does NOT provide storage for AddressableContent.
@@ -22,11 +29,29 @@ pub trait EntityAttributeValueStorage<A: Attribute>:
 {
     /// Adds the given EntityAttributeValue to the EntityAttributeValueStorage
     /// append only storage.
+    /// Returns the eavi as it is stored, which is as it was given, even if another eavi of its
+    /// entity has its index, @see resolve_collision
     fn add_eavi(
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>>;
 
+    /// The clock that stamps eavis made with new_eavi, which observes every eavi added.
+    /// The default is the wall clock, storages that hold a clock return it.
+    fn clock(&self) -> Arc<dyn Clock> {
+        Arc::new(WallClock)
+    }
+
+    /// A new eavi stamped by this storage's clock, for adding to it.
+    fn new_eavi(
+        &self,
+        entity: &Entity,
+        attribute: &A,
+        value: &Value,
+    ) -> PersistenceResult<EntityAttributeValueIndex<A>> {
+        EntityAttributeValueIndex::new_with_clock(entity, attribute, value, &*self.clock())
+    }
+
     /// Fetch the set of EntityAttributeValues that match constraints according to the latest hash version
     /// - None = no constraint
     /// - Some(Entity) = requires the given entity (e.g. all a/v pairs for the entity)
@@ -37,17 +62,151 @@ pub trait EntityAttributeValueStorage<A: Attribute>:
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>;
 
-    // @TODO: would like to do this, but can't because of the generic type param
-    // fn iter<I>(&self) -> I
-    // where
-    //     I: Iterator<Item = EntityAttributeValueIndex>;
+    /// Fetches the page of matching rows the query's order, limit, offset and cursor ask for.
+    /// The default only knows the rows fetch_eavi returns, so it gives a cursor whenever the
+    /// page is full even if nothing follows. Storages should override it to be exact.
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        Ok(query.page_of_fetched(self.fetch_eavi(query)?))
+    }
+
+    /// Fetches the rows that match the query one at a time, so they needn't all be held at once.
+    /// Rows that can be checked on their own come in no particular order, @see
+    /// EaviQuery::can_stream; other queries are run in full and their rows returned in order.
+    /// The default runs every query in full, storages should override it to read lazily.
+    fn fetch_eavi_iter<'s>(
+        &'s self,
+        query: &'s EaviQuery<A>,
+    ) -> PersistenceResult<EaviIter<'s, A>> {
+        let page = self.fetch_eavi_page(query)?;
+        Ok(Box::new(page.rows.into_iter().map(Ok)))
+    }
+
+    /// Counts the rows fetch_eavi would return.
+    /// The default reads them with fetch_eavi_iter, storages can count them without reading
+    /// them into a set.
+    fn count_eavi(&self, query: &EaviQuery<A>) -> PersistenceResult<usize> {
+        count_by(self.fetch_eavi_iter(query)?, |_| ()).map(|counts| counts.values().sum())
+    }
+
+    /// Counts the rows fetch_eavi would return for each attribute.
+    fn count_eavi_by_attribute(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<A, usize>> {
+        count_by(
+            self.fetch_eavi_iter(query)?,
+            EntityAttributeValueIndex::attribute,
+        )
+    }
+
+    /// Counts the rows fetch_eavi would return for each entity.
+    fn count_eavi_by_entity(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<Entity, usize>> {
+        count_by(
+            self.fetch_eavi_iter(query)?,
+            EntityAttributeValueIndex::entity,
+        )
+    }
+
+    /// Follows links from the traversal's start, @see Traversal.
+    /// The default fetches the links from each address with fetch_eavi, storages can read
+    /// them all in one transaction.
+    fn traverse(&self, traversal: &Traversal<A>) -> PersistenceResult<Vec<TraversalStep<A>>> {
+        traversal.run(|query| self.fetch_eavi(query))
+    }
+
+    /// Finds every binding of the query's variables under which all its patterns match,
+    /// @see JoinQuery. Retracted rows never match.
+    /// The default fetches the rows for each pattern with fetch_eavi, storages can read them
+    /// all in one transaction.
+    fn fetch_join(&self, query: &JoinQuery<A>) -> PersistenceResult<BTreeSet<Bindings<A>>> {
+        query.run(|query| self.fetch_eavi(query))
+    }
+
+    /// Adds the rows of other this storage lacks and retracts the rows other retracted, so
+    /// that storages merging each other's rows converge whatever order they merge in,
+    /// @see merge
+    fn merge_from(
+        &mut self,
+        other: &dyn EntityAttributeValueStorage<A>,
+    ) -> PersistenceResult<MergeReport> {
+        merge(self, other)
+    }
+
+    /// Retracts a stored EntityAttributeValueIndex, which must match exactly (index included).
+    /// The row is kept but queries hide it unless they ask for retracted rows,
+    /// @see EaviQuery::with_retraction
+    /// Returns false if no such row is stored or it was already retracted.
+    fn retract_eavi(&mut self, _eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        Err(PersistenceError::ErrorGeneric(
+            "retraction is not supported by this EAV".to_string(),
+        ))
+    }
+
+    /// the id must be the same for every handle on the same stored rows, like a CAS's
+    /// @see ContentAddressableStorage::get_id
+    fn get_id(&self) -> Uuid;
+
+    /// The live queries of the stored rows, which add_eavi and retract_eavi update.
+    /// Clones must return the same registry, so a row written through one reaches them all,
+    /// @see LiveRegistry
+    /// The default is None, for storages that keep no registry, whose live queries only see
+    /// the rows written through a LiveEavStorage.
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        None
+    }
+}
+
+/// The rows of a query as fetch_eavi_iter reads them, along with any error reading a row.
+pub type EaviIter<'s, A> =
+    Box<dyn Iterator<Item = PersistenceResult<EntityAttributeValueIndex<A>>> + 's>;
+
+/// Turns rows from fetch_eavi_iter into a Stream for async code.
+/// Rows are still read by the task polling the stream, as it asks for them.
+pub fn eavi_stream<A: Attribute>(rows: EaviIter<A>) -> futures::stream::Iter<EaviIter<A>> {
+    futures::stream::iter(rows)
+}
+
+/// Counts rows by a key of each, stopping at the first error reading them.
+/// Rows can be borrowed, so storages holding them can count without cloning any.
+pub fn count_by<A, R, I, K, F>(rows: I, key: F) -> PersistenceResult<BTreeMap<K, usize>>
+where
+    A: Attribute,
+    R: Borrow<EntityAttributeValueIndex<A>>,
+    I: Iterator<Item = PersistenceResult<R>>,
+    K: Ord,
+    F: Fn(&EntityAttributeValueIndex<A>) -> K,
+{
+    let mut counts = BTreeMap::new();
+    for row in rows {
+        *counts.entry(key(row?.borrow())).or_insert(0) += 1;
+    }
+    Ok(counts)
 }
 
 clone_trait_object!(<A:Attribute>EntityAttributeValueStorage<A>);
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct ExampleEntityAttributeValueStorage<A: Attribute> {
     storage: Arc<RwLock<BTreeSet<EntityAttributeValueIndex<A>>>>,
+    retracted: Arc<RwLock<BTreeSet<EntityAttributeValueIndex<A>>>>,
+    live: LiveRegistry<A>,
+    clock: Arc<dyn Clock>,
+    id: Uuid,
+}
+
+impl<A: Attribute> Default for ExampleEntityAttributeValueStorage<A> {
+    fn default() -> ExampleEntityAttributeValueStorage<A> {
+        ExampleEntityAttributeValueStorage {
+            storage: Default::default(),
+            retracted: Default::default(),
+            live: Default::default(),
+            clock: Arc::new(WallClock),
+            id: Uuid::new_v4(),
+        }
+    }
 }
 
 impl<A: Attribute> ExampleEntityAttributeValueStorage<A> {
@@ -57,6 +216,37 @@ impl<A: Attribute> ExampleEntityAttributeValueStorage<A> {
     {
         Default::default()
     }
+
+    /// replaces the clock that stamps new eavis, the wall clock by default
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<A: Attribute> ExampleEntityAttributeValueStorage<A>
+where
+    A: std::marker::Send + std::marker::Sync,
+{
+    /// counts the rows the query selects in place, by a key of each
+    fn count_eavi_by<K: Ord, F>(
+        &self,
+        query: &EaviQuery<A>,
+        key: F,
+    ) -> PersistenceResult<BTreeMap<K, usize>>
+    where
+        F: Fn(&EntityAttributeValueIndex<A>) -> K,
+    {
+        if !query.can_stream() {
+            return count_by(self.fetch_eavi(query)?.into_iter().map(Ok), key);
+        }
+        let map = self.storage.read()?;
+        let retracted = self.retracted.read()?;
+        let selected = map.iter().filter(|eavi| {
+            query.retraction().keeps(retracted.contains(eavi)) && query.selects(eavi)
+        });
+        count_by(selected.map(Ok), key)
+    }
 }
 
 impl<A: Attribute> EntityAttributeValueStorage<A> for ExampleEntityAttributeValueStorage<A>
@@ -67,20 +257,70 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
-        let mut map = self.storage.write()?;
-        let new_eav = increment_key_till_no_collision(eav.clone(), map.clone())?;
-        map.insert(new_eav.clone());
-        Ok(Some(new_eav))
+        // eavis are kept whole, so they only collide with themselves
+        self.storage.write()?.insert(eav.clone());
+        self.clock.observe(eav.index());
+        self.live.update(self, eav);
+        Ok(Some(eav.clone()))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        let page = self.fetch_eavi_page(query)?;
+        Ok(page.rows.into_iter().collect())
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
         let lock = self.storage.read()?;
+        let retracted = self.retracted.read()?;
         let set = (*lock).clone();
         let iter = set.iter().cloned();
-        Ok(query.run(iter))
+        Ok(query.run_page_with_retractions(iter, |eavi| retracted.contains(eavi)))
+    }
+
+    fn count_eavi(&self, query: &EaviQuery<A>) -> PersistenceResult<usize> {
+        self.count_eavi_by(query, |_| ())
+            .map(|counts| counts.values().sum())
+    }
+
+    fn count_eavi_by_attribute(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<A, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::attribute)
+    }
+
+    fn count_eavi_by_entity(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<Entity, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::entity)
+    }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        let retracted = {
+            let map = self.storage.read()?;
+            let mut retracted = self.retracted.write()?;
+            map.contains(eav) && retracted.insert(eav.clone())
+        };
+        if retracted {
+            self.live.update(self, eav);
+        }
+        Ok(retracted)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
     }
 }
 
@@ -88,11 +328,11 @@ impl<A: Attribute> ReportStorage for ExampleEntityAttributeValueStorage<A> {}
 
 impl<A: Attribute> PartialEq for dyn EntityAttributeValueStorage<A> {
     fn eq(&self, other: &dyn EntityAttributeValueStorage<A>) -> bool {
-        let query = EaviQuery::default();
-        self.fetch_eavi(&query) == other.fetch_eavi(&query)
+        self.get_id() == other.get_id()
     }
 }
 
+#[deprecated(note = "storages keep eavis at their own index, @see resolve_collision")]
 pub fn increment_key_till_no_collision<A: Attribute>(
     mut eav: EntityAttributeValueIndex<A>,
     map: BTreeSet<EntityAttributeValueIndex<A>>,
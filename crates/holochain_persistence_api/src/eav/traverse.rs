@@ -0,0 +1,160 @@
+//! Following links between AddressableContent, breadth first from one address.
+//! Each address is visited once, at the fewest links from the start, so cycles end the walk
+//! along them instead of looping.
+
+use cas::content::Address;
+use eav::{
+    eavi::{Attribute, EntityAttributeValueIndex},
+    query::{EavFilter, EaviQuery, IndexFilter},
+};
+use error::PersistenceResult;
+use std::collections::{BTreeSet, VecDeque};
+
+/// which way a traversal follows links
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// from entities to the values they link to
+    Forward,
+    /// from values to the entities linking to them
+    Reverse,
+}
+
+impl Default for Direction {
+    fn default() -> Direction {
+        Direction::Forward
+    }
+}
+
+/// Describes a walk over links from a starting address.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Traversal<A: Attribute> {
+    start: Address,
+    /// the attributes of the links to follow, or every link if empty
+    attributes: Vec<A>,
+    direction: Direction,
+    /// the most links from the start to follow, or no limit if None
+    max_depth: Option<usize>,
+}
+
+/// An address a traversal reached, along with the links it followed to get there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraversalStep<A: Attribute> {
+    pub address: Address,
+    /// how many links from the start the address is
+    pub depth: usize,
+    /// the links from the start to the address, in the order they were followed
+    pub path: Vec<EntityAttributeValueIndex<A>>,
+}
+
+impl<A: Attribute> Traversal<A> {
+    /// a traversal following every link forward from start
+    pub fn new(start: Address) -> Traversal<A> {
+        Traversal {
+            start,
+            attributes: Vec::new(),
+            direction: Direction::default(),
+            max_depth: None,
+        }
+    }
+
+    /// follows links with the attribute, alongside any others already followed
+    pub fn follow(mut self, attribute: A) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// follows links backwards, from values to the entities linking to them
+    pub fn reverse(self) -> Self {
+        self.with_direction(Direction::Reverse)
+    }
+
+    /// stops after following max_depth links from the start
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn start(&self) -> &Address {
+        &self.start
+    }
+    pub fn attributes(&self) -> &[A] {
+        &self.attributes
+    }
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// the query for the links to follow from an address
+    pub fn links_from<'a>(&self, address: &Address) -> EaviQuery<'a, A>
+    where
+        A: 'a,
+    {
+        let attribute = match self.attributes.as_slice() {
+            [] => EavFilter::default(),
+            [attribute] => EavFilter::single(attribute.clone()),
+            attributes => EavFilter::multiple(attributes.to_vec()),
+        };
+        let (entity, value) = match self.direction {
+            Direction::Forward => (EavFilter::single(address.clone()), EavFilter::default()),
+            Direction::Reverse => (EavFilter::default(), EavFilter::single(address.clone())),
+        };
+        EaviQuery::new(
+            entity,
+            attribute,
+            value,
+            IndexFilter::Range(None, None),
+            None,
+        )
+    }
+
+    /// the address a link leads to
+    pub fn follows_to(&self, link: &EntityAttributeValueIndex<A>) -> Address {
+        match self.direction {
+            Direction::Forward => link.value(),
+            Direction::Reverse => link.entity(),
+        }
+    }
+
+    /// Walks the links, getting the links from each address with fetch_links, which is given
+    /// the query from links_from. Returns every address reached apart from the start, nearest
+    /// first.
+    pub fn run<F>(&self, mut fetch_links: F) -> PersistenceResult<Vec<TraversalStep<A>>>
+    where
+        F: FnMut(&EaviQuery<A>) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>>,
+    {
+        let mut visited = BTreeSet::new();
+        visited.insert(self.start.clone());
+        let mut queue = VecDeque::new();
+        queue.push_back((self.start.clone(), Vec::new()));
+        let mut steps = Vec::new();
+        while let Some((address, path)) = queue.pop_front() {
+            match self.max_depth {
+                Some(max_depth) if path.len() >= max_depth => continue,
+                _ => (),
+            }
+            for link in fetch_links(&self.links_from(&address))? {
+                let next = self.follows_to(&link);
+                if !visited.insert(next.clone()) {
+                    continue;
+                }
+                let mut next_path = path.clone();
+                next_path.push(link);
+                steps.push(TraversalStep {
+                    address: next.clone(),
+                    depth: next_path.len(),
+                    path: next_path.clone(),
+                });
+                queue.push_back((next, next_path));
+            }
+        }
+        Ok(steps)
+    }
+}
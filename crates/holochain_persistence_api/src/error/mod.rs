@@ -1,6 +1,7 @@
 //! This module contains Error type definitions that are used throughout persistence.
 
 use self::PersistenceError::*;
+use crate::cas::content::Address;
 use futures::channel::oneshot::Canceled as FutureCanceled;
 use holochain_json_api::{error::JsonError, json::*};
 use serde_json::Error as SerdeError;
@@ -9,12 +10,76 @@ use std::{
     fmt,
     io::{self, Error as IoError},
     option::NoneError,
+    sync::PoisonError,
 };
 
 //--------------------------------------------------------------------------------------------------
 // PersistenceError
 //--------------------------------------------------------------------------------------------------
 
+/// where and why a persistence operation failed
+/// the cause, if any, is the error it failed with and is what `Error::source` returns
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash, PartialOrd, Ord)]
+pub struct ErrorContext {
+    /// the backend that failed, e.g. "lmdb"
+    pub backend: String,
+    /// what the backend was asked to do, e.g. "add"
+    pub operation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cause: Option<Box<PersistenceError>>,
+}
+
+impl ErrorContext {
+    pub fn new(backend: &str, operation: &str) -> ErrorContext {
+        ErrorContext {
+            backend: backend.to_string(),
+            operation: operation.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_address(mut self, address: &Address) -> ErrorContext {
+        self.address = Some(address.clone());
+        self
+    }
+
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> ErrorContext {
+        self.message = message.into();
+        self
+    }
+
+    pub fn with_cause<E: Into<PersistenceError>>(mut self, cause: E) -> ErrorContext {
+        self.cause = Some(Box::new(cause.into()));
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    /// `<backend> <operation> of <address>: <message>: <cause>`, leaving out whatever is missing
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut at: Vec<String> = [&self.backend, &self.operation]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| part.to_string())
+            .collect();
+        if let Some(address) = &self.address {
+            if !at.is_empty() {
+                at.push("of".to_string());
+            }
+            at.push(address.to_string());
+        }
+        let mut parts = vec![at.join(" "), self.message.clone()];
+        if let Some(cause) = &self.cause {
+            parts.push(cause.to_string());
+        }
+        let parts: Vec<String> = parts.into_iter().filter(|part| !part.is_empty()).collect();
+        write!(f, "{}", parts.join(": "))
+    }
+}
+
 #[derive(
     Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DefaultJson, Hash, PartialOrd, Ord,
 )]
@@ -22,12 +87,85 @@ pub enum PersistenceError {
     ErrorGeneric(String),
     IoError(String),
     SerializationError(String),
+    /// a write was attempted on a store opened read-only
+    ReadOnlyError(ErrorContext),
+    /// the store has no room left for a write, even after trying to grow
+    MapFull(ErrorContext),
+    /// stored data could not be read back as what was written
+    Corruption(ErrorContext),
+    /// something the operation needs, such as a store or its files, does not exist
+    NotFound(ErrorContext),
+    /// a lock guarding the store was poisoned by a panic while it was held
+    LockPoisoned(ErrorContext),
+    /// the process may not read or write the store's files
+    PermissionDenied(ErrorContext),
+    /// any other failure reported by a backend
+    Backend(ErrorContext),
 }
 
 impl PersistenceError {
     pub fn new(msg: &str) -> PersistenceError {
         PersistenceError::ErrorGeneric(msg.to_string())
     }
+
+    /// the context of the structured variants
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            ErrorGeneric(_) | IoError(_) | SerializationError(_) => None,
+            ReadOnlyError(context)
+            | MapFull(context)
+            | Corruption(context)
+            | NotFound(context)
+            | LockPoisoned(context)
+            | PermissionDenied(context)
+            | Backend(context) => Some(context),
+        }
+    }
+
+    /// the error of an io operation, matching on its kind and keeping it as the cause
+    pub fn io(context: ErrorContext, error: &IoError) -> PersistenceError {
+        let context = context.with_cause(PersistenceError::IoError(reason_for_io_error(error)));
+        match error.kind() {
+            io::ErrorKind::NotFound => PersistenceError::NotFound(context),
+            io::ErrorKind::PermissionDenied => PersistenceError::PermissionDenied(context),
+            _ => PersistenceError::Backend(context),
+        }
+    }
+
+    /// the error of taking a lock that a panic poisoned
+    pub fn poisoned<T>(context: ErrorContext, error: &PoisonError<T>) -> PersistenceError {
+        PersistenceError::LockPoisoned(context.with_message(error.to_string()))
+    }
+
+    /// names the backend and operation of errors converted without them, such as io errors and
+    /// poisoned locks, leaving errors that already name a backend as they are
+    pub fn in_backend(mut self, backend: &str, operation: &str) -> PersistenceError {
+        if let ReadOnlyError(context)
+        | MapFull(context)
+        | Corruption(context)
+        | NotFound(context)
+        | LockPoisoned(context)
+        | PermissionDenied(context)
+        | Backend(context) = &mut self
+        {
+            if context.backend.is_empty() {
+                context.backend = backend.to_string();
+                context.operation = operation.to_string();
+            }
+        }
+        self
+    }
+}
+
+/// names the backend of the errors of results, @see PersistenceError::in_backend
+pub trait InBackend<T> {
+    fn in_backend(self, backend: &str, operation: &str) -> PersistenceResult<T>;
+}
+
+impl<T, E: Into<PersistenceError>> InBackend<T> for Result<T, E> {
+    fn in_backend(self, backend: &str, operation: &str) -> PersistenceResult<T> {
+        self.map_err(|e| e.into().in_backend(backend, operation))
+    }
 }
 
 impl From<JsonError> for PersistenceError {
@@ -47,11 +185,24 @@ impl fmt::Display for PersistenceError {
             ErrorGeneric(err_msg) => write!(f, "{}", err_msg),
             SerializationError(err_msg) => write!(f, "{}", err_msg),
             IoError(err_msg) => write!(f, "{}", err_msg),
+            ReadOnlyError(context) => write!(f, "read-only: {}", context),
+            MapFull(context) => write!(f, "store full: {}", context),
+            Corruption(context) => write!(f, "corrupt data: {}", context),
+            NotFound(context) => write!(f, "not found: {}", context),
+            LockPoisoned(context) => write!(f, "lock poisoned: {}", context),
+            PermissionDenied(context) => write!(f, "permission denied: {}", context),
+            Backend(context) => write!(f, "{}", context),
         }
     }
 }
 
-impl Error for PersistenceError {}
+impl Error for PersistenceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.context()
+            .and_then(|context| context.cause.as_ref())
+            .map(|cause| &**cause as &(dyn Error + 'static))
+    }
+}
 
 impl From<PersistenceError> for String {
     fn from(holochain_persistence_error: PersistenceError) -> Self {
@@ -75,20 +226,21 @@ impl From<&'static str> for PersistenceError {
 fn reason_for_io_error(error: &IoError) -> String {
     match error.kind() {
         io::ErrorKind::InvalidData => format!("contains invalid data: {}", error),
-        io::ErrorKind::PermissionDenied => format!("missing permissions to read: {}", error),
         _ => format!("unexpected error: {}", error),
     }
 }
 
-impl<T> From<::std::sync::PoisonError<T>> for PersistenceError {
-    fn from(error: ::std::sync::PoisonError<T>) -> Self {
-        PersistenceError::ErrorGeneric(format!("sync poison error: {}", error))
+/// names no backend, which backends add with in_backend
+impl<T> From<PoisonError<T>> for PersistenceError {
+    fn from(error: PoisonError<T>) -> Self {
+        PersistenceError::poisoned(ErrorContext::new("", "lock"), &error)
     }
 }
 
+/// names no backend, which backends add with in_backend
 impl From<IoError> for PersistenceError {
     fn from(error: IoError) -> Self {
-        PersistenceError::IoError(reason_for_io_error(&error))
+        PersistenceError::io(ErrorContext::new("", "io"), &error)
     }
 }
 
@@ -195,8 +347,123 @@ mod tests {
                 "foo",
             ),
             (PersistenceError::IoError(String::from("foo")), "foo"),
+            (
+                PersistenceError::ReadOnlyError(ErrorContext::new("lmdb", "add")),
+                "read-only: lmdb add",
+            ),
+            (
+                PersistenceError::MapFull(
+                    ErrorContext::new("lmdb", "add").with_message("MDB_MAP_FULL"),
+                ),
+                "store full: lmdb add: MDB_MAP_FULL",
+            ),
+            (
+                PersistenceError::Corruption(
+                    ErrorContext::new("pickle", "fetch")
+                        .with_address(&Address::from("Qm"))
+                        .with_message("bad cbor"),
+                ),
+                "corrupt data: pickle fetch of Qm: bad cbor",
+            ),
+            (
+                PersistenceError::Backend(
+                    ErrorContext::new("file", "add").with_cause(PersistenceError::new("foo")),
+                ),
+                "file add: foo",
+            ),
         ] {
             assert_eq!(output, &input.to_string());
         }
     }
+
+    #[test]
+    /// the cause of a structured error is its source
+    fn structured_errors_keep_their_source() {
+        let cause = PersistenceError::IoError("disk on fire".to_string());
+        let err = PersistenceError::Backend(
+            ErrorContext::new("lmdb", "add")
+                .with_message("write failed")
+                .with_cause(cause.clone()),
+        );
+        assert_eq!(
+            Some(cause.to_string()),
+            err.source().map(|source| source.to_string())
+        );
+        assert_eq!(Some("lmdb"), err.context().map(|c| c.backend.as_str()));
+        assert!(PersistenceError::new("foo").source().is_none());
+        assert!(PersistenceError::new("foo").context().is_none());
+    }
+
+    #[test]
+    /// structured errors round trip through JSON along with their causes
+    fn structured_errors_are_serializable() {
+        let err = PersistenceError::NotFound(
+            ErrorContext::new("file", "fetch")
+                .with_address(&Address::from("Qm"))
+                .with_cause(PersistenceError::LockPoisoned(ErrorContext::new(
+                    "", "lock",
+                ))),
+        );
+        let json = JsonString::from(err.clone());
+        assert_eq!(
+            JsonString::from_json(
+                "{\"NotFound\":{\"backend\":\"file\",\"operation\":\"fetch\",\"address\":\"Qm\",\"message\":\"\",\"cause\":{\"LockPoisoned\":{\"backend\":\"\",\"operation\":\"lock\",\"message\":\"\"}}}}"
+            ),
+            json
+        );
+        assert_eq!(err, serde_json::from_str(&String::from(json)).unwrap());
+    }
+
+    #[test]
+    /// io errors that say why they failed are matchable
+    fn io_errors_map_to_variants() {
+        let not_found: PersistenceError = io::Error::from(io::ErrorKind::NotFound).into();
+        match not_found {
+            PersistenceError::NotFound(_) => (),
+            e => panic!("expected NotFound, got {:?}", e),
+        }
+        let denied: PersistenceError = io::Error::from(io::ErrorKind::PermissionDenied).into();
+        match denied {
+            PersistenceError::PermissionDenied(_) => (),
+            e => panic!("expected PermissionDenied, got {:?}", e),
+        }
+        let other: PersistenceError = io::Error::from(io::ErrorKind::InvalidData).into();
+        match &other {
+            PersistenceError::Backend(_) => (),
+            e => panic!("expected Backend, got {:?}", e),
+        }
+        // the io error is kept as the cause
+        for e in &[not_found, denied, other] {
+            match e.source().and_then(|source| source.downcast_ref()) {
+                Some(PersistenceError::IoError(_)) => (),
+                source => panic!("expected an io error cause, got {:?}", source),
+            }
+        }
+
+        let lock = std::sync::RwLock::new(());
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = lock.write().unwrap();
+            panic!("poison the lock");
+        });
+        match PersistenceError::from(lock.read().unwrap_err()) {
+            PersistenceError::LockPoisoned(_) => (),
+            e => panic!("expected LockPoisoned, got {:?}", e),
+        }
+    }
+
+    #[test]
+    /// backends name themselves in the errors they convert
+    fn converted_errors_name_their_backend() {
+        let read: Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::NotFound));
+        match read.in_backend("file", "fetch") {
+            Err(PersistenceError::NotFound(context)) => {
+                assert_eq!(("file", "fetch"), (&*context.backend, &*context.operation))
+            }
+            e => panic!("expected NotFound, got {:?}", e),
+        }
+        let named = PersistenceError::Backend(ErrorContext::new("lmdb", "add"));
+        assert_eq!(named.clone(), named.clone().in_backend("file", "fetch"));
+        let plain = PersistenceError::new("foo");
+        assert_eq!(plain.clone(), plain.clone().in_backend("file", "fetch"));
+    }
 }
@@ -0,0 +1,76 @@
+//! Persistent identity for stores.
+//! A store is given a random id when it is first created and keeps it for as long as its data
+//! lives, so every handle opened on the same data reports the same id.
+//! Backends that keep their data in files can keep the id next to it with these helpers.
+
+use crate::error::{PersistenceError, PersistenceResult};
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    path::Path,
+};
+use uuid::Uuid;
+
+/// reads the id kept in the given file
+pub fn load_id(path: &Path) -> PersistenceResult<Option<Uuid>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let id = read_to_string(path)?;
+    Uuid::parse_str(id.trim()).map(Some).map_err(|e| {
+        PersistenceError::ErrorGeneric(format!("invalid store id in {}: {}", path.display(), e))
+    })
+}
+
+/// reads the id kept in the given file, creating the file with a new id the first time
+pub fn load_or_create_id(path: &Path) -> PersistenceResult<Uuid> {
+    if let Some(id) = load_id(path)? {
+        return Ok(id);
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let id = Uuid::new_v4();
+    write(path, id.to_string())?;
+    Ok(id)
+}
+
+/// reads the id kept in the given file without ever writing it
+/// stores that never had an id persisted get one that lasts as long as the handle
+pub fn load_id_or_ephemeral(path: &Path) -> PersistenceResult<Uuid> {
+    Ok(load_id(path)?.unwrap_or_else(Uuid::new_v4))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn id_is_created_once() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("store").join("id");
+
+        assert_eq!(Ok(None), load_id(&path));
+        let id = load_or_create_id(&path).unwrap();
+        assert_eq!(Ok(id), load_or_create_id(&path));
+        assert_eq!(Ok(id), load_id_or_ephemeral(&path));
+    }
+
+    #[test]
+    fn ephemeral_id_is_not_persisted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("id");
+
+        let first = load_id_or_ephemeral(&path).unwrap();
+        assert_ne!(first, load_id_or_ephemeral(&path).unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn invalid_id_is_an_error() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("id");
+        write(&path, "not a uuid").unwrap();
+        assert!(load_or_create_id(&path).is_err());
+    }
+}
@@ -12,22 +12,31 @@ extern crate lazy_static;
 extern crate chrono;
 extern crate futures;
 extern crate multihash;
+extern crate rand;
 extern crate regex;
 extern crate rust_base58;
+extern crate serde;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate holochain_json_derive;
 extern crate holochain_json_api;
+extern crate holochain_logging;
+#[cfg(test)]
+extern crate tempfile;
 extern crate uuid;
 
 pub mod cas;
+pub mod diff;
 pub mod eav;
 pub mod error;
 pub mod fixture;
 pub mod hash;
+pub mod identity;
+pub mod registry;
 pub mod reporting;
+pub mod txn;
 
 #[macro_use]
 extern crate objekt;
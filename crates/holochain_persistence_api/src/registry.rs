@@ -0,0 +1,426 @@
+//! Opening stores from configuration instead of naming a backend in code.
+//!
+//! A StoreConfig says which backend to use (its scheme), where its data lives and any backend
+//! specific parameters. It can be parsed from a URI such as
+//! `lmdb:///data/cas?initial_map_bytes=104857600`, `pickle:///data/eav`, `file:///data` or
+//! `mem://`, or deserialized from a service's own configuration. Paths and parameters are
+//! percent-encoded in URIs, so `file:///my%20data` is the path `/my data`.
+//!
+//! A Registry maps schemes to factories that build boxed stores from a StoreConfig. The backend
+//! crates each provide a `register` function (behind their `registry` feature) that adds their
+//! factories, so a service only links the backends it registers.
+
+use crate::{
+    cas::storage::ContentAddressableStorage,
+    eav::{Attribute, EntityAttributeValueStorage},
+    error::{PersistenceError, PersistenceResult},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// which backend to open a store with and how
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreConfig {
+    pub scheme: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+impl StoreConfig {
+    pub fn new(scheme: &str) -> StoreConfig {
+        StoreConfig {
+            scheme: scheme.to_string(),
+            path: None,
+            params: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> StoreConfig {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_param<V: ToString>(mut self, key: &str, value: V) -> StoreConfig {
+        self.params.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// parses a `scheme://path?key=value&...` URI
+    /// the path is everything between `://` and `?`, so `lmdb:///data` is the absolute path
+    /// `/data` and `mem://` has no path
+    /// the path and parameters are percent-decoded
+    pub fn parse(uri: &str) -> PersistenceResult<StoreConfig> {
+        let invalid = |reason: &str| {
+            PersistenceError::ErrorGeneric(format!("invalid store URI {}: {}", uri, reason))
+        };
+
+        let scheme_end = uri.find("://").ok_or_else(|| invalid("missing ://"))?;
+        let scheme = &uri[..scheme_end];
+        if scheme.is_empty() {
+            return Err(invalid("missing scheme"));
+        }
+        let rest = &uri[scheme_end + 3..];
+        let (path, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+
+        let decode = |part: &str| decode(part).ok_or_else(|| invalid("bad percent-encoding"));
+        let mut config = StoreConfig::new(scheme);
+        if !path.is_empty() {
+            config.path = Some(PathBuf::from(decode(path)?));
+        }
+        for pair in query.unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let mut key_value = pair.splitn(2, '=');
+            let key = key_value.next().unwrap_or("");
+            if key.is_empty() {
+                return Err(invalid("parameter without a name"));
+            }
+            config
+                .params
+                .insert(decode(key)?, decode(key_value.next().unwrap_or(""))?);
+        }
+        Ok(config)
+    }
+
+    /// the path, for backends that keep their data on disk
+    pub fn required_path(&self) -> PersistenceResult<&Path> {
+        self.path.as_deref().ok_or_else(|| {
+            PersistenceError::ErrorGeneric(format!("{} stores need a path", self.scheme))
+        })
+    }
+
+    /// the named parameter parsed as a T, if it was given
+    pub fn param<T: FromStr>(&self, key: &str) -> PersistenceResult<Option<T>> {
+        match self.params.get(key) {
+            Some(value) => value.parse().map(Some).map_err(|_| {
+                PersistenceError::ErrorGeneric(format!(
+                    "invalid value for {} parameter {}: {}",
+                    self.scheme, key, value
+                ))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// whether the `read_only` parameter is set, which backends that support it honour by
+    /// opening the store with `new_read_only`
+    pub fn read_only(&self) -> PersistenceResult<bool> {
+        Ok(self.param("read_only")?.unwrap_or(false))
+    }
+}
+
+impl FromStr for StoreConfig {
+    type Err = PersistenceError;
+
+    fn from_str(uri: &str) -> PersistenceResult<StoreConfig> {
+        StoreConfig::parse(uri)
+    }
+}
+
+impl fmt::Display for StoreConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://", self.scheme)?;
+        if let Some(path) = &self.path {
+            write!(f, "{}", encode(&path.to_string_lossy(), b"/:"))?;
+        }
+        for (i, (key, value)) in self.params.iter().enumerate() {
+            let separator = if i == 0 { "?" } else { "&" };
+            write!(
+                f,
+                "{}{}={}",
+                separator,
+                encode(key, b""),
+                encode(value, b"")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// percent-encodes every byte of a part of a URI but the unreserved ones and those kept
+fn encode(part: &str, keep: &[u8]) -> String {
+    let mut encoded = String::with_capacity(part.len());
+    for byte in part.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) || keep.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// decodes the percent-encoded bytes of a part of a URI, or None if an escape is malformed or
+/// the bytes aren't UTF-8
+fn decode(part: &str) -> Option<String> {
+    let bytes = part.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+pub type CasFactory = Box<
+    dyn Fn(&StoreConfig) -> PersistenceResult<Box<dyn ContentAddressableStorage>> + Send + Sync,
+>;
+
+pub type EavFactory<A> = Box<
+    dyn Fn(&StoreConfig) -> PersistenceResult<Box<dyn EntityAttributeValueStorage<A>>>
+        + Send
+        + Sync,
+>;
+
+/// factories for opening stores, keyed by scheme
+pub struct Registry<A: Attribute> {
+    cas_factories: HashMap<String, CasFactory>,
+    eav_factories: HashMap<String, EavFactory<A>>,
+}
+
+impl<A: Attribute> Default for Registry<A> {
+    fn default() -> Registry<A> {
+        Registry {
+            cas_factories: HashMap::new(),
+            eav_factories: HashMap::new(),
+        }
+    }
+}
+
+impl<A: Attribute> fmt::Debug for Registry<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("cas_schemes", &self.cas_schemes())
+            .field("eav_schemes", &self.eav_schemes())
+            .finish()
+    }
+}
+
+impl<A: Attribute> Registry<A> {
+    pub fn new() -> Registry<A> {
+        Default::default()
+    }
+
+    /// registers the factory for CAS URIs with the given scheme, replacing any earlier one
+    pub fn register_cas<F>(&mut self, scheme: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&StoreConfig) -> PersistenceResult<Box<dyn ContentAddressableStorage>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.cas_factories
+            .insert(scheme.to_string(), Box::new(factory));
+        self
+    }
+
+    /// registers the factory for EAV URIs with the given scheme, replacing any earlier one
+    pub fn register_eav<F>(&mut self, scheme: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&StoreConfig) -> PersistenceResult<Box<dyn EntityAttributeValueStorage<A>>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.eav_factories
+            .insert(scheme.to_string(), Box::new(factory));
+        self
+    }
+
+    pub fn cas_schemes(&self) -> Vec<&str> {
+        let mut schemes: Vec<&str> = self.cas_factories.keys().map(String::as_str).collect();
+        schemes.sort();
+        schemes
+    }
+
+    pub fn eav_schemes(&self) -> Vec<&str> {
+        let mut schemes: Vec<&str> = self.eav_factories.keys().map(String::as_str).collect();
+        schemes.sort();
+        schemes
+    }
+
+    pub fn open_cas(
+        &self,
+        config: &StoreConfig,
+    ) -> PersistenceResult<Box<dyn ContentAddressableStorage>> {
+        let factory = self
+            .cas_factories
+            .get(&config.scheme)
+            .ok_or_else(|| unknown_scheme("CAS", &config.scheme))?;
+        factory(config)
+    }
+
+    pub fn open_eav(
+        &self,
+        config: &StoreConfig,
+    ) -> PersistenceResult<Box<dyn EntityAttributeValueStorage<A>>> {
+        let factory = self
+            .eav_factories
+            .get(&config.scheme)
+            .ok_or_else(|| unknown_scheme("EAV", &config.scheme))?;
+        factory(config)
+    }
+
+    pub fn open_cas_uri(&self, uri: &str) -> PersistenceResult<Box<dyn ContentAddressableStorage>> {
+        self.open_cas(&StoreConfig::parse(uri)?)
+    }
+
+    pub fn open_eav_uri(
+        &self,
+        uri: &str,
+    ) -> PersistenceResult<Box<dyn EntityAttributeValueStorage<A>>> {
+        self.open_eav(&StoreConfig::parse(uri)?)
+    }
+}
+
+fn unknown_scheme(kind: &str, scheme: &str) -> PersistenceError {
+    PersistenceError::ErrorGeneric(format!("no {} backend registered for {}://", kind, scheme))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        cas::{
+            content::{AddressableContent, Content},
+            storage::ExampleContentAddressableStorage,
+        },
+        eav::{
+            EaviQuery, EntityAttributeValueIndex, ExampleAttribute,
+            ExampleEntityAttributeValueStorage,
+        },
+    };
+    use holochain_json_api::json::RawString;
+
+    fn example_registry() -> Registry<ExampleAttribute> {
+        let mut registry = Registry::new();
+        registry
+            .register_cas("example", |_: &StoreConfig| {
+                Ok(Box::new(ExampleContentAddressableStorage::new()?)
+                    as Box<dyn ContentAddressableStorage>)
+            })
+            .register_eav("example", |_: &StoreConfig| {
+                Ok(Box::new(ExampleEntityAttributeValueStorage::new())
+                    as Box<dyn EntityAttributeValueStorage<ExampleAttribute>>)
+            });
+        registry
+    }
+
+    #[test]
+    fn parse_store_uris() {
+        let config =
+            StoreConfig::parse("lmdb:///data/cas?initial_map_bytes=1024&read_only=true").unwrap();
+        assert_eq!(
+            StoreConfig::new("lmdb")
+                .with_path("/data/cas")
+                .with_param("initial_map_bytes", 1024)
+                .with_param("read_only", true),
+            config
+        );
+        assert_eq!(Ok(Some(1024)), config.param::<usize>("initial_map_bytes"));
+        assert_eq!(Ok(true), config.read_only());
+        assert_eq!(Ok(None), config.param::<usize>("missing"));
+        assert!(config.param::<usize>("read_only").is_err());
+
+        let config: StoreConfig = "mem://".parse().unwrap();
+        assert_eq!(StoreConfig::new("mem"), config);
+        assert_eq!(Ok(false), config.read_only());
+        assert!(config.required_path().is_err());
+
+        assert_eq!(
+            Some(PathBuf::from("relative/dir")),
+            StoreConfig::parse("file://relative/dir").unwrap().path
+        );
+
+        assert!(StoreConfig::parse("/data/cas").is_err());
+        assert!(StoreConfig::parse("://data").is_err());
+        assert!(StoreConfig::parse("lmdb:///data?=1").is_err());
+    }
+
+    #[test]
+    fn store_uris_round_trip() {
+        for uri in [
+            "mem://",
+            "file:///data",
+            "lmdb:///data/cas?initial_map_bytes=1024&read_only=true",
+        ]
+        .iter()
+        {
+            assert_eq!(*uri, StoreConfig::parse(uri).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn store_uris_percent_encode_paths_and_params() {
+        let config = StoreConfig::new("file")
+            .with_path("/my data/100%")
+            .with_param("label", "a&b=c");
+        let uri = config.to_string();
+        assert_eq!("file:///my%20data/100%25?label=a%26b%3Dc", uri);
+        assert_eq!(config, StoreConfig::parse(&uri).unwrap());
+
+        assert!(StoreConfig::parse("file:///data%2").is_err());
+        assert!(StoreConfig::parse("file:///data%zz").is_err());
+        assert!(StoreConfig::parse("file:///data%FF").is_err());
+    }
+
+    #[test]
+    fn store_config_is_deserializable() {
+        let config: StoreConfig = serde_json::from_str(
+            r#"{"scheme":"lmdb","path":"/data","params":{"initial_map_bytes":"1024"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            StoreConfig::parse("lmdb:///data?initial_map_bytes=1024").unwrap(),
+            config
+        );
+        let config: StoreConfig = serde_json::from_str(r#"{"scheme":"mem"}"#).unwrap();
+        assert_eq!(StoreConfig::new("mem"), config);
+        assert_eq!(
+            r#"{"scheme":"mem"}"#,
+            serde_json::to_string(&config).unwrap()
+        );
+    }
+
+    #[test]
+    fn registry_opens_registered_schemes() {
+        let registry = example_registry();
+        assert_eq!(vec!["example"], registry.cas_schemes());
+        assert_eq!(vec!["example"], registry.eav_schemes());
+
+        let mut cas = registry.open_cas_uri("example://").unwrap();
+        let content = Content::from(RawString::from("foo"));
+        cas.add(&content).unwrap();
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+
+        let mut eav = registry.open_eav_uri("example://").unwrap();
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        eav.add_eavi(&eavi).unwrap();
+        assert_eq!(1, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+
+        assert!(registry.open_cas_uri("lmdb:///data").is_err());
+        assert!(registry.open_eav(&StoreConfig::new("lmdb")).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+//! Transactions spanning a ContentAddressableStorage and an EntityAttributeValueStorage.
+//! Content and the metadata that refers to it are staged in a Transaction and then committed
+//! together, or rolled back so that nothing is written.
+//!
+//! How atomic a commit is depends on the TransactionalStorage it is applied to.
+//! Backends that can write both stores in a single native transaction (e.g. LMDB when the CAS
+//! and EAV share an environment) apply everything or nothing.
+//! Every other pairing of stores can use the JournalStorage, which writes the staged content and
+//! metadata to a journal file before applying them, content before metadata, and removes the
+//! journal once they are applied. A commit that fails or is cut short by a crash leaves the
+//! journal behind, and it is applied again before the next commit or when the JournalStorage is
+//! next created, so the writes are all made eventually. Until then there may be content nothing
+//! refers to yet, but never metadata pointing at content that was not stored.
+
+use crate::{
+    cas::{
+        content::{Address, AddressableContent, Content},
+        storage::ContentAddressableStorage,
+    },
+    eav::{Attribute, EntityAttributeValueIndex, EntityAttributeValueStorage},
+    error::{ErrorContext, PersistenceError, PersistenceResult},
+};
+use holochain_json_api::error::JsonError;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+/// content staged in a transaction
+/// keeps the address of the original AddressableContent as it may not be the hash of the content
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StagedContent {
+    address: Address,
+    content: Content,
+}
+
+impl StagedContent {
+    pub fn new(content: &dyn AddressableContent) -> StagedContent {
+        StagedContent {
+            address: content.address(),
+            content: content.content(),
+        }
+    }
+}
+
+impl AddressableContent for StagedContent {
+    fn address(&self) -> Address {
+        self.address.clone()
+    }
+
+    fn content(&self) -> Content {
+        self.content.clone()
+    }
+
+    fn try_from_content(content: &Content) -> Result<Self, JsonError> {
+        Ok(StagedContent::new(content))
+    }
+}
+
+/// everything staged in a transaction, in the order it was staged
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StagedWrites<A: Attribute> {
+    pub contents: Vec<StagedContent>,
+    pub eavis: Vec<EntityAttributeValueIndex<A>>,
+}
+
+impl<A: Attribute> Default for StagedWrites<A> {
+    fn default() -> StagedWrites<A> {
+        StagedWrites {
+            contents: Vec::new(),
+            eavis: Vec::new(),
+        }
+    }
+}
+
+impl<A: Attribute> StagedWrites<A> {
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty() && self.eavis.is_empty()
+    }
+}
+
+/// a pair of stores that staged writes can be committed to
+pub trait TransactionalStorage<A: Attribute>: Send + Sync {
+    /// writes all the staged content and metadata
+    /// returns the EAVIs as they were stored, as add_eavi may change their index
+    fn commit_writes(
+        &mut self,
+        writes: StagedWrites<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>>;
+
+    /// starts a new transaction against this storage
+    fn begin(&mut self) -> Transaction<'_, A, Self>
+    where
+        Self: Sized,
+    {
+        Transaction::begin(self)
+    }
+}
+
+/// stages content and metadata until it is committed or rolled back
+/// dropping a transaction without committing it is the same as rolling it back
+pub struct Transaction<'s, A: Attribute, S: TransactionalStorage<A>> {
+    storage: &'s mut S,
+    staged: StagedWrites<A>,
+}
+
+impl<'s, A: Attribute, S: TransactionalStorage<A>> Transaction<'s, A, S> {
+    pub fn begin(storage: &'s mut S) -> Transaction<'s, A, S> {
+        Transaction {
+            storage,
+            staged: StagedWrites::default(),
+        }
+    }
+
+    pub fn add_content(&mut self, content: &dyn AddressableContent) -> &mut Self {
+        self.staged.contents.push(StagedContent::new(content));
+        self
+    }
+
+    pub fn add_eavi(&mut self, eavi: &EntityAttributeValueIndex<A>) -> &mut Self {
+        self.staged.eavis.push(eavi.clone());
+        self
+    }
+
+    pub fn staged(&self) -> &StagedWrites<A> {
+        &self.staged
+    }
+
+    /// writes everything staged so far
+    pub fn commit(self) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        if self.staged.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.storage.commit_writes(self.staged)
+    }
+
+    /// discards everything staged so far
+    pub fn rollback(self) {}
+}
+
+/// TransactionalStorage for any CAS and EAV, journaling staged writes to a file and applying
+/// staged content before staged metadata
+/// @see the module docs for the guarantees this gives
+#[derive(Clone, Debug)]
+pub struct JournalStorage<
+    A: Attribute,
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+> {
+    cas: C,
+    eav: E,
+    journal: PathBuf,
+    attribute: PhantomData<A>,
+}
+
+impl<A, C, E> JournalStorage<A, C, E>
+where
+    A: Attribute + DeserializeOwned,
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+{
+    /// journals commits to the file at the given path, first applying any journal left there
+    /// by a commit that did not finish
+    pub fn new<P: AsRef<Path>>(cas: C, eav: E, journal: P) -> PersistenceResult<Self> {
+        let mut storage = JournalStorage {
+            cas,
+            eav,
+            journal: journal.as_ref().to_path_buf(),
+            attribute: PhantomData,
+        };
+        storage.recover()?;
+        Ok(storage)
+    }
+
+    pub fn cas(&self) -> &C {
+        &self.cas
+    }
+
+    pub fn eav(&self) -> &E {
+        &self.eav
+    }
+
+    pub fn journal(&self) -> &Path {
+        &self.journal
+    }
+
+    /// applies the journal left by a commit that did not finish, if there is one, and removes it
+    fn recover(&mut self) -> PersistenceResult<()> {
+        if !self.journal.exists() {
+            return Ok(());
+        }
+        let writes: StagedWrites<A> = serde_json::from_reader(File::open(&self.journal)?)?;
+        self.apply(&writes)?;
+        fs::remove_file(&self.journal)?;
+        Ok(())
+    }
+
+    /// writes the journal next to its path and moves it in place, so it is never half written
+    fn write_journal(&self, writes: &StagedWrites<A>) -> PersistenceResult<()> {
+        let mut partial = OsString::from(self.journal.as_os_str());
+        partial.push(".partial");
+        let mut file = File::create(&partial)?;
+        serde_json::to_writer(&mut file, writes)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&partial, &self.journal)?;
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        writes: &StagedWrites<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        let total = writes.contents.len() + writes.eavis.len();
+        let mut applied = 0;
+        let partial = |applied: usize, e: PersistenceError| {
+            PersistenceError::Backend(
+                ErrorContext::new("journal", "commit")
+                    .with_message(format!(
+                        "transaction failed after {} of {} writes",
+                        applied, total
+                    ))
+                    .with_cause(e),
+            )
+        };
+
+        for content in writes.contents.iter() {
+            self.cas.add(content).map_err(|e| partial(applied, e))?;
+            applied += 1;
+        }
+
+        let mut stored = Vec::with_capacity(writes.eavis.len());
+        for eavi in writes.eavis.iter() {
+            let result = self.eav.add_eavi(eavi).map_err(|e| partial(applied, e))?;
+            stored.push(result.unwrap_or_else(|| eavi.clone()));
+            applied += 1;
+        }
+        Ok(stored)
+    }
+}
+
+impl<A, C, E> TransactionalStorage<A> for JournalStorage<A, C, E>
+where
+    A: Attribute + DeserializeOwned + Send + Sync,
+    C: ContentAddressableStorage,
+    E: EntityAttributeValueStorage<A>,
+{
+    /// the journal is left behind if applying the writes fails, @see recover
+    fn commit_writes(
+        &mut self,
+        writes: StagedWrites<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        self.recover()?;
+        self.write_journal(&writes)?;
+        let stored = self.apply(&writes)?;
+        fs::remove_file(&self.journal)?;
+        Ok(stored)
+    }
+}
+
+pub struct TransactionTestSuite;
+
+impl TransactionTestSuite {
+    /// commits and rolls back a transaction against the given storage
+    /// cas and eav must be views onto the same stores the storage writes to
+    pub fn test_commit_and_rollback<A, S, C, E>(mut storage: S, cas: C, eav: E)
+    where
+        A: Attribute + Default,
+        S: TransactionalStorage<A>,
+        C: ContentAddressableStorage,
+        E: EntityAttributeValueStorage<A>,
+    {
+        use crate::eav::EaviQuery;
+        use holochain_json_api::json::RawString;
+
+        let entry = Content::from(RawString::from("entry"));
+        let link_target = Content::from(RawString::from("link target"));
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &entry.address(),
+            &A::default(),
+            &link_target.address(),
+            1,
+        )
+        .unwrap();
+
+        // rolled back writes are never seen
+        {
+            let mut txn = storage.begin();
+            txn.add_content(&entry)
+                .add_content(&link_target)
+                .add_eavi(&eavi);
+            assert_eq!(2, txn.staged().contents.len());
+            txn.rollback();
+        }
+        assert_eq!(Ok(false), cas.contains(&entry.address()));
+        assert_eq!(0, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+
+        // dropping a transaction is a rollback
+        {
+            let mut txn = storage.begin();
+            txn.add_content(&entry);
+        }
+        assert_eq!(Ok(false), cas.contains(&entry.address()));
+
+        // committed writes are all seen
+        let mut txn = storage.begin();
+        txn.add_content(&entry)
+            .add_content(&link_target)
+            .add_eavi(&eavi);
+        let stored = txn.commit().unwrap();
+        assert_eq!(vec![eavi.clone()], stored);
+        assert_eq!(Ok(Some(entry.clone())), cas.fetch(&entry.address()));
+        assert_eq!(
+            Ok(Some(link_target.clone())),
+            cas.fetch(&link_target.address())
+        );
+        assert_eq!(
+            vec![eavi],
+            eav.fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+
+        // an empty commit is fine
+        assert_eq!(Ok(Vec::new()), storage.begin().commit());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::eav::EaviQuery;
+    use crate::{
+        cas::{content::OtherExampleAddressableContent, storage::ExampleContentAddressableStorage},
+        eav::{ExampleAttribute, ExampleEntityAttributeValueStorage},
+    };
+    use holochain_json_api::json::RawString;
+    use tempfile::{tempdir, TempDir};
+
+    type ExampleJournalStorage = JournalStorage<
+        ExampleAttribute,
+        ExampleContentAddressableStorage,
+        ExampleEntityAttributeValueStorage<ExampleAttribute>,
+    >;
+
+    fn journal_storage(dir: &TempDir) -> ExampleJournalStorage {
+        JournalStorage::new(
+            ExampleContentAddressableStorage::new().unwrap(),
+            ExampleEntityAttributeValueStorage::new(),
+            dir.path().join("journal"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn journal_commit_and_rollback() {
+        let dir = tempdir().unwrap();
+        let storage = journal_storage(&dir);
+        let (cas, eav) = (storage.cas().clone(), storage.eav().clone());
+        TransactionTestSuite::test_commit_and_rollback(storage, cas, eav);
+        assert!(!dir.path().join("journal").exists());
+    }
+
+    #[test]
+    fn journals_left_by_unfinished_commits_are_applied() {
+        let dir = tempdir().unwrap();
+        let content = Content::from(RawString::from("entry"));
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+            1,
+        )
+        .unwrap();
+        let mut writes = StagedWrites::default();
+        writes.contents.push(StagedContent::new(&content));
+        writes.eavis.push(eavi.clone());
+        // as a commit cut short would leave it
+        let unfinished = journal_storage(&dir);
+        unfinished.write_journal(&writes).unwrap();
+        assert!(unfinished.journal().exists());
+
+        let storage = JournalStorage::new(
+            unfinished.cas().clone(),
+            unfinished.eav().clone(),
+            unfinished.journal(),
+        )
+        .unwrap();
+        assert!(!storage.journal().exists());
+        assert_eq!(
+            Ok(Some(content.clone())),
+            storage.cas().fetch(&content.address())
+        );
+        assert_eq!(
+            vec![eavi],
+            storage
+                .eav()
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn staged_content_keeps_its_address() {
+        let dir = tempdir().unwrap();
+        let mut storage = journal_storage(&dir);
+        let content =
+            OtherExampleAddressableContent::try_from_content(&RawString::from("foo").into())
+                .unwrap();
+        let mut txn = storage.begin();
+        txn.add_content(&content);
+        txn.commit().unwrap();
+        assert_eq!(
+            Ok(Some(content.content())),
+            storage.cas().fetch(&content.address())
+        );
+    }
+}
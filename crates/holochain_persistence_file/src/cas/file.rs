@@ -3,25 +3,35 @@ use holochain_persistence_api::{
     cas::{
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
+        ttl::{expires_at, from_millis, is_expired, to_millis, SystemTimeSource, TimeSource},
     },
-    error::PersistenceResult,
+    error::{ErrorContext, InBackend, PersistenceError, PersistenceResult},
+    identity::{load_id_or_ephemeral, load_or_create_id},
     reporting::ReportStorage,
 };
 
 use std::{
-    fs::{create_dir_all, read_to_string, write},
+    collections::BTreeSet,
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, write},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
 use uuid::Uuid;
 
+/// holds the id of the store, next to the content
+const ID_FILE: &str = "id";
+const BACKEND: &str = "file";
+
 #[derive(Clone, Debug)]
 pub struct FilesystemStorage {
     /// path to the directory where content will be saved to disk
     dir_path: PathBuf,
     id: Uuid,
     lock: Arc<RwLock<()>>,
+    time_source: Arc<dyn TimeSource>,
+    read_only: bool,
 }
 
 impl PartialEq for FilesystemStorage {
@@ -31,14 +41,54 @@ impl PartialEq for FilesystemStorage {
 }
 
 impl FilesystemStorage {
+    /// creates the directory if needed, along with the id file that identifies the store
     pub fn new<P: AsRef<Path>>(dir_path: P) -> PersistenceResult<FilesystemStorage> {
-        let dir_path = dir_path.as_ref().into();
+        let dir_path: PathBuf = dir_path.as_ref().into();
+        create_dir_all(&dir_path).in_backend(BACKEND, "new")?;
+        let id = load_or_create_id(&dir_path.join(ID_FILE)).in_backend(BACKEND, "new")?;
+        Ok(FilesystemStorage::open(dir_path, id, false))
+    }
+
+    /// opens existing content without ever writing to the directory
+    /// adding content returns a ReadOnlyError
+    pub fn new_read_only<P: AsRef<Path>>(dir_path: P) -> PersistenceResult<FilesystemStorage> {
+        let dir_path: PathBuf = dir_path.as_ref().into();
+        if !dir_path.is_dir() {
+            return Err(PersistenceError::NotFound(
+                ErrorContext::new(BACKEND, "open")
+                    .with_message(format!("no CAS directory at {}", dir_path.display())),
+            ));
+        }
+        let id =
+            load_id_or_ephemeral(&dir_path.join(ID_FILE)).in_backend(BACKEND, "new_read_only")?;
+        Ok(FilesystemStorage::open(dir_path, id, true))
+    }
 
-        Ok(FilesystemStorage {
+    fn open(dir_path: PathBuf, id: Uuid, read_only: bool) -> FilesystemStorage {
+        FilesystemStorage {
             dir_path,
-            id: Uuid::new_v4(),
+            id,
             lock: Arc::new(RwLock::new(())),
-        })
+            time_source: Arc::new(SystemTimeSource),
+            read_only,
+        }
+    }
+
+    /// replaces the time source used to expire content added with a ttl
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> FilesystemStorage {
+        self.time_source = time_source;
+        self
+    }
+
+    fn check_writable(&self) -> PersistenceResult<()> {
+        if self.read_only {
+            Err(PersistenceError::ReadOnlyError(
+                ErrorContext::new(BACKEND, "write")
+                    .with_message(format!("CAS at {} is read-only", self.dir_path.display())),
+            ))
+        } else {
+            Ok(())
+        }
     }
 
     /// builds an absolute path for an AddressableContent address
@@ -49,39 +99,141 @@ impl FilesystemStorage {
             .join(address.to_string())
             .with_extension("txt")
     }
+
+    /// builds an absolute path for the file holding the expiry time of an address
+    /// only content added with a ttl has one
+    fn address_to_ttl_path(&self, address: &Address) -> PathBuf {
+        self.dir_path
+            .join(address.to_string())
+            .with_extension("ttl")
+    }
 }
 
 impl ContentAddressableStorage for FilesystemStorage {
     fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
-        let _guard = self.lock.write()?;
+        self.check_writable()?;
+        let _guard = self.lock.write().in_backend(BACKEND, "add")?;
         // @TODO be more efficient here
         // @see https://github.com/holochain/holochain-rust/issues/248
-        create_dir_all(&self.dir_path)?;
+        create_dir_all(&self.dir_path).in_backend(BACKEND, "add")?;
 
         write(
             self.address_to_path(&content.address()),
             content.content().to_string(),
-        )?;
+        )
+        .in_backend(BACKEND, "add")?;
+
+        let ttl_path = self.address_to_ttl_path(&content.address());
+        if ttl_path.is_file() {
+            remove_file(ttl_path).in_backend(BACKEND, "add")?;
+        }
 
         Ok(())
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
-        let _guard = self.lock.read()?;
+        let _guard = self.lock.read().in_backend(BACKEND, "contains")?;
         Ok(Path::new(&self.address_to_path(address)).is_file())
     }
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
-        let _guard = self.lock.read()?;
+        let _guard = self.lock.read().in_backend(BACKEND, "fetch")?;
         if self.contains(&address)? {
-            Ok(Some(JsonString::from_json(&read_to_string(
-                self.address_to_path(address),
-            )?)))
+            let content =
+                read_to_string(self.address_to_path(address)).in_backend(BACKEND, "fetch")?;
+            Ok(Some(JsonString::from_json(&content)))
         } else {
             Ok(None)
         }
     }
 
+    fn add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> PersistenceResult<()> {
+        self.check_writable()?;
+        let _guard = self.lock.write().in_backend(BACKEND, "add_with_ttl")?;
+        create_dir_all(&self.dir_path).in_backend(BACKEND, "add_with_ttl")?;
+
+        let address = content.address();
+        let ttl_path = self.address_to_ttl_path(&address);
+        if self.address_to_path(&address).is_file() && !ttl_path.is_file() {
+            // already stored without a ttl so it must not expire
+            return Ok(());
+        }
+
+        write(
+            self.address_to_path(&address),
+            content.content().to_string(),
+        )
+        .in_backend(BACKEND, "add_with_ttl")?;
+        write(
+            ttl_path,
+            to_millis(expires_at(&*self.time_source, ttl)).to_string(),
+        )
+        .in_backend(BACKEND, "add_with_ttl")?;
+
+        Ok(())
+    }
+
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        self.check_writable()?;
+        let _guard = self.lock.write().in_backend(BACKEND, "sweep_expired")?;
+        if !self.dir_path.is_dir() {
+            return Ok(0);
+        }
+
+        let mut swept = 0;
+        for entry in read_dir(&self.dir_path).in_backend(BACKEND, "sweep_expired")? {
+            let ttl_path = entry.in_backend(BACKEND, "sweep_expired")?.path();
+            if ttl_path.extension().map(|ext| ext != "ttl").unwrap_or(true) {
+                continue;
+            }
+            let expiry = read_to_string(&ttl_path)
+                .in_backend(BACKEND, "sweep_expired")?
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| {
+                    PersistenceError::Corruption(
+                        ErrorContext::new(BACKEND, "sweep").with_message(format!(
+                            "invalid expiry in {}: {}",
+                            ttl_path.display(),
+                            e
+                        )),
+                    )
+                })?;
+            if is_expired(&*self.time_source, from_millis(expiry)) {
+                let content_path = ttl_path.with_extension("txt");
+                if content_path.is_file() {
+                    remove_file(content_path).in_backend(BACKEND, "sweep_expired")?;
+                }
+                remove_file(ttl_path).in_backend(BACKEND, "sweep_expired")?;
+                swept += 1;
+            }
+        }
+
+        Ok(swept)
+    }
+
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        let _guard = self.lock.read().in_backend(BACKEND, "addresses")?;
+        if !self.dir_path.is_dir() {
+            return Ok(BTreeSet::new());
+        }
+
+        let mut addresses = BTreeSet::new();
+        for entry in read_dir(&self.dir_path).in_backend(BACKEND, "addresses")? {
+            let path = entry.in_backend(BACKEND, "addresses")?.path();
+            if path.extension().map(|ext| ext == "txt").unwrap_or_default() {
+                if let Some(stem) = path.file_stem() {
+                    addresses.insert(Address::from(stem.to_string_lossy().into_owned()));
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
     fn get_id(&self) -> Uuid {
         self.id
     }
@@ -91,12 +243,21 @@ impl ReportStorage for FilesystemStorage {}
 
 #[cfg(test)]
 pub mod tests {
-    use crate::cas::file::FilesystemStorage;
+    use crate::{cas::file::FilesystemStorage, eav::file::EavFileStorage};
     use holochain_json_api::json::RawString;
-    use holochain_persistence_api::cas::{
-        content::{ExampleAddressableContent, OtherExampleAddressableContent},
-        storage::StorageTestSuite,
+    use holochain_persistence_api::{
+        cas::{
+            content::{
+                AddressableContent, Content, ExampleAddressableContent,
+                OtherExampleAddressableContent,
+            },
+            storage::{ContentAddressableStorage, StorageTestSuite},
+            ttl::MockTimeSource,
+        },
+        eav::ExampleAttribute,
+        txn::{JournalStorage, TransactionTestSuite},
     };
+    use std::sync::Arc;
     use tempfile::{tempdir, TempDir};
 
     pub fn test_file_cas() -> (FilesystemStorage, TempDir) {
@@ -115,4 +276,79 @@ pub mod tests {
             RawString::from("bar").into(),
         );
     }
+
+    #[test]
+    fn file_addresses_test() {
+        let (cas, _dir) = test_file_cas();
+        StorageTestSuite::new(cas)
+            .addresses_test(RawString::from("foo").into(), RawString::from("bar").into());
+    }
+
+    #[test]
+    fn file_ttl_test() {
+        let (cas, _dir) = test_file_cas();
+        let time_source = MockTimeSource::default();
+        let cas = cas.with_time_source(Arc::new(time_source.clone()));
+        StorageTestSuite::new(cas).ttl_test(
+            time_source,
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
+    #[test]
+    fn file_transaction() {
+        let (cas, _cas_dir) = test_file_cas();
+        let eav_dir = tempdir().expect("Could not create a tempdir for EAV testing");
+        let eav = EavFileStorage::<ExampleAttribute>::new(eav_dir.path()).unwrap();
+        let storage = JournalStorage::new(cas, eav, eav_dir.path().join("journal")).unwrap();
+        let (cas, eav) = (storage.cas().clone(), storage.eav().clone());
+        TransactionTestSuite::test_commit_and_rollback(storage, cas, eav);
+    }
+
+    #[test]
+    fn file_read_only_test() {
+        let (cas, dir) = test_file_cas();
+        StorageTestSuite::new(cas).read_only_test(
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+            || FilesystemStorage::new_read_only(dir.path()).unwrap(),
+        );
+        assert!(FilesystemStorage::new_read_only(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn file_id_test() {
+        let (cas, dir) = test_file_cas();
+        let (other, _other_dir) = test_file_cas();
+        assert_ne!(cas.get_id(), other.get_id());
+        StorageTestSuite::new(cas).id_test(FilesystemStorage::new(dir.path()).unwrap());
+        assert_eq!(
+            FilesystemStorage::new(dir.path()).unwrap().get_id(),
+            FilesystemStorage::new_read_only(dir.path())
+                .unwrap()
+                .get_id()
+        );
+    }
+
+    #[test]
+    fn io_errors_name_the_backend() {
+        let (mut cas, dir) = test_file_cas();
+        let content = Content::from(RawString::from("foo"));
+        // a directory where the content file should be cannot be written as one
+        std::fs::create_dir_all(
+            dir.path()
+                .join(content.address().to_string())
+                .with_extension("txt"),
+        )
+        .unwrap();
+        match cas.add(&content) {
+            Err(e) => {
+                let context = e.context().expect("io errors have a context");
+                assert_eq!(("file", "add"), (&*context.backend, &*context.operation));
+                assert!(std::error::Error::source(&e).is_some());
+            }
+            result => panic!("expected an error, got {:?}", result),
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use glob::glob;
+use glob::{glob, Pattern};
 use holochain_json_api::{
     error::{JsonError, JsonResult},
     json::JsonString,
@@ -6,10 +6,11 @@ use holochain_json_api::{
 use holochain_persistence_api::{
     cas::content::AddressableContent,
     eav::{
-        Attribute, EavFilter, EaviQuery, Entity, EntityAttributeValueIndex,
-        EntityAttributeValueStorage, Value,
+        Attribute, Clock, EavFilter, EaviIter, EaviPage, EaviQuery, Entity,
+        EntityAttributeValueIndex, EntityAttributeValueStorage, LiveRegistry, Value, WallClock,
     },
-    error::{PersistenceError, PersistenceResult},
+    error::{ErrorContext, InBackend, PersistenceError, PersistenceResult},
+    identity::{load_id_or_ephemeral, load_or_create_id},
     reporting::ReportStorage,
 };
 use std::{
@@ -26,6 +27,11 @@ use uuid::Uuid;
 const ENTITY_DIR: &str = "e";
 const ATTRIBUTE_DIR: &str = "a";
 const VALUE_DIR: &str = "v";
+/// holds a copy of every retracted eavi, named by its address
+const RETRACTED_DIR: &str = "r";
+/// holds the id of the store, next to the entity, attribute and value directories
+const ID_FILE: &str = "id";
+const BACKEND: &str = "file";
 
 #[derive(Clone, Debug)]
 pub struct EavFileStorage<A: Attribute> {
@@ -33,6 +39,9 @@ pub struct EavFileStorage<A: Attribute> {
     id: Uuid,
     lock: Arc<RwLock<()>>,
     attribute: PhantomData<A>,
+    read_only: bool,
+    live: LiveRegistry<A>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<A: Attribute> PartialEq for EavFileStorage<A> {
@@ -41,6 +50,15 @@ impl<A: Attribute> PartialEq for EavFileStorage<A> {
     }
 }
 
+/// the glob for the directories named by the values a filter could match
+/// a prefix filter only lists the directories that start with its prefix
+fn dir_pattern<T: Eq>(filter: &EavFilter<T>) -> String {
+    match filter.key_prefix() {
+        Some(prefix) => format!("{}*", Pattern::escape(prefix)),
+        None => "*".to_string(),
+    }
+}
+
 #[warn(unused_must_use)]
 pub fn read_eav(parent_path: PathBuf) -> JsonResult<Vec<String>> {
     //glob all  files
@@ -71,15 +89,59 @@ impl<A: Attribute> EavFileStorage<A>
 where
     A: std::string::ToString + serde::de::DeserializeOwned,
 {
+    /// creates the directory if needed, along with the id file that identifies the store
     pub fn new<P: AsRef<Path>>(dir_path: P) -> JsonResult<EavFileStorage<A>> {
-        let dir_path = dir_path.as_ref().into();
+        let dir_path: PathBuf = dir_path.as_ref().into();
+        create_dir_all(&dir_path)?;
+        let id = load_or_create_id(&dir_path.join(ID_FILE))
+            .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
+        Ok(EavFileStorage::open(dir_path, id, false))
+    }
 
-        Ok(EavFileStorage {
+    /// opens existing metadata without ever writing to the directory
+    /// adding eavis returns a ReadOnlyError
+    pub fn new_read_only<P: AsRef<Path>>(dir_path: P) -> PersistenceResult<EavFileStorage<A>> {
+        let dir_path: PathBuf = dir_path.as_ref().into();
+        if !dir_path.is_dir() {
+            return Err(PersistenceError::NotFound(
+                ErrorContext::new(BACKEND, "open")
+                    .with_message(format!("no EAV directory at {}", dir_path.display())),
+            ));
+        }
+        let id =
+            load_id_or_ephemeral(&dir_path.join(ID_FILE)).in_backend(BACKEND, "new_read_only")?;
+        Ok(EavFileStorage::open(dir_path, id, true))
+    }
+
+    fn open(dir_path: PathBuf, id: Uuid, read_only: bool) -> EavFileStorage<A> {
+        EavFileStorage {
             dir_path,
-            id: Uuid::new_v4(),
+            id,
             lock: Arc::new(RwLock::new(())),
             attribute: PhantomData,
-        })
+            read_only,
+            live: LiveRegistry::new(),
+            clock: Arc::new(WallClock),
+        }
+    }
+
+    /// replaces the clock that stamps new eavis, the wall clock by default
+    /// a logical clock should start after the indexes already stored, @see
+    /// LamportClock::starting_after
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn check_writable(&self, operation: &str) -> PersistenceResult<()> {
+        if self.read_only {
+            Err(PersistenceError::ReadOnlyError(
+                ErrorContext::new(BACKEND, operation)
+                    .with_message(format!("EAV at {} is read-only", self.dir_path.display())),
+            ))
+        } else {
+            Ok(())
+        }
     }
 
     fn write_to_file(
@@ -122,7 +184,7 @@ where
         let path = self.dir_path.join(&subscript);
 
         if path.exists() {
-            let full_path = path.join("*");
+            let full_path = path.join(dir_pattern(eav_filter));
 
             let paths = glob(full_path.to_str().unwrap())
                 .map_err(|_| JsonError::ErrorGeneric("Could not get form path".to_string()))?;
@@ -174,36 +236,45 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
-        let _guard = self.lock.write()?;
-        let wild_card = Path::new("*");
-        //create glob path to query file system parentdir/*/*/*/{address}.txt
-        let text_file_path = Path::new(&eav.address().to_string()).with_extension("txt");
-        let path: PathBuf = self
+        self.check_writable("add_eavi")?;
+        let _guard = self.lock.write().in_backend(BACKEND, "add_eavi")?;
+        // eavis are kept in files named by their address, so they only collide with
+        // themselves, @see resolve_collision
+        let file = self
             .dir_path
-            .join(wild_card)
             .join(ENTITY_DIR)
-            .join(&*eav.index().to_string())
-            .join(&text_file_path);
+            .join(eav.entity().to_string())
+            .join(eav.index().to_string())
+            .join(eav.address().to_string())
+            .with_extension("txt");
+        if file.exists() {
+            self.clock.observe(eav.index());
+            return Ok(Some(eav.clone()));
+        }
 
-        //if next exists create a new eav with a different index
-        let eav = if path.exists() {
-            EntityAttributeValueIndex::new(&eav.entity(), &eav.attribute(), &eav.value())?
-        } else {
-            eav.clone()
-        };
+        self.write_to_file(ENTITY_DIR.to_string(), eav)
+            .and_then(|_| self.write_to_file(ATTRIBUTE_DIR.to_string(), eav))
+            .and_then(|_| self.write_to_file(VALUE_DIR.to_string(), eav))?;
+        self.clock.observe(eav.index());
+        drop(_guard);
+        self.live.update(self, eav);
+        Ok(Some(eav.clone()))
+    }
 
-        self.write_to_file(ENTITY_DIR.to_string(), &eav)
-            .and_then(|_| self.write_to_file(ATTRIBUTE_DIR.to_string(), &eav))
-            .and_then(|_| self.write_to_file(VALUE_DIR.to_string(), &eav))
-            .map(|_| Some(eav.clone()))
-            .map_err(|err| err.into())
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
-        let _guard = self.lock.read()?;
+        let page = self.fetch_eavi_page(query)?;
+        Ok(page.rows.into_iter().collect())
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        let _guard = self.lock.read().in_backend(BACKEND, "fetch_eavi_page")?;
 
         let entity_set = self.read_from_dir::<Entity>(ENTITY_DIR.to_string(), query.entity())?;
         let attribute_set =
@@ -226,18 +297,135 @@ where
             .partition(Result::is_ok);
         if !errors.is_empty() {
             // not all EAVs were converted
-            Err(PersistenceError::ErrorGeneric(
-                "Error Converting EAVs".to_string(),
+            Err(PersistenceError::Corruption(
+                ErrorContext::new(BACKEND, "fetch_eavi_page").with_message("Error Converting EAVs"),
             ))
         } else {
             let it = eavis.iter().map(|e| {
                 e.clone()
                     .expect("no problem here since we have filtered out all bad conversions")
             });
-            let results = query.run(it);
-            Ok(results)
+            let retracted = self.read_retracted()?;
+            Ok(query.run_page_with_retractions(it, |eavi| retracted.contains(eavi)))
         }
     }
+
+    fn fetch_eavi_iter<'s>(
+        &'s self,
+        query: &'s EaviQuery<A>,
+    ) -> PersistenceResult<EaviIter<'s, A>> {
+        if !query.can_stream() {
+            let page = self.fetch_eavi_page(query)?;
+            return Ok(Box::new(page.rows.into_iter().map(Ok)));
+        }
+        let retracted = {
+            let _guard = self.lock.read().in_backend(BACKEND, "fetch_eavi_iter")?;
+            self.read_retracted()?
+        };
+        // every eavi is in the entity directory once, at e/<entity>/<index>/<address>.txt
+        let pattern = self
+            .dir_path
+            .join(ENTITY_DIR)
+            .join(dir_pattern(query.entity()))
+            .join("*")
+            .join("*.txt");
+        let paths = glob(pattern.to_str().unwrap()).map_err(|e| {
+            PersistenceError::Backend(
+                ErrorContext::new(BACKEND, "fetch_eavi_iter").with_message(e.to_string()),
+            )
+        })?;
+        Ok(Box::new(paths.filter_map(move |path| {
+            let path = match path {
+                Ok(path) => path,
+                Err(e) => return Some(Err(e.into_error().into())),
+            };
+            // skip entities the query rules out without reading their files
+            let entity = path.parent()?.parent()?.file_name()?.to_string_lossy();
+            if !query.entity().check(entity.to_string().into()) {
+                return None;
+            }
+            let content = match self.lock.read() {
+                Ok(_guard) => fs::read_to_string(&path),
+                Err(e) => return Some(Err(e.into())),
+            };
+            let eavi = match content.map(|content| {
+                EntityAttributeValueIndex::try_from_content(&JsonString::from_json(&content))
+            }) {
+                Ok(Ok(eavi)) => eavi,
+                Ok(Err(e)) => {
+                    return Some(Err(PersistenceError::Corruption(
+                        ErrorContext::new(BACKEND, "fetch_eavi_iter").with_message(format!(
+                            "{}: {}",
+                            path.display(),
+                            e
+                        )),
+                    )))
+                }
+                Err(e) => return Some(Err(e.into())),
+            };
+            if query.retraction().keeps(retracted.contains(&eavi)) && query.selects(&eavi) {
+                Some(Ok(eavi))
+            } else {
+                None
+            }
+        })))
+    }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        self.check_writable("retract_eavi")?;
+        let _guard = self.lock.write().in_backend(BACKEND, "retract_eavi")?;
+        let file_name = Path::new(&eav.address().to_string()).with_extension("txt");
+        let stored = self
+            .dir_path
+            .join(ENTITY_DIR)
+            .join(eav.entity().to_string())
+            .join(eav.index().to_string())
+            .join(&file_name);
+        let retracted = self.dir_path.join(RETRACTED_DIR).join(&file_name);
+        if !stored.is_file() || retracted.is_file() {
+            return Ok(false);
+        }
+        create_dir_all(self.dir_path.join(RETRACTED_DIR)).in_backend(BACKEND, "retract_eavi")?;
+        let mut file = File::create(retracted).in_backend(BACKEND, "retract_eavi")?;
+        writeln!(file, "{}", eav.content()).in_backend(BACKEND, "retract_eavi")?;
+        drop(_guard);
+        self.live.update(self, eav);
+        Ok(true)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
+    }
+}
+
+impl<A: Attribute> EavFileStorage<A>
+where
+    A: TryFrom<JsonString> + serde::de::DeserializeOwned,
+{
+    fn read_retracted(&self) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
+        let dir = self.dir_path.join(RETRACTED_DIR);
+        if !dir.is_dir() {
+            return Ok(BTreeSet::new());
+        }
+        let mut retracted = BTreeSet::new();
+        for entry in fs::read_dir(dir).in_backend(BACKEND, "read_retracted")? {
+            let path = entry.in_backend(BACKEND, "read_retracted")?.path();
+            let content = fs::read_to_string(path).in_backend(BACKEND, "read_retracted")?;
+            retracted.insert(
+                EntityAttributeValueIndex::try_from_content(&JsonString::from_json(&content))
+                    .map_err(|e| {
+                        PersistenceError::Corruption(
+                            ErrorContext::new(BACKEND, "fetch_eavi").with_cause(e),
+                        )
+                    })?,
+            );
+        }
+        Ok(retracted)
+    }
 }
 
 impl<A: Attribute> ReportStorage for EavFileStorage<A> {}
@@ -251,8 +439,9 @@ pub mod tests {
             content::{AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::ExampleAttribute,
+        eav::{ExampleAttribute, LamportClock},
     };
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     #[test]
@@ -334,4 +523,120 @@ pub mod tests {
         let eav_storage = EavFileStorage::new(temp_path).unwrap();
         EavTestSuite::test_tombstone::<ExampleAddressableContent, EavFileStorage<_>>(eav_storage)
     }
+
+    #[test]
+    fn file_eav_read_only() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_read_only(EavFileStorage::new(temp.path()).unwrap(), || {
+            EavFileStorage::new_read_only(temp.path()).unwrap()
+        });
+    }
+
+    #[test]
+    fn file_eav_id_is_persisted() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let other = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage: EavFileStorage<ExampleAttribute> =
+            EavFileStorage::new(temp.path()).unwrap();
+        assert!(eav_storage == EavFileStorage::new(temp.path()).unwrap());
+        assert!(eav_storage == EavFileStorage::new_read_only(temp.path()).unwrap());
+        assert!(eav_storage != EavFileStorage::new(other.path()).unwrap());
+        EavTestSuite::test_id(
+            eav_storage,
+            EavFileStorage::new(temp.path()).unwrap(),
+            EavFileStorage::new(other.path()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn file_eav_retraction() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_retraction(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_pagination() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_pagination(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_fetch_iter() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_fetch_iter(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_counts() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_counts(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_key_filters() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_key_filters(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_traversal() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_traversal(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_join() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_join(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_range_tombstone() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_range_tombstone(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_source() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_source(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_clock() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_clock(
+            EavFileStorage::new(temp.path())
+                .unwrap()
+                .with_clock(Arc::new(LamportClock::new())),
+        );
+    }
+
+    #[test]
+    fn file_eav_collisions() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_collisions(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_merge() {
+        let a = tempdir().expect("test was supposed to create temp dir");
+        let b = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_merge(
+            EavFileStorage::new(a.path()).unwrap(),
+            EavFileStorage::new(b.path()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn file_eav_live() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_live(EavFileStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn file_eav_as_of() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_as_of(EavFileStorage::new(temp.path()).unwrap());
+    }
 }
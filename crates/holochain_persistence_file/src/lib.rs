@@ -8,3 +8,5 @@
 
 pub mod cas;
 pub mod eav;
+#[cfg(feature = "registry")]
+pub mod registry;
@@ -0,0 +1,113 @@
+//! Registers the file system stores under the `file://` scheme.
+//! The URI path is the directory the store keeps its files in, e.g. `file:///data/cas`.
+//! Setting `read_only=true` opens an existing store with `new_read_only`.
+
+use crate::{cas::file::FilesystemStorage, eav::file::EavFileStorage};
+use holochain_json_api::json::JsonString;
+use holochain_persistence_api::{
+    cas::storage::ContentAddressableStorage,
+    eav::{Attribute, EntityAttributeValueStorage},
+    registry::{Registry, StoreConfig},
+};
+use std::convert::TryFrom;
+
+pub const SCHEME: &str = "file";
+
+pub fn register<A>(registry: &mut Registry<A>)
+where
+    A: Attribute
+        + ToString
+        + TryFrom<String>
+        + Send
+        + Sync
+        + serde::de::DeserializeOwned
+        + TryFrom<JsonString>
+        + Into<JsonString>
+        + 'static,
+{
+    registry
+        .register_cas(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let cas = if config.read_only()? {
+                FilesystemStorage::new_read_only(path)?
+            } else {
+                FilesystemStorage::new(path)?
+            };
+            Ok(Box::new(cas) as Box<dyn ContentAddressableStorage>)
+        })
+        .register_eav(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let eav = if config.read_only()? {
+                EavFileStorage::new_read_only(path)?
+            } else {
+                EavFileStorage::new(path)?
+            };
+            Ok(Box::new(eav) as Box<dyn EntityAttributeValueStorage<A>>)
+        });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, Content},
+        eav::{EaviQuery, EntityAttributeValueIndex, ExampleAttribute},
+        error::PersistenceError,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn file_stores_open_from_uris() {
+        let dir = tempdir().unwrap();
+        let mut registry = Registry::<ExampleAttribute>::new();
+        register(&mut registry);
+        let cas_config = StoreConfig::new(SCHEME).with_path(dir.path().join("cas"));
+        let eav_config = StoreConfig::new(SCHEME).with_path(dir.path().join("eav"));
+
+        let content = Content::from(RawString::from("foo"));
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        let mut cas = registry.open_cas(&cas_config).unwrap();
+        cas.add(&content).unwrap();
+        let mut eav = registry.open_eav(&eav_config).unwrap();
+        eav.add_eavi(&eavi).unwrap();
+
+        // reopening through the registry gives the same store
+        let reopened = registry.open_cas_uri(&cas_config.to_string()).unwrap();
+        assert_eq!(cas.get_id(), reopened.get_id());
+        assert_eq!(
+            Ok(Some(content.clone())),
+            reopened.fetch(&content.address())
+        );
+
+        let mut read_only = registry
+            .open_cas(&cas_config.clone().with_param("read_only", true))
+            .unwrap();
+        assert_eq!(
+            Ok(Some(content.clone())),
+            read_only.fetch(&content.address())
+        );
+        match read_only.add(&content) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+        let read_only_eav = registry
+            .open_eav(&eav_config.with_param("read_only", true))
+            .unwrap();
+        assert_eq!(
+            1,
+            read_only_eav
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .len()
+        );
+
+        // file stores need somewhere to live
+        assert!(registry.open_cas_uri("file://").is_err());
+    }
+}
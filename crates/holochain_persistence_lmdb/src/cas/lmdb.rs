@@ -1,20 +1,27 @@
-use crate::common::LmdbInstance;
+use crate::{
+    common::{context, store_error, write_txn, LmdbInstance},
+    env::LmdbEnvironment,
+};
 use holochain_json_api::json::JsonString;
 use holochain_persistence_api::{
     cas::{
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
+        ttl::{expires_at, from_millis, is_expired, to_millis, SystemTimeSource, TimeSource},
     },
-    error::{PersistenceError, PersistenceResult},
+    error::PersistenceResult,
     reporting::{ReportStorage, StorageReport},
 };
 use rkv::{
     error::{DataError, StoreError},
-    Value,
+    Rkv, Value, Writer,
 };
 use std::{
+    collections::BTreeSet,
     fmt::{Debug, Error, Formatter},
     path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 use uuid::Uuid;
 
@@ -24,6 +31,16 @@ const CAS_BUCKET: &str = "cas";
 pub struct LmdbStorage {
     id: Uuid,
     lmdb: LmdbInstance,
+    /// expiry times (in ms since the unix epoch) of content added with a ttl, keyed by address
+    /// only missing when opened read-only, in which case it is never needed
+    ttl_lmdb: Option<LmdbInstance>,
+    time_source: Arc<dyn TimeSource>,
+}
+
+impl PartialEq for LmdbStorage {
+    fn eq(&self, other: &LmdbStorage) -> bool {
+        self.id == other.id
+    }
 }
 
 impl Debug for LmdbStorage {
@@ -36,20 +53,164 @@ impl LmdbStorage {
     pub fn new<P: AsRef<Path> + Clone>(
         db_path: P,
         initial_map_bytes: Option<usize>,
+    ) -> LmdbStorage {
+        let env = LmdbEnvironment::new(
+            db_path.as_ref().join(CAS_BUCKET).with_extension("db"),
+            initial_map_bytes,
+            None,
+        );
+        env.cas(CAS_BUCKET).expect("Could not create store")
+    }
+
+    /// opens a CAS created with `new` at the same path without ever writing to it
+    pub fn new_read_only<P: AsRef<Path>>(db_path: P) -> PersistenceResult<LmdbStorage> {
+        LmdbEnvironment::new_read_only(
+            db_path.as_ref().join(CAS_BUCKET).with_extension("db"),
+            None,
+        )?
+        .cas(CAS_BUCKET)
+    }
+
+    pub(crate) fn from_instances(
+        lmdb: LmdbInstance,
+        ttl_lmdb: Option<LmdbInstance>,
+        id: Uuid,
     ) -> LmdbStorage {
         LmdbStorage {
-            id: Uuid::new_v4(),
-            lmdb: LmdbInstance::new(CAS_BUCKET, db_path, initial_map_bytes),
+            id,
+            lmdb,
+            ttl_lmdb,
+            time_source: Arc::new(SystemTimeSource),
         }
     }
+
+    /// replaces the time source used to expire content added with a ttl
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> LmdbStorage {
+        self.time_source = time_source;
+        self
+    }
 }
 
 impl LmdbStorage {
     fn lmdb_add(&mut self, content: &dyn AddressableContent) -> Result<(), StoreError> {
-        self.lmdb.add(
+        write_txn(&self.lmdb.manager, |writer| {
+            self.put_content(writer, content)
+        })
+    }
+
+    /// adds the content as part of a larger write transaction in this store's environment
+    pub(crate) fn put_content(
+        &self,
+        writer: &mut Writer,
+        content: &dyn AddressableContent,
+    ) -> Result<(), StoreError> {
+        self.lmdb.store.put(
+            writer,
             content.address(),
             &Value::Json(&content.content().to_string()),
-        )
+        )?;
+        self.ttl_lmdb().delete_in(writer, content.address())
+    }
+
+    pub(crate) fn check_writable(&self) -> PersistenceResult<()> {
+        self.lmdb.check_writable("CAS")
+    }
+
+    /// the store holding expiry times, which is always open unless read-only
+    fn ttl_lmdb(&self) -> &LmdbInstance {
+        self.ttl_lmdb
+            .as_ref()
+            .expect("only read-only stores have no ttl store and they are never written")
+    }
+
+    pub(crate) fn manager(&self) -> &Arc<RwLock<Rkv>> {
+        &self.lmdb.manager
+    }
+
+    fn lmdb_add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> Result<(), StoreError> {
+        let address = content.address();
+        let expiry = to_millis(expires_at(&*self.time_source, ttl));
+        write_txn(&self.lmdb.manager, |writer| {
+            // looked up in the write transaction so that a concurrent add can't slip in between
+            let stored = self.lmdb.store.get(writer, address.clone())?.is_some();
+            let expires = self
+                .ttl_lmdb()
+                .store
+                .get(writer, address.clone())?
+                .is_some();
+            if stored && !expires {
+                // already stored without a ttl so it must not expire
+                return Ok(());
+            }
+            self.lmdb.store.put(
+                writer,
+                address.clone(),
+                &Value::Json(&content.content().to_string()),
+            )?;
+            self.ttl_lmdb()
+                .store
+                .put(writer, address.clone(), &Value::U64(expiry))
+        })
+    }
+
+    fn lmdb_sweep_expired(&mut self) -> Result<usize, StoreError> {
+        let expired = self.lmdb_expired_keys()?;
+        self.lmdb_delete_expired(&expired)
+    }
+
+    /// the keys of the content that has expired, read outside the write transaction
+    fn lmdb_expired_keys(&self) -> Result<Vec<Vec<u8>>, StoreError> {
+        let env = self.lmdb.manager.read().unwrap();
+        let reader = env.read()?;
+        let mut expired = Vec::new();
+        for result in self.ttl_lmdb().store.iter_start(&reader)? {
+            if let (key, Some(Value::U64(expiry))) = result? {
+                if is_expired(&*self.time_source, from_millis(expiry)) {
+                    expired.push(key.to_vec());
+                }
+            }
+        }
+        Ok(expired)
+    }
+
+    /// deletes the content at the keys that is still expired, returning how much was
+    fn lmdb_delete_expired(&mut self, expired: &[Vec<u8>]) -> Result<usize, StoreError> {
+        write_txn(&self.lmdb.manager, |writer| {
+            let mut swept = 0;
+            for key in expired.iter() {
+                // content added again since it was read may no longer expire
+                let still_expired = match self.ttl_lmdb().store.get(writer, key)? {
+                    Some(Value::U64(expiry)) => is_expired(&*self.time_source, from_millis(expiry)),
+                    _ => false,
+                };
+                if still_expired {
+                    self.lmdb.delete_in(writer, key)?;
+                    self.ttl_lmdb().delete_in(writer, key)?;
+                    swept += 1;
+                }
+            }
+            Ok(swept)
+        })
+    }
+
+    fn lmdb_addresses(&self) -> Result<BTreeSet<Address>, StoreError> {
+        let env = self.lmdb.manager.read().unwrap();
+        let reader = env.read()?;
+
+        let addresses = self
+            .lmdb
+            .store
+            .iter_start(&reader)?
+            .map(|result| {
+                let (key, _) = result?;
+                Ok(Address::from(String::from_utf8_lossy(key).into_owned()))
+            })
+            .collect();
+        addresses
     }
 
     fn lmdb_fetch(&self, address: &Address) -> Result<Option<Content>, StoreError> {
@@ -69,8 +230,9 @@ impl LmdbStorage {
 
 impl ContentAddressableStorage for LmdbStorage {
     fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
+        self.check_writable()?;
         self.lmdb_add(content)
-            .map_err(|e| PersistenceError::from(format!("CAS add error: {}", e)))
+            .map_err(|e| store_error(context("add").with_address(&content.address()), e))
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
@@ -82,7 +244,28 @@ impl ContentAddressableStorage for LmdbStorage {
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
         self.lmdb_fetch(address)
-            .map_err(|e| PersistenceError::from(format!("CAS fetch error: {}", e)))
+            .map_err(|e| store_error(context("fetch").with_address(address), e))
+    }
+
+    fn add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> PersistenceResult<()> {
+        self.check_writable()?;
+        self.lmdb_add_with_ttl(content, ttl)
+            .map_err(|e| store_error(context("add_with_ttl").with_address(&content.address()), e))
+    }
+
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        self.check_writable()?;
+        self.lmdb_sweep_expired()
+            .map_err(|e| store_error(context("sweep"), e))
+    }
+
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        self.lmdb_addresses()
+            .map_err(|e| store_error(context("addresses"), e))
     }
 
     fn get_id(&self) -> Uuid {
@@ -102,11 +285,16 @@ mod tests {
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
-            content::{Content, ExampleAddressableContent, OtherExampleAddressableContent},
+            content::{
+                AddressableContent, Content, ExampleAddressableContent,
+                OtherExampleAddressableContent,
+            },
             storage::{CasBencher, ContentAddressableStorage, StorageTestSuite},
+            ttl::MockTimeSource,
         },
         reporting::{ReportStorage, StorageReport},
     };
+    use std::{sync::Arc, time::Duration};
     use tempfile::{tempdir, TempDir};
 
     pub fn test_lmdb_cas() -> (LmdbStorage, TempDir) {
@@ -138,6 +326,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lmdb_addresses_test() {
+        let (cas, _dir) = test_lmdb_cas();
+        StorageTestSuite::new(cas)
+            .addresses_test(RawString::from("foo").into(), RawString::from("bar").into());
+    }
+
+    #[test]
+    fn lmdb_ttl_test() {
+        let (cas, _dir) = test_lmdb_cas();
+        let time_source = MockTimeSource::default();
+        let cas = cas.with_time_source(Arc::new(time_source.clone()));
+        StorageTestSuite::new(cas).ttl_test(
+            time_source,
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
+    #[test]
+    fn lmdb_sweep_keeps_content_added_without_a_ttl_since_it_expired() {
+        let (cas, _dir) = test_lmdb_cas();
+        let time_source = MockTimeSource::default();
+        let mut cas = cas.with_time_source(Arc::new(time_source.clone()));
+        let content =
+            ExampleAddressableContent::try_from_content(&RawString::from("foo").into()).unwrap();
+        cas.add_with_ttl(&content, Duration::from_secs(1)).unwrap();
+        time_source.advance(Duration::from_secs(2));
+
+        // as if another handle added it between the sweep's read and its write
+        let expired = cas.lmdb_expired_keys().unwrap();
+        cas.add(&content).unwrap();
+        assert_eq!(0, cas.lmdb_delete_expired(&expired).unwrap());
+        assert_eq!(Ok(true), cas.contains(&content.address()));
+
+        // and adding it with a ttl now doesn't make it expire
+        cas.add_with_ttl(&content, Duration::from_secs(1)).unwrap();
+        time_source.advance(Duration::from_secs(2));
+        assert_eq!(Ok(0), cas.sweep_expired());
+        assert_eq!(Ok(true), cas.contains(&content.address()));
+    }
+
     #[test]
     fn lmdb_report_storage_test() {
         let (mut cas, _) = test_lmdb_cas();
@@ -151,4 +381,27 @@ mod tests {
             .expect("could not add to CAS");
         assert_eq!(cas.get_storage_report().unwrap(), StorageReport::new(0 + 0),);
     }
+
+    #[test]
+    fn lmdb_read_only_test() {
+        let (cas, dir) = test_lmdb_cas();
+        StorageTestSuite::new(cas).read_only_test(
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+            || LmdbStorage::new_read_only(dir.path()).unwrap(),
+        );
+        assert!(LmdbStorage::new_read_only(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn lmdb_id_test() {
+        let (cas, dir) = test_lmdb_cas();
+        let (other, _other_dir) = test_lmdb_cas();
+        assert_ne!(cas.get_id(), other.get_id());
+        assert_eq!(
+            cas.get_id(),
+            LmdbStorage::new_read_only(dir.path()).unwrap().get_id()
+        );
+        StorageTestSuite::new(cas).id_test(LmdbStorage::new(dir.path(), None));
+    }
 }
@@ -1,85 +1,84 @@
+use crate::env::LmdbEnvironment;
 use holochain_logging::prelude::*;
+use holochain_persistence_api::error::{ErrorContext, PersistenceError, PersistenceResult};
 use lmdb::Error as LmdbError;
-use rkv::{
-    DatabaseFlags, EnvironmentFlags, Manager, Rkv, SingleStore, StoreError, StoreOptions, Value,
-};
-use std::{
-    path::Path,
-    sync::{Arc, RwLock},
-};
+use rkv::{DatabaseFlags, Rkv, SingleStore, StoreError, StoreOptions, Writer};
+use std::sync::{Arc, RwLock};
 
-const DEFAULT_INITIAL_MAP_BYTES: usize = 100 * 1024 * 1024;
+pub(crate) const BACKEND: &str = "lmdb";
+/// errno values LMDB passes through from the file system
+const ENOENT: i32 = 2;
+const EACCES: i32 = 13;
 
 #[derive(Clone)]
 pub(crate) struct LmdbInstance {
     pub store: SingleStore,
     pub manager: Arc<RwLock<Rkv>>,
+    pub read_only: bool,
 }
 
 impl LmdbInstance {
-    pub fn new<P: AsRef<Path> + Clone>(
+    /// opens the named store in an environment of its own at `<path>/<db_name>.db`
+    #[cfg(test)]
+    pub fn new<P: AsRef<std::path::Path> + Clone>(
         db_name: &str,
         path: P,
         initial_map_bytes: Option<usize>,
     ) -> LmdbInstance {
-        let db_path = path.as_ref().join(db_name).with_extension("db");
-        std::fs::create_dir_all(db_path.clone()).expect("Could not create file path for store");
-
-        let manager = Manager::singleton()
-            .write()
-            .unwrap()
-            .get_or_create(db_path.as_path(), |path: &Path| {
-                let mut env_builder = Rkv::environment_builder();
-                env_builder
-                    // max size of memory map, can be changed later
-                    .set_map_size(initial_map_bytes.unwrap_or(DEFAULT_INITIAL_MAP_BYTES))
-                    // max number of DBs in this environment
-                    .set_max_dbs(1)
-                    // Thes flags make writes waaaaay faster by async writing to disk rather than blocking
-                    // There is some loss of data integrity guarantees that comes with this
-                    .set_flags(EnvironmentFlags::WRITE_MAP | EnvironmentFlags::MAP_ASYNC);
-                Rkv::from_env(path, env_builder)
-            })
-            .expect("Could not create the environment");
-
-        let env = manager
-            .read()
-            .expect("Could not get a read lock on the manager");
-
-        // Then you can use the environment handle to get a handle to a datastore:
-        let options = StoreOptions {
-            create: true,
-            flags: DatabaseFlags::empty(),
+        let env = LmdbEnvironment::new(
+            path.as_ref().join(db_name).with_extension("db"),
+            initial_map_bytes,
+            None,
+        );
+        LmdbInstance::open(&env, db_name).expect("Could not create store")
+    }
+
+    /// opens the named store inside an existing environment
+    /// the store is created if needed unless the environment is read-only
+    pub fn open(env: &LmdbEnvironment, db_name: &str) -> Result<LmdbInstance, StoreError> {
+        let manager = env.manager();
+        let read_only = env.is_read_only();
+        let store = {
+            let env = manager
+                .read()
+                .expect("Could not get a read lock on the manager");
+            let options = StoreOptions {
+                create: !read_only,
+                flags: DatabaseFlags::empty(),
+            };
+            env.open_single(db_name, options)?
         };
-        let store: SingleStore = env
-            .open_single(db_name, options)
-            .expect("Could not create store");
 
-        LmdbInstance {
-            store: store,
-            manager: manager.clone(),
-        }
+        Ok(LmdbInstance {
+            store,
+            manager,
+            read_only,
+        })
     }
 
-    pub fn add<K: AsRef<[u8]> + Clone>(&self, key: K, value: &Value) -> Result<(), StoreError> {
-        let env = self.manager.read().unwrap();
-        let mut writer = env.write()?;
+    /// errors if the store was opened read-only
+    pub fn check_writable(&self, store: &str) -> PersistenceResult<()> {
+        if self.read_only {
+            Err(PersistenceError::ReadOnlyError(
+                context("write").with_message(format!("{} is read-only", store)),
+            ))
+        } else {
+            Ok(())
+        }
+    }
 
-        match self
-            .store
-            .put(&mut writer, key.clone(), value)
-            .and_then(|_| writer.commit())
-        {
-            Err(StoreError::LmdbError(LmdbError::MapFull)) => {
-                trace!("Insufficient space in MMAP, doubling and trying again");
-                let map_size = env.info()?.map_size();
-                env.set_map_size(map_size * 2)?;
-                self.add(key, value)
-            }
-            r => r, // preserve any other errors
-        }?;
+    #[cfg(test)]
+    pub fn add<K: AsRef<[u8]>>(&self, key: K, value: &rkv::Value) -> Result<(), StoreError> {
+        write_txn(&self.manager, |writer| self.store.put(writer, &key, value))
+    }
 
-        Ok(())
+    /// deletes the key as part of a larger write transaction
+    pub fn delete_in<K: AsRef<[u8]>>(&self, writer: &mut Writer, key: K) -> Result<(), StoreError> {
+        match self.store.delete(writer, key) {
+            // deleting something that isn't there is fine
+            Ok(()) | Err(StoreError::LmdbError(LmdbError::NotFound)) => Ok(()),
+            Err(e) => Err(e),
+        }
     }
 
     #[allow(dead_code)]
@@ -88,12 +87,115 @@ impl LmdbInstance {
     }
 }
 
+/// the context of an error in the given LMDB operation
+pub(crate) fn context(operation: &str) -> ErrorContext {
+    ErrorContext::new(BACKEND, operation)
+}
+
+/// maps an rkv error onto the PersistenceError variant that says what went wrong
+/// the error is appended to any message the context already has
+pub(crate) fn store_error(context: ErrorContext, error: StoreError) -> PersistenceError {
+    let message = |context: ErrorContext| {
+        let message = if context.message.is_empty() {
+            error.to_string()
+        } else {
+            format!("{}: {}", context.message, error)
+        };
+        context.with_message(message)
+    };
+    match &error {
+        StoreError::LmdbError(LmdbError::MapFull) => PersistenceError::MapFull(message(context)),
+        StoreError::LmdbError(LmdbError::NotFound)
+        | StoreError::LmdbError(LmdbError::Other(ENOENT))
+        | StoreError::DirectoryDoesNotExistError(_) => PersistenceError::NotFound(message(context)),
+        StoreError::LmdbError(LmdbError::Other(EACCES)) => {
+            PersistenceError::PermissionDenied(message(context))
+        }
+        StoreError::LmdbError(LmdbError::PageNotFound)
+        | StoreError::LmdbError(LmdbError::Corrupted)
+        | StoreError::LmdbError(LmdbError::Panic)
+        | StoreError::LmdbError(LmdbError::VersionMismatch)
+        | StoreError::LmdbError(LmdbError::Invalid)
+        | StoreError::DataError(_) => PersistenceError::Corruption(message(context)),
+        StoreError::IoError(io_error) => PersistenceError::io(context, io_error),
+        _ => PersistenceError::Backend(message(context)),
+    }
+}
+
+/// runs the writes in a single transaction and commits it
+/// if the memory map fills up the transaction is aborted, the map doubled and the writes retried
+pub(crate) fn write_txn<R, F>(manager: &RwLock<Rkv>, mut writes: F) -> Result<R, StoreError>
+where
+    F: FnMut(&mut Writer) -> Result<R, StoreError>,
+{
+    let env = manager.read().unwrap();
+    loop {
+        let mut writer = env.write()?;
+        match writes(&mut writer).and_then(|r| writer.commit().map(|_| r)) {
+            Err(StoreError::LmdbError(LmdbError::MapFull)) => {
+                trace!("Insufficient space in MMAP, doubling and trying again");
+                let map_size = env.info()?.map_size();
+                env.set_map_size(map_size * 2)?;
+            }
+            r => return r, // preserve any other errors
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use holochain_persistence_api::cas::{content::AddressableContent, storage::CasBencher};
+    use rkv::Value;
     use tempfile::tempdir;
 
+    #[test]
+    fn store_errors_map_to_variants() {
+        let address = "Qm".into();
+        match store_error(
+            context("add").with_address(&address),
+            StoreError::LmdbError(LmdbError::MapFull),
+        ) {
+            PersistenceError::MapFull(context) => {
+                assert_eq!(BACKEND, context.backend);
+                assert_eq!("add", context.operation);
+                assert_eq!(Some(address), context.address);
+            }
+            e => panic!("expected MapFull, got {:?}", e),
+        }
+        match store_error(
+            context("fetch"),
+            StoreError::LmdbError(LmdbError::Corrupted),
+        ) {
+            PersistenceError::Corruption(_) => (),
+            e => panic!("expected Corruption, got {:?}", e),
+        }
+        match store_error(
+            context("open"),
+            StoreError::LmdbError(LmdbError::Other(EACCES)),
+        ) {
+            PersistenceError::PermissionDenied(_) => (),
+            e => panic!("expected PermissionDenied, got {:?}", e),
+        }
+        let e = store_error(
+            context("open"),
+            StoreError::IoError(std::io::ErrorKind::NotFound.into()),
+        );
+        match &e {
+            PersistenceError::NotFound(_) => assert!(std::error::Error::source(&e).is_some()),
+            e => panic!("expected NotFound, got {:?}", e),
+        }
+        match store_error(
+            context("open").with_message("Could not open store cas"),
+            StoreError::LmdbError(LmdbError::DbsFull),
+        ) {
+            PersistenceError::Backend(context) => {
+                assert!(context.message.starts_with("Could not open store cas: "))
+            }
+            e => panic!("expected Backend, got {:?}", e),
+        }
+    }
+
     #[test]
     fn can_grow_map_on_write() {
         // make a db with a 1MB MMAP. This seems to be the lowest you an go (probably OS dependent)
@@ -0,0 +1,211 @@
+//! Keys for the secondary indexes of an EavLmdbStorage.
+//!
+//! LMDB keeps keys in byte order, so every part of a key is encoded such that byte order is the
+//! order we want to scan in and a prefix of the parts selects exactly the rows that match them.
+//! Parts are separated by a NUL byte, which never appears in an address or in the JSON of an
+//! attribute, and indexes are encoded so that they sort numerically.
+
+use holochain_persistence_api::{
+    cas::content::{Address, AddressableContent},
+    eav::{Attribute, EntityAttributeValueIndex},
+    hash::HashString,
+};
+use multihash::Hash;
+
+const SEPARATOR: char = '\0';
+/// attributes with longer JSON than this are keyed by its hash to stay inside LMDB's key limit
+const MAX_ATTRIBUTE_KEY_BYTES: usize = 200;
+
+/// encodes an index as 16 hex digits that sort in the same order as the indexes
+pub fn encode_index(index: i64) -> String {
+    // flipping the sign bit puts negative indexes before positive ones
+    format!("{:016x}", (index as u64) ^ (1 << 63))
+}
+
+/// the attribute as it appears in keys
+pub fn attribute_part<A: Attribute>(attribute: &A) -> String {
+    let json = serde_json::to_string(attribute).expect("attributes always serialize");
+    if json.len() > MAX_ATTRIBUTE_KEY_BYTES {
+        // '#' never starts the JSON of an attribute so hashed keys can't collide with the rest
+        format!("#{}", HashString::encode_from_str(&json, Hash::SHA2256))
+    } else {
+        json
+    }
+}
+
+fn join(parts: &[&str]) -> String {
+    let mut key = String::new();
+    for part in parts {
+        key.push_str(part);
+        key.push(SEPARATOR);
+    }
+    key
+}
+
+/// attribute, entity, index: finds everything with an attribute, optionally of one entity
+pub fn aev_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> String {
+    join(&[
+        &attribute_part(&eavi.attribute()),
+        &eavi.entity().to_string(),
+        &encode_index(eavi.index()),
+    ])
+}
+
+/// value, entity, attribute, index: finds everything pointing at a value, optionally from one
+/// entity
+pub fn vea_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> String {
+    join(&[
+        &eavi.value().to_string(),
+        &eavi.entity().to_string(),
+        &attribute_part(&eavi.attribute()),
+        &encode_index(eavi.index()),
+    ])
+}
+
+/// source, entity, attribute, index: finds everything asserted by a source
+/// None for eavis without a source, which aren't in the source index
+pub fn source_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> Option<String> {
+    eavi.source().map(|source| {
+        join(&[
+            &source.to_string(),
+            &eavi.entity().to_string(),
+            &attribute_part(&eavi.attribute()),
+            &encode_index(eavi.index()),
+        ])
+    })
+}
+
+/// index, entity, attribute: finds everything in a range of indexes, in index order
+pub fn iea_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> String {
+    join(&[
+        &encode_index(eavi.index()),
+        &eavi.entity().to_string(),
+        &attribute_part(&eavi.attribute()),
+    ])
+}
+
+/// ends the index keys of an eavi that shares its entity and index with another, to keep the
+/// keys of the two apart
+pub fn collision_part<A>(eavi: &EntityAttributeValueIndex<A>) -> String
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    join(&[&eavi.address().to_string()])
+}
+
+/// the least key after every key whose index is at most the given one, for index ordered keys
+/// that start with the prefix, or None if there is no such key
+pub fn index_end(prefix: &str, index: i64) -> Option<Vec<u8>> {
+    prefix_end(format!("{}{}", prefix, encode_index(index)).as_bytes())
+}
+
+/// the prefix of the AEV keys with the attribute and, if given, the entity
+pub fn aev_prefix<A: Attribute>(attribute: &A, entity: Option<&Address>) -> String {
+    let attribute = attribute_part(attribute);
+    match entity {
+        Some(entity) => join(&[&attribute, &entity.to_string()]),
+        None => join(&[&attribute]),
+    }
+}
+
+/// the prefix of the VEA keys with the value and, if given, the entity
+pub fn vea_prefix(value: &Address, entity: Option<&Address>) -> String {
+    match entity {
+        Some(entity) => join(&[&value.to_string(), &entity.to_string()]),
+        None => join(&[&value.to_string()]),
+    }
+}
+
+/// the prefix of the source keys with the source
+pub fn source_prefix(source: &Address) -> String {
+    join(&[&source.to_string()])
+}
+
+/// the least key after every key that starts with the prefix, or None if there is no such key
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < std::u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_persistence_api::eav::ExampleAttribute;
+
+    #[test]
+    fn encoded_indexes_sort_numerically() {
+        let indexes = [std::i64::MIN, -10, -1, 0, 1, 10, 1_000_000, std::i64::MAX];
+        let encoded: Vec<String> = indexes.iter().map(|i| encode_index(*i)).collect();
+        let mut sorted = encoded.clone();
+        sorted.sort();
+        assert_eq!(encoded, sorted);
+        assert!(encoded.iter().all(|e| e.len() == 16));
+    }
+
+    #[test]
+    fn prefixes_select_matching_keys() {
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &"entity".into(),
+            &ExampleAttribute::WithPayload("link".into()),
+            &"value".into(),
+            7,
+        )
+        .unwrap();
+        let aev = aev_key(&eavi);
+        assert!(aev.starts_with(&aev_prefix(&eavi.attribute(), None)));
+        assert!(aev.starts_with(&aev_prefix(&eavi.attribute(), Some(&eavi.entity()))));
+        assert!(!aev.starts_with(&aev_prefix(
+            &ExampleAttribute::WithPayload("lin".into()),
+            None
+        )));
+
+        let vea = vea_key(&eavi);
+        assert!(vea.starts_with(&vea_prefix(&eavi.value(), None)));
+        assert!(vea.starts_with(&vea_prefix(&eavi.value(), Some(&eavi.entity()))));
+        // a value that is a prefix of another is not matched by it
+        assert!(!vea.starts_with(&vea_prefix(&"valu".into(), None)));
+
+        let iea = iea_key(&eavi);
+        assert!(iea.starts_with(&encode_index(7)));
+        assert!(iea.as_bytes() < index_end("", 7).unwrap().as_slice());
+        assert!(iea.as_bytes() >= index_end("", 6).unwrap().as_slice());
+        assert!(
+            aev.as_bytes()
+                < index_end(&aev_prefix(&eavi.attribute(), Some(&eavi.entity())), 7)
+                    .unwrap()
+                    .as_slice()
+        );
+
+        assert_eq!(None, source_key(&eavi));
+        let source = source_key(&eavi.with_source(&"author".into())).unwrap();
+        assert!(source.starts_with(&source_prefix(&"author".into())));
+        assert!(!source.starts_with(&source_prefix(&"auth".into())));
+    }
+
+    #[test]
+    fn long_attributes_are_hashed() {
+        let long = ExampleAttribute::WithPayload("x".repeat(1000));
+        let part = attribute_part(&long);
+        assert!(part.starts_with('#'));
+        assert!(part.len() < MAX_ATTRIBUTE_KEY_BYTES);
+        assert_eq!(part, attribute_part(&long));
+    }
+
+    #[test]
+    fn prefix_ends_follow_every_key_with_the_prefix() {
+        let end = prefix_end(b"ab").unwrap();
+        assert_eq!(b"ac".to_vec(), end);
+        for key in [&b"ab"[..], b"ab\0", b"abzzz", b"ab\xff\xff"].iter() {
+            assert!(key < &end.as_slice());
+        }
+        assert_eq!(Some(b"b".to_vec()), prefix_end(b"a\xff"));
+        assert_eq!(None, prefix_end(b"\xff\xff"));
+        assert_eq!(None, prefix_end(b""));
+    }
+}
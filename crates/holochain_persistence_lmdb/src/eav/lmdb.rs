@@ -1,22 +1,29 @@
 use holochain_persistence_api::{
-    cas::content::AddressableContent,
+    cas::content::{Address, AddressableContent},
     eav::{
-        Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage,
+        count_by, resolve_collision, Attribute, Bindings, Clock, EavFilter, EaviIter, EaviPage,
+        EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, Index, IndexFilter,
+        JoinQuery, LiveRegistry, Order, Placement, Slot, Traversal, TraversalStep, WallClock,
     },
     error::{PersistenceError, PersistenceResult},
     reporting::{ReportStorage, StorageReport},
 };
 // use kv::{Config, Manager, Store, Error as KvError};
-use crate::common::LmdbInstance;
+use crate::{
+    common::{context, store_error, write_txn, LmdbInstance},
+    eav::key,
+    env::LmdbEnvironment,
+};
 use rkv::{
     error::{DataError, StoreError},
-    Value,
+    Readable, Rkv, Value, Writer,
 };
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fmt::{Debug, Error, Formatter},
     marker::{PhantomData, Send, Sync},
     path::Path,
+    sync::{Arc, RwLock},
 };
 use uuid::Uuid;
 
@@ -26,22 +33,234 @@ const EAV_BUCKET: &str = "EAV";
 pub struct EavLmdbStorage<A: Attribute> {
     id: Uuid,
     lmdb: LmdbInstance,
+    /// retracted eavis, under the same keys as in the main store
+    /// only missing when opened read-only on a store written before retraction support
+    retracted_lmdb: Option<LmdbInstance>,
+    /// only missing when opened read-only on a store written before the indexes existed
+    indexes: Option<EavIndexes>,
     attribute: PhantomData<A>,
+    live: LiveRegistry<A>,
+    clock: Arc<dyn Clock>,
+}
+
+/// secondary indexes onto the main store, written in the same transaction as every eavi
+/// each maps a key from the key module to the key of the eavi in the main store
+#[derive(Clone)]
+pub(crate) struct EavIndexes {
+    pub aev: LmdbInstance,
+    pub vea: LmdbInstance,
+    /// None in read-only stores written before eavis had sources
+    pub source: Option<LmdbInstance>,
+    /// the keys of eavis in index order, None in read-only stores written before it existed
+    pub index: Option<LmdbInstance>,
+}
+
+/// the eavis a scan finds, read lazily
+type Candidates<'r, A> =
+    Box<dyn Iterator<Item = Result<EntityAttributeValueIndex<A>, StoreError>> + 'r>;
+
+/// how a query finds its candidate eavis, from the filters that are Exact, Prefix or Range
+#[derive(Clone, Debug, PartialEq)]
+enum Scan {
+    /// the eavis of one entity in the main store
+    Entity(Address),
+    /// the keys in the AEV index starting with the prefix
+    Aev(String),
+    /// the keys in the AEV index starting with the prefix, which fixes the entity, up to those
+    /// with the index
+    AevUntil(String, Index),
+    /// the keys in the VEA index starting with the prefix
+    Vea(String),
+    /// the keys in the source index starting with the prefix
+    Source(String),
+    /// the eavis in the main store whose entities are in a prefix or range
+    Entities(Span),
+    /// the keys in the VEA index whose values are in a prefix or range
+    Values(Span),
+    /// the keys in the index order index whose indexes are in a range
+    Indexes(Span),
+    All,
+}
+
+/// the keys a scan reads, from start up to but not including end
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Span {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+}
+
+impl Span {
+    fn prefix(prefix: &str) -> Span {
+        Span {
+            start: prefix.as_bytes().to_vec(),
+            end: key::prefix_end(prefix.as_bytes()),
+        }
+    }
+
+    /// the span of keys whose leading part passes the filter, if it is a prefix or range
+    /// end_of turns the end of a range into the end of the keys
+    fn of<T: Eq>(filter: &EavFilter<T>, end_of: fn(&str) -> Option<Vec<u8>>) -> Option<Span> {
+        if let Some(prefix) = filter.key_prefix() {
+            return Some(Span::prefix(prefix));
+        }
+        filter.key_range().map(|range| Span {
+            start: range.start.clone().unwrap_or_default().into_bytes(),
+            end: range.end.as_ref().and_then(|end| end_of(end)),
+        })
+    }
+
+    fn before_end(&self, key: &[u8]) -> bool {
+        self.end.iter().all(|end| key < end.as_slice())
+    }
 }
 
-impl<A: Attribute> EavLmdbStorage<A> {
+/// the end of the main store keys for entities before end
+/// A key is the entity followed by "::", so an entity that is a prefix of end can have a key
+/// after end, when the byte of end after it is ':' or below. The keys of every entity before
+/// end come before the end of the prefix of end up to its first such byte.
+fn entity_keys_end(end: &str) -> Option<Vec<u8>> {
+    match end.bytes().position(|b| b <= b':') {
+        Some(position) => key::prefix_end(&end.as_bytes()[..position]),
+        None => Some(end.as_bytes().to_vec()),
+    }
+}
+
+/// VEA keys are the value followed by a NUL, so keys of values before end come before end
+fn value_keys_end(end: &str) -> Option<Vec<u8>> {
+    Some(end.as_bytes().to_vec())
+}
+
+impl<A: Attribute> EavLmdbStorage<A>
+where
+    A: Sync + Send + serde::de::DeserializeOwned,
+{
     pub fn new<P: AsRef<Path> + Clone>(
         db_path: P,
         initial_map_bytes: Option<usize>,
+    ) -> EavLmdbStorage<A> {
+        let env = LmdbEnvironment::new(
+            db_path.as_ref().join(EAV_BUCKET).with_extension("db"),
+            initial_map_bytes,
+            None,
+        );
+        env.eav(EAV_BUCKET).expect("Could not create store")
+    }
+
+    /// opens an EAV created with `new` at the same path without ever writing to it
+    pub fn new_read_only<P: AsRef<Path>>(db_path: P) -> PersistenceResult<EavLmdbStorage<A>> {
+        let env = LmdbEnvironment::new_read_only(
+            db_path.as_ref().join(EAV_BUCKET).with_extension("db"),
+            None,
+        )?;
+        env.eav(EAV_BUCKET)
+    }
+
+    pub(crate) fn check_writable(&self) -> PersistenceResult<()> {
+        self.lmdb.check_writable("EAV")
+    }
+
+    pub(crate) fn manager(&self) -> &Arc<RwLock<Rkv>> {
+        &self.lmdb.manager
+    }
+
+    pub(crate) fn from_instances(
+        lmdb: LmdbInstance,
+        retracted_lmdb: Option<LmdbInstance>,
+        indexes: Option<EavIndexes>,
+        id: Uuid,
     ) -> EavLmdbStorage<A> {
         EavLmdbStorage {
-            id: Uuid::new_v4(),
-            lmdb: LmdbInstance::new(EAV_BUCKET, db_path, initial_map_bytes),
+            id,
+            lmdb,
+            retracted_lmdb,
+            indexes,
             attribute: PhantomData,
+            live: LiveRegistry::new(),
+            clock: Arc::new(WallClock),
+        }
+    }
+
+    /// replaces the clock that stamps new eavis, the wall clock by default
+    /// a logical clock should start after the indexes already stored, @see
+    /// LamportClock::starting_after
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+/// how many rows fetch_eavi_iter reads in each read transaction
+const ITER_BATCH: usize = 256;
+
+/// a row read by fetch_eavi_iter that has yet to be deserialized
+struct RawRow {
+    /// the key of the row in the store that was scanned, to carry on from
+    key: Vec<u8>,
+    json: String,
+    retracted: bool,
+}
+
+/// the rows of a query, read a batch at a time with each batch in a read transaction of its
+/// own, so that no transaction is left open between calls to next
+struct LmdbEaviIter<'s, 'q, A: Attribute> {
+    storage: &'s EavLmdbStorage<A>,
+    query: &'s EaviQuery<'q, A>,
+    scan: Scan,
+    batch: VecDeque<RawRow>,
+    /// the key of the last row read, which the next batch starts after
+    last_key: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'s, 'q, A: Attribute> Iterator for LmdbEaviIter<'s, 'q, A>
+where
+    A: Sync + Send + serde::de::DeserializeOwned,
+{
+    type Item = PersistenceResult<EntityAttributeValueIndex<A>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.batch.is_empty() {
+                if self.done {
+                    return None;
+                }
+                match self
+                    .storage
+                    .read_batch(&self.scan, self.last_key.as_deref())
+                {
+                    Ok(batch) => {
+                        self.done = batch.len() < ITER_BATCH;
+                        self.last_key = batch.last().map(|row| row.key.clone());
+                        self.batch = batch.into();
+                    }
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(store_error(context("fetch_eavi_iter"), e)));
+                    }
+                }
+            }
+            let row = self.batch.pop_front()?;
+            let eavi: EntityAttributeValueIndex<A> = match serde_json::from_str(&row.json) {
+                Ok(eavi) => eavi,
+                Err(e) => {
+                    return Some(Err(PersistenceError::Corruption(
+                        context("fetch_eavi_iter").with_message(e.to_string()),
+                    )))
+                }
+            };
+            if self.query.retraction().keeps(row.retracted) && self.query.selects(&eavi) {
+                return Some(Ok(eavi));
+            }
         }
     }
 }
 
+impl<A: Attribute> PartialEq for EavLmdbStorage<A> {
+    fn eq(&self, other: &EavLmdbStorage<A>) -> bool {
+        self.id == other.id
+    }
+}
+
 impl<A: Attribute> Debug for EavLmdbStorage<A> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         f.debug_struct("EavLmdbStorage")
@@ -50,6 +269,40 @@ impl<A: Attribute> Debug for EavLmdbStorage<A> {
     }
 }
 
+/// eavis are keyed by entity and index so that exact match queries on the entity can seek
+fn row_key<A: Attribute>(eav: &EntityAttributeValueIndex<A>) -> String {
+    format!("{}::{}", eav.entity(), eav.index())
+}
+
+/// the key of an eavi added once another eavi had its entity and index, @see resolve_collision
+fn collision_key<A>(eav: &EntityAttributeValueIndex<A>) -> String
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    format!("{}::{}::{}", eav.entity(), eav.index(), eav.address())
+}
+
+/// the key the eavi is kept under in the store, if it is
+/// the retraction store keys eavis the same way as the main store
+fn key_in<A, R>(
+    store: &LmdbInstance,
+    reader: &R,
+    eav: &EntityAttributeValueIndex<A>,
+) -> Result<Option<String>, StoreError>
+where
+    A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+    R: Readable,
+{
+    for key in &[row_key(eav), collision_key(eav)] {
+        if let Some(value) = store.store.get(reader, key)? {
+            if &handle_cursor_result::<A>(Ok((key.as_bytes(), Some(value))))? == eav {
+                return Ok(Some(key.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
 fn handle_cursor_result<A: Attribute>(
     result: Result<(&[u8], Option<rkv::Value>), StoreError>,
 ) -> Result<EntityAttributeValueIndex<A>, StoreError>
@@ -75,63 +328,500 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> Result<Option<EntityAttributeValueIndex<A>>, StoreError> {
+        let stored = write_txn(&self.lmdb.manager, |writer| self.put_eavi(writer, eav))?;
+        // the clock only moves past indexes that were committed
+        self.clock.observe(stored.index());
+        Ok(Some(stored))
+    }
+
+    /// adds the eavi as part of a larger write transaction in this store's environment
+    /// returns the eavi as it was stored, the caller observes its index once the write commits
+    pub(crate) fn put_eavi(
+        &self,
+        writer: &mut Writer,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<EntityAttributeValueIndex<A>, StoreError> {
+        let placement =
+            resolve_collision::<_, StoreError, _>(vec![row_key(eav), collision_key(eav)], |key| {
+                let stored = match self.lmdb.store.get(writer, key)? {
+                    Some(value) => Some(handle_cursor_result::<A>(Ok((
+                        key.as_bytes(),
+                        Some(value),
+                    )))?),
+                    None => None,
+                };
+                Ok(Slot::of(stored.as_ref(), eav))
+            })?;
+        if let Placement::Add(key) = placement {
+            self.lmdb
+                .store
+                .put(writer, &key, &Value::Json(&eav.content().to_string()))?;
+            self.put_index_entries(writer, &key, eav)?;
+        }
+        Ok(eav.clone())
+    }
+
+    fn put_index_entries(
+        &self,
+        writer: &mut Writer,
+        key: &str,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<(), StoreError> {
+        let indexes = self
+            .indexes
+            .as_ref()
+            .expect("only read-only stores have no indexes and they are never written");
+        // eavis sharing an entity and index need entries of their own
+        let collision = if key == row_key(eav) {
+            String::new()
+        } else {
+            key::collision_part(eav)
+        };
+        let entry = |index_key: String| index_key + &collision;
+        indexes
+            .aev
+            .store
+            .put(writer, entry(key::aev_key(eav)), &Value::Str(key))?;
+        indexes
+            .vea
+            .store
+            .put(writer, entry(key::vea_key(eav)), &Value::Str(key))?;
+        if let (Some(source), Some(source_key)) = (&indexes.source, key::source_key(eav)) {
+            source
+                .store
+                .put(writer, entry(source_key), &Value::Str(key))?;
+        }
+        match &indexes.index {
+            Some(index) => index
+                .store
+                .put(writer, entry(key::iea_key(eav)), &Value::Str(key)),
+            None => Ok(()),
+        }
+    }
+
+    /// indexes every eavi in the main store if the indexes, or the index order index, are empty
+    /// stores written before the indexes existed are indexed the first time they are opened
+    pub(crate) fn index_if_unindexed(&self) -> Result<(), StoreError> {
+        let indexes = match &self.indexes {
+            Some(indexes) => indexes,
+            None => return Ok(()),
+        };
+        write_txn(&self.lmdb.manager, |writer| {
+            let indexed = indexes.aev.store.iter_start(writer)?.next().is_some()
+                && match &indexes.index {
+                    Some(index) => index.store.iter_start(writer)?.next().is_some(),
+                    None => true,
+                };
+            if indexed {
+                return Ok(());
+            }
+            let eavis = self
+                .lmdb
+                .store
+                .iter_start(writer)?
+                .map(|result| {
+                    let (key, value) = result?;
+                    let eavi = handle_cursor_result::<A>(Ok((key, value)))?;
+                    Ok((String::from_utf8_lossy(key).into_owned(), eavi))
+                })
+                .collect::<Result<Vec<_>, StoreError>>()?;
+            for (key, eavi) in eavis.iter() {
+                self.put_index_entries(writer, key, eavi)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// picks the most selective way to find the eavis the query could match
+    fn plan(&self, query: &EaviQuery<A>) -> Scan {
+        let entity = query.entity.exact();
+        let attribute = query.attribute.exact();
+        let value = query.value.exact();
+        match (self.indexes.is_some(), entity, attribute, value) {
+            (true, Some(entity), _, Some(value)) => Scan::Vea(key::vea_prefix(value, Some(entity))),
+            (true, Some(entity), Some(attribute), None) => {
+                let prefix = key::aev_prefix(attribute, Some(entity));
+                match query.index_span() {
+                    (_, Some(end)) => Scan::AevUntil(prefix, end),
+                    (_, None) => Scan::Aev(prefix),
+                }
+            }
+            (_, Some(entity), _, _) => Scan::Entity(entity.clone()),
+            (true, None, _, Some(value)) => Scan::Vea(key::vea_prefix(value, None)),
+            (indexed, None, _, _) => {
+                let entities = Span::of(&query.entity, entity_keys_end);
+                let values = Span::of(&query.value, value_keys_end).filter(|_| indexed);
+                let source = match query.source.exact() {
+                    Some(Some(source)) if self.has_source_index() => Some(source),
+                    _ => None,
+                };
+                match (source, entities, values, attribute) {
+                    (Some(source), _, _, _) => Scan::Source(key::source_prefix(source)),
+                    (None, Some(entities), _, _) => Scan::Entities(entities),
+                    (None, None, Some(values), _) => Scan::Values(values),
+                    (None, None, None, Some(attribute)) if indexed => {
+                        Scan::Aev(key::aev_prefix(attribute, None))
+                    }
+                    _ => match query.index_span() {
+                        (None, None) => Scan::All,
+                        _ if !self.has_index_order_index() => Scan::All,
+                        (start, end) => Scan::Indexes(Span {
+                            start: start
+                                .map(key::encode_index)
+                                .unwrap_or_default()
+                                .into_bytes(),
+                            end: end.and_then(|end| key::index_end("", end)),
+                        }),
+                    },
+                }
+            }
+        }
+    }
+
+    /// whether rows come out of the planned scan in ascending order of index, so that a page
+    /// can seek to its cursor and stop reading once it is full
+    /// that is so for the index order index, and for the AEV index with the entity fixed, whose
+    /// keys end with the index. Other scans read rows in the order of their entity or value.
+    fn scans_in_order(scan: &Scan, query: &EaviQuery<A>) -> bool {
+        match scan {
+            Scan::Indexes(_) | Scan::AevUntil(..) => true,
+            Scan::Aev(_) => query.entity.exact().is_some(),
+            _ => false,
+        }
+    }
+
+    /// Narrows a scan in index order to the rows that can follow the cursor.
+    /// Ascending scans seek to the cursor's index, returned as the key to start reading at.
+    /// LMDB cursors are only read forwards here, so descending scans can't start at the cursor
+    /// and read back. They end at the cursor's index instead, and still read every row before
+    /// it.
+    fn seek_to_cursor(scan: Scan, query: &EaviQuery<A>) -> (Scan, Option<String>) {
+        let cursor = match query.cursor() {
+            Some(cursor) => cursor.index(),
+            None => return (scan, None),
+        };
+        match (query.order(), scan) {
+            (Order::Ascending, Scan::Indexes(span)) => {
+                (Scan::Indexes(span), Some(key::encode_index(cursor)))
+            }
+            (Order::Ascending, Scan::Aev(prefix)) => {
+                let seek = format!("{}{}", prefix, key::encode_index(cursor));
+                (Scan::Aev(prefix), Some(seek))
+            }
+            (Order::Ascending, Scan::AevUntil(prefix, end)) => {
+                let seek = format!("{}{}", prefix, key::encode_index(cursor));
+                (Scan::AevUntil(prefix, end), Some(seek))
+            }
+            (Order::Descending, Scan::Indexes(span)) => {
+                let end = key::index_end("", cursor);
+                let end = match (span.end, end) {
+                    (Some(span_end), Some(end)) => Some(span_end.min(end)),
+                    (span_end, end) => span_end.or(end),
+                };
+                (Scan::Indexes(Span { end, ..span }), None)
+            }
+            (Order::Descending, Scan::Aev(prefix)) => (Scan::AevUntil(prefix, cursor), None),
+            (Order::Descending, Scan::AevUntil(prefix, end)) => {
+                (Scan::AevUntil(prefix, end.min(cursor)), None)
+            }
+            (_, scan) => (scan, None),
+        }
+    }
+
+    /// the eavis the scan finds, read lazily in key order
+    /// seek is where to start reading, if past the start of the scan
+    fn candidates<'r, R: Readable>(
+        &'r self,
+        reader: &'r R,
+        scan: Scan,
+        seek: Option<String>,
+    ) -> Result<Candidates<'r, A>, StoreError> {
+        let (store, span) = self.scan_range(&scan);
+        let is_index = !std::ptr::eq(store, &self.lmdb);
+        // a seek before the start of the span would read rows outside it
+        let start = seek
+            .map(String::into_bytes)
+            .filter(|seek| *seek > span.start)
+            .unwrap_or_else(|| span.start.clone());
+        let iter = if start.is_empty() {
+            store.store.iter_start(reader)?
+        } else {
+            store.store.iter_from(reader, start)?
+        };
+        let in_span = iter.take_while(move |r| {
+            // stop at the first key past the end (but keep taking errors)
+            match r {
+                Ok((k, _)) => span.before_end(k),
+                _ => true,
+            }
+        });
+        if !is_index {
+            return Ok(Box::new(in_span.map(handle_cursor_result)));
+        }
+        // index entries hold the key of the eavi in the main store
+        Ok(Box::new(in_span.filter_map(move |result| {
+            let key = match result {
+                Ok((_, Some(Value::Str(key)))) => key,
+                Ok(_) => return Some(Err(StoreError::DataError(DataError::Empty))),
+                Err(e) => return Some(Err(e)),
+            };
+            match self.lmdb.store.get(reader, key) {
+                Ok(Some(value)) => Some(handle_cursor_result(Ok((key.as_bytes(), Some(value))))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    /// the store a scan reads and the span of keys it reads there
+    fn scan_range(&self, scan: &Scan) -> (&LmdbInstance, Span) {
+        match scan {
+            Scan::Entity(entity) => (&self.lmdb, Span::prefix(&format!("{}::", entity))),
+            Scan::Aev(prefix) => (&self.indexes().aev, Span::prefix(prefix)),
+            Scan::AevUntil(prefix, end) => (
+                &self.indexes().aev,
+                Span {
+                    end: key::index_end(prefix, *end),
+                    ..Span::prefix(prefix)
+                },
+            ),
+            Scan::Vea(prefix) => (&self.indexes().vea, Span::prefix(prefix)),
+            Scan::Source(prefix) => (
+                self.indexes()
+                    .source
+                    .as_ref()
+                    .expect("only planned with a source index"),
+                Span::prefix(prefix),
+            ),
+            Scan::Entities(span) => (&self.lmdb, span.clone()),
+            Scan::Values(span) => (&self.indexes().vea, span.clone()),
+            Scan::Indexes(span) => (
+                self.indexes()
+                    .index
+                    .as_ref()
+                    .expect("only planned with an index order index"),
+                span.clone(),
+            ),
+            Scan::All => (&self.lmdb, Span::default()),
+        }
+    }
+
+    /// reads the rows of the scan that come after the given key, up to a batch of them, in a
+    /// read transaction of its own
+    /// rows are kept as JSON, to be deserialized only when they are reached
+    fn read_batch(&self, scan: &Scan, after: Option<&[u8]>) -> Result<Vec<RawRow>, StoreError> {
         let env = self.lmdb.manager.read().unwrap();
         let reader = env.read()?;
+        let (store, span) = self.scan_range(scan);
+        let is_index = !std::ptr::eq(store, &self.lmdb);
+        let iter = match after {
+            Some(after) => store.store.iter_from(&reader, after)?,
+            None if span.start.is_empty() => store.store.iter_start(&reader)?,
+            None => store.store.iter_from(&reader, &span.start)?,
+        };
+        let mut batch = Vec::new();
+        for result in iter {
+            let (key, value) = result?;
+            if !span.before_end(key) || batch.len() == ITER_BATCH {
+                break;
+            }
+            if Some(key) == after {
+                continue;
+            }
+            let (row_key, json) = match (is_index, value) {
+                // index entries hold the key of the eavi in the main store
+                (true, Some(Value::Str(row_key))) => match self.lmdb.store.get(&reader, row_key)? {
+                    Some(Value::Json(json)) => (row_key.to_string(), json.to_string()),
+                    Some(_) => return Err(StoreError::DataError(DataError::Empty)),
+                    None => continue,
+                },
+                (false, Some(Value::Json(json))) => {
+                    (String::from_utf8_lossy(key).into_owned(), json.to_string())
+                }
+                _ => return Err(StoreError::DataError(DataError::Empty)),
+            };
+            let retracted = match &self.retracted_lmdb {
+                Some(retracted) => retracted.store.get(&reader, &row_key)?.is_some(),
+                None => false,
+            };
+            batch.push(RawRow {
+                key: key.to_vec(),
+                json,
+                retracted,
+            });
+        }
+        Ok(batch)
+    }
+
+    fn indexes(&self) -> &EavIndexes {
+        self.indexes.as_ref().expect("only planned with indexes")
+    }
+
+    fn has_source_index(&self) -> bool {
+        self.indexes
+            .as_ref()
+            .map(|indexes| indexes.source.is_some())
+            .unwrap_or(false)
+    }
 
-        // use a clever key naming scheme to speed up exact match queries on the entity
-        let mut new_eav = eav.clone();
-        let mut key = format!("{}::{}", new_eav.entity(), new_eav.index());
-        // need to check there isn't a duplicate key though and if there is create a new EAVI which
-        // will have a more recent timestamp
-        while let Ok(Some(_)) = self.lmdb.store.get(&reader, key.clone()) {
-            new_eav = EntityAttributeValueIndex::new(&eav.entity(), &eav.attribute(), &eav.value())
-                .map_err(|_| StoreError::DataError(DataError::Empty))?;
-            key = format!("{}::{}", new_eav.entity(), new_eav.index());
+    fn has_index_order_index(&self) -> bool {
+        self.indexes
+            .as_ref()
+            .map(|indexes| indexes.index.is_some())
+            .unwrap_or(false)
+    }
+
+    fn is_retracted<R: Readable>(
+        &self,
+        reader: &R,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> Result<bool, StoreError> {
+        match &self.retracted_lmdb {
+            Some(retracted) => Ok(key_in(retracted, reader, eavi)?.is_some()),
+            None => Ok(false),
         }
+    }
 
-        self.lmdb
-            .add(key, &Value::Json(&new_eav.content().to_string()))?;
-        Ok(Some(new_eav))
+    /// whether the query keeps the row, once it has been read
+    fn keeps<R: Readable>(
+        &self,
+        reader: &R,
+        query: &EaviQuery<A>,
+        eavi: &EntityAttributeValueIndex<A>,
+    ) -> Result<bool, StoreError> {
+        Ok(query.selects(eavi) && query.retraction().keeps(self.is_retracted(reader, eavi)?))
     }
 
-    fn fetch_lmdb_eavi(
+    /// counts the rows the query selects by a key of each, in one read transaction and
+    /// without keeping any of them
+    fn count_eavi_by<K: Ord, F>(
         &self,
         query: &EaviQuery<A>,
-    ) -> Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError> {
+        key: F,
+    ) -> PersistenceResult<BTreeMap<K, usize>>
+    where
+        F: Fn(&EntityAttributeValueIndex<A>) -> K,
+    {
+        if !query.can_stream() {
+            return count_by(self.fetch_eavi(query)?.into_iter().map(Ok), key);
+        }
+        let env = self
+            .lmdb
+            .manager
+            .read()
+            .map_err(|e| PersistenceError::poisoned(context("count_eavi"), &e))?;
+        let reader = env
+            .read()
+            .map_err(|e| store_error(context("count_eavi"), e))?;
+        let selected = self.selected(&reader, query, "count_eavi")?;
+        count_by(selected, key)
+    }
+
+    /// the rows a query that can stream selects, read lazily in the reader's transaction
+    fn selected<'r, R: Readable>(
+        &'r self,
+        reader: &'r R,
+        query: &'r EaviQuery<A>,
+        operation: &'static str,
+    ) -> PersistenceResult<impl Iterator<Item = PersistenceResult<EntityAttributeValueIndex<A>>> + 'r>
+    {
+        Ok(self
+            .candidates(reader, self.plan(query), None)
+            .map_err(|e| store_error(context(operation), e))?
+            .filter_map(move |row| {
+                let kept = row.and_then(|eavi| {
+                    let keeps = self.keeps(reader, query, &eavi)?;
+                    Ok(Some(eavi).filter(|_| keeps))
+                });
+                kept.map_err(|e| store_error(context(operation), e))
+                    .transpose()
+            }))
+    }
+
+    fn fetch_lmdb_eavi_page(&self, query: &EaviQuery<A>) -> Result<EaviPage<A>, StoreError> {
         let env = self.lmdb.manager.read().unwrap();
         let reader = env.read()?;
 
-        let entries = match &query.entity {
-            EavFilter::Exact(entity) => {
-                // Can optimize here thanks to the sorted keys and only iterate matching entities
-                self.lmdb
-                    .store
-                    .iter_from(&reader, format!("{}::{}", entity, 0))? // start at the first key containing the entity address
-                    .take_while(|r| {
-                        // stop at the first key that doesn't match (but keep taking errors)
-                        match r {
-                            Ok((k, _)) => String::from_utf8(k.to_vec())
-                                .unwrap()
-                                .contains(&entity.to_string()),
-                            _ => true,
-                        }
-                    })
-                    .map(handle_cursor_result)
-                    .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()?
-            }
+        let scan = self.plan(query);
 
+        let limit = match (query.index(), query.limit()) {
+            (IndexFilter::Range(..), Some(limit)) if query.tombstone().is_none() => limit,
             _ => {
-                // In this case all we can do is iterate the entire database
-                self.lmdb
-                    .store
-                    .iter_start(&reader)?
-                    .map(handle_cursor_result)
-                    .collect::<Result<BTreeSet<EntityAttributeValueIndex<A>>, StoreError>>()?
+                // LatestByAttribute and tombstones need every row to tell which are kept, and
+                // without a limit every row is returned anyway
+                let mut entries = BTreeSet::new();
+                for eavi in self.candidates(&reader, scan, None)? {
+                    let eavi = eavi?;
+                    if query.retraction().keeps(self.is_retracted(&reader, &eavi)?) {
+                        entries.insert(eavi);
+                    }
+                }
+                return Ok(query.run_page(entries.into_iter()));
             }
         };
 
-        let entries_iter = entries.iter().cloned();
-        Ok(query.run(entries_iter))
+        // keep only the rows that could be in the page, plus one to tell if more follow
+        // scans that aren't in index order read every row of the scan, as do descending pages
+        // before their cursor, @see seek_to_cursor
+        let capacity = query.offset() + limit + 1;
+        let (in_order, (scan, seek)) = if Self::scans_in_order(&scan, query) {
+            (
+                query.order() == Order::Ascending,
+                Self::seek_to_cursor(scan, query),
+            )
+        } else {
+            (false, (scan, None))
+        };
+        let mut kept = BTreeSet::new();
+        for eavi in self.candidates(&reader, scan, seek)? {
+            let eavi = eavi?;
+            // rows after a full page can only be left out of it, unless they share the index
+            // of its last row, which rows of one entity can
+            let past_page = |last: &EntityAttributeValueIndex<A>| eavi.index() > last.index();
+            if in_order
+                && kept.len() == capacity
+                && kept.iter().next_back().map(past_page) == Some(true)
+            {
+                break;
+            }
+            if !self.keeps(&reader, query, &eavi)? {
+                continue;
+            }
+            kept.insert(eavi);
+            if kept.len() > capacity {
+                let evicted = match query.order() {
+                    Order::Ascending => kept.iter().next_back().cloned(),
+                    Order::Descending => kept.iter().next().cloned(),
+                };
+                if let Some(evicted) = evicted {
+                    kept.remove(&evicted);
+                }
+            }
+        }
+        Ok(query.page(kept))
+    }
+
+    fn retract_lmdb_eavi(
+        &mut self,
+        eav: &EntityAttributeValueIndex<A>,
+    ) -> Result<bool, StoreError> {
+        let retracted = self
+            .retracted_lmdb
+            .as_ref()
+            .expect("only read-only stores have no retraction store and they are never written");
+        write_txn(&self.lmdb.manager, |writer| {
+            let key = match key_in(&self.lmdb, writer, eav)? {
+                Some(key) => key,
+                None => return Ok(false),
+            };
+            if retracted.store.get(writer, &key)?.is_some() {
+                return Ok(false);
+            }
+            retracted
+                .store
+                .put(writer, &key, &Value::Json(&eav.content().to_string()))?;
+            Ok(true)
+        })
     }
 }
 
@@ -143,16 +833,113 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
-        self.add_lmdb_eavi(eav)
-            .map_err(|e| PersistenceError::from(format!("EAV add error: {}", e)))
+        self.lmdb.check_writable("EAV")?;
+        let stored = self
+            .add_lmdb_eavi(eav)
+            .map_err(|e| store_error(context("add_eavi").with_address(&eav.address()), e))?;
+        self.live.update(self, stored.as_ref().unwrap_or(eav));
+        Ok(stored)
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
-        self.fetch_lmdb_eavi(query)
-            .map_err(|e| PersistenceError::from(format!("EAV fetch error: {}", e)))
+        let page = self.fetch_eavi_page(query)?;
+        Ok(page.rows.into_iter().collect())
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        self.fetch_lmdb_eavi_page(query)
+            .map_err(|e| store_error(context("fetch_eavi_page"), e))
+    }
+
+    fn fetch_eavi_iter<'s>(
+        &'s self,
+        query: &'s EaviQuery<A>,
+    ) -> PersistenceResult<EaviIter<'s, A>> {
+        if !query.can_stream() {
+            let page = self.fetch_eavi_page(query)?;
+            return Ok(Box::new(page.rows.into_iter().map(Ok)));
+        }
+        Ok(Box::new(LmdbEaviIter {
+            storage: self,
+            query,
+            scan: self.plan(query),
+            batch: VecDeque::new(),
+            last_key: None,
+            done: false,
+        }))
+    }
+
+    fn count_eavi(&self, query: &EaviQuery<A>) -> PersistenceResult<usize> {
+        self.count_eavi_by(query, |_| ())
+            .map(|counts| counts.values().sum())
+    }
+
+    fn count_eavi_by_attribute(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<A, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::attribute)
+    }
+
+    fn count_eavi_by_entity(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<Address, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::entity)
+    }
+
+    fn traverse(&self, traversal: &Traversal<A>) -> PersistenceResult<Vec<TraversalStep<A>>> {
+        // every address is read from the one transaction, so the walk sees a single snapshot
+        let env = self
+            .lmdb
+            .manager
+            .read()
+            .map_err(|e| PersistenceError::poisoned(context("traverse"), &e))?;
+        let reader = env
+            .read()
+            .map_err(|e| store_error(context("traverse"), e))?;
+        let steps = traversal.run(|query| self.selected(&reader, query, "traverse")?.collect())?;
+        Ok(steps)
+    }
+
+    fn fetch_join(&self, query: &JoinQuery<A>) -> PersistenceResult<BTreeSet<Bindings<A>>> {
+        // as with traverse, every pattern reads the same snapshot
+        let env = self
+            .lmdb
+            .manager
+            .read()
+            .map_err(|e| PersistenceError::poisoned(context("fetch_join"), &e))?;
+        let reader = env
+            .read()
+            .map_err(|e| store_error(context("fetch_join"), e))?;
+        let answers = query.run(|query| self.selected(&reader, query, "fetch_join")?.collect())?;
+        Ok(answers)
+    }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        self.lmdb.check_writable("EAV")?;
+        let retracted = self
+            .retract_lmdb_eavi(eav)
+            .map_err(|e| store_error(context("retract_eavi").with_address(&eav.address()), e))?;
+        if retracted {
+            self.live.update(self, eav);
+        }
+        Ok(retracted)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
     }
 }
 
@@ -167,15 +954,28 @@ where
 
 #[cfg(test)]
 pub mod tests {
-    use crate::eav::lmdb::EavLmdbStorage;
+    use crate::{
+        common::write_txn,
+        eav::{
+            key,
+            lmdb::{EavLmdbStorage, Scan, Span},
+        },
+        env::LmdbEnvironment,
+    };
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
-            content::{AddressableContent, ExampleAddressableContent},
+            content::{Address, AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::{storage::EavBencher, Attribute, ExampleAttribute},
+        eav::{
+            storage::EavBencher, Attribute, EavFilter, EaviQuery, EntityAttributeValueIndex,
+            EntityAttributeValueStorage, ExampleAttribute, IndexFilter, LamportClock, Order,
+        },
+        error::PersistenceResult,
     };
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     #[test]
@@ -197,7 +997,10 @@ pub mod tests {
         )
     }
 
-    fn new_store<A: Attribute>() -> EavLmdbStorage<A> {
+    fn new_store<A>() -> EavLmdbStorage<A>
+    where
+        A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+    {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
         EavLmdbStorage::new(temp_path, None)
@@ -282,4 +1085,650 @@ pub mod tests {
         let eav_storage = EavLmdbStorage::new(temp_path, None);
         EavTestSuite::test_tombstone::<ExampleAddressableContent, EavLmdbStorage<_>>(eav_storage)
     }
+
+    #[test]
+    fn lmdb_eav_read_only() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_read_only(EavLmdbStorage::new(temp.path(), None), || {
+            EavLmdbStorage::new_read_only(temp.path()).unwrap()
+        });
+        assert!(
+            EavLmdbStorage::<ExampleAttribute>::new_read_only(temp.path().join("missing")).is_err()
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_id_is_persisted() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let other = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage: EavLmdbStorage<ExampleAttribute> = EavLmdbStorage::new(temp.path(), None);
+        assert!(eav_storage == EavLmdbStorage::new(temp.path(), None));
+        assert!(eav_storage == EavLmdbStorage::new_read_only(temp.path()).unwrap());
+        assert!(eav_storage != EavLmdbStorage::new(other.path(), None));
+        EavTestSuite::test_id(
+            eav_storage,
+            EavLmdbStorage::new(temp.path(), None),
+            EavLmdbStorage::new(other.path(), None),
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_retraction() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_retraction(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_pagination() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_pagination(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_fetch_iter() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_fetch_iter(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_counts() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_counts(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_key_filters() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_key_filters(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_traversal() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_traversal(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_join() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_join(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_range_tombstone() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_range_tombstone(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_source() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_source(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_clock() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_clock(
+            EavLmdbStorage::new(temp.path(), None).with_clock(Arc::new(LamportClock::new())),
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_collisions() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_collisions(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_merge() {
+        let a = tempdir().expect("test was supposed to create temp dir");
+        let b = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_merge(
+            EavLmdbStorage::new(a.path(), None),
+            EavLmdbStorage::new(b.path(), None),
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_live() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_live(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    #[test]
+    fn lmdb_eav_as_of() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_as_of(EavLmdbStorage::new(temp.path(), None));
+    }
+
+    fn query<'a>(
+        entity: Option<Address>,
+        attribute: Option<ExampleAttribute>,
+        value: Option<Address>,
+    ) -> EaviQuery<'a, ExampleAttribute> {
+        EaviQuery::new(
+            entity.into(),
+            attribute.into(),
+            value.into(),
+            IndexFilter::Range(None, None),
+            None,
+        )
+    }
+
+    /// a predicate filter matching the same as the exact filter from the option would
+    fn predicate<'a, T: Eq + 'a>(x: Option<T>) -> EavFilter<'a, T> {
+        match x {
+            Some(x) => EavFilter::multiple(vec![x]),
+            None => EavFilter::default(),
+        }
+    }
+
+    /// the same query with every filter a predicate, so it always scans the whole store
+    fn scanning_query<'a>(
+        entity: Option<Address>,
+        attribute: Option<ExampleAttribute>,
+        value: Option<Address>,
+    ) -> EaviQuery<'a, ExampleAttribute> {
+        EaviQuery::new(
+            predicate(entity),
+            predicate(attribute),
+            predicate(value),
+            IndexFilter::Range(None, None),
+            None,
+        )
+    }
+
+    #[test]
+    fn lmdb_eav_plans_most_selective_scan() {
+        let eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let (entity, value): (Address, Address) = ("entity".into(), "value".into());
+        let attribute = ExampleAttribute::WithPayload("link".into());
+
+        let plan = |entity: Option<&Address>, attribute: Option<&ExampleAttribute>, value| {
+            eav_storage.plan(&query(entity.cloned(), attribute.cloned(), value))
+        };
+        assert_eq!(
+            Scan::Vea(key::vea_prefix(&value, Some(&entity))),
+            plan(Some(&entity), Some(&attribute), Some(value.clone()))
+        );
+        assert_eq!(
+            Scan::Aev(key::aev_prefix(&attribute, Some(&entity))),
+            plan(Some(&entity), Some(&attribute), None)
+        );
+        assert_eq!(
+            Scan::Entity(entity.clone()),
+            plan(Some(&entity), None, None)
+        );
+        assert_eq!(
+            Scan::Vea(key::vea_prefix(&value, None)),
+            plan(None, Some(&attribute), Some(value.clone()))
+        );
+        assert_eq!(
+            Scan::Aev(key::aev_prefix(&attribute, None)),
+            plan(None, Some(&attribute), None)
+        );
+        assert_eq!(Scan::All, plan(None, None, None));
+        assert_eq!(
+            Scan::All,
+            eav_storage.plan(&scanning_query(Some(entity), Some(attribute), Some(value)))
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_plans_key_range_scans() {
+        let eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let plan = |entity, attribute: Option<ExampleAttribute>, value| {
+            eav_storage.plan(&EaviQuery::new(
+                entity,
+                attribute.into(),
+                value,
+                IndexFilter::Range(None, None),
+                None,
+            ))
+        };
+        let span = |start: &str, end: Option<&str>| Span {
+            start: start.as_bytes().to_vec(),
+            end: end.map(|end| end.as_bytes().to_vec()),
+        };
+        let attribute = ExampleAttribute::WithPayload("link".into());
+
+        assert_eq!(
+            Scan::Entities(span("Qm", Some("Qn"))),
+            plan(EavFilter::prefix("Qm"), None, EavFilter::prefix("Qx"))
+        );
+        assert_eq!(
+            Scan::Entities(span("Qma", Some("Qmb"))),
+            plan(
+                EavFilter::range(Some("Qma".into()), Some("Qmb".into())),
+                None,
+                EavFilter::default()
+            )
+        );
+        // keys of entities before "Qm:b" can come after it, such as "Qm::0"
+        assert_eq!(
+            Scan::Entities(span("", Some("Qn"))),
+            plan(
+                EavFilter::range(None, Some("Qm:b".into())),
+                None,
+                EavFilter::default()
+            )
+        );
+        assert_eq!(
+            Scan::Values(span("Qm", Some("Qn"))),
+            plan(
+                EavFilter::default(),
+                Some(attribute.clone()),
+                EavFilter::prefix("Qm")
+            )
+        );
+        assert_eq!(
+            Scan::Values(span("", Some("Qmb"))),
+            plan(
+                EavFilter::default(),
+                None,
+                EavFilter::range(None, Some("Qmb".into()))
+            )
+        );
+        // exact filters are still preferred
+        assert_eq!(
+            Scan::Vea(key::vea_prefix(&"value".into(), None)),
+            plan(
+                EavFilter::prefix("Qm"),
+                None,
+                EavFilter::single("value".into())
+            )
+        );
+        assert_eq!(
+            Scan::Entity("entity".into()),
+            plan(
+                EavFilter::single("entity".into()),
+                None,
+                EavFilter::prefix("Qm")
+            )
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_plans_source_scans() {
+        let eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let plan = |entity, value, source| {
+            eav_storage.plan(
+                &EaviQuery::new(
+                    entity,
+                    EavFilter::default(),
+                    value,
+                    IndexFilter::Range(None, None),
+                    None,
+                )
+                .with_source(source),
+            )
+        };
+        let author: Address = "author".into();
+
+        assert_eq!(
+            Scan::Source(key::source_prefix(&author)),
+            plan(
+                EavFilter::prefix("Qm"),
+                EavFilter::default(),
+                EavFilter::single(Some(author.clone()))
+            )
+        );
+        // rows without a source aren't in the index
+        assert_eq!(
+            Scan::All,
+            plan(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::single(None)
+            )
+        );
+        assert_eq!(
+            Scan::Vea(key::vea_prefix(&"value".into(), None)),
+            plan(
+                EavFilter::default(),
+                EavFilter::single("value".into()),
+                EavFilter::single(Some(author))
+            )
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_plans_index_order_scans() {
+        let eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let attribute = ExampleAttribute::WithPayload("link".into());
+        let tombstone = ExampleAttribute::WithPayload("removed".into());
+        let plan = |entity: Option<Address>, index, tombstone, as_of| {
+            let mut query = EaviQuery::new(
+                entity.into(),
+                Some(attribute.clone()).into(),
+                EavFilter::default(),
+                index,
+                tombstone,
+            );
+            query.as_of = as_of;
+            eav_storage.plan(&query)
+        };
+        let prefix = key::aev_prefix(&attribute, Some(&"entity".into()));
+
+        assert_eq!(
+            Scan::AevUntil(prefix.clone(), 5),
+            plan(
+                Some("entity".into()),
+                IndexFilter::LatestByAttribute,
+                None,
+                Some(5)
+            )
+        );
+        assert_eq!(
+            Scan::AevUntil(prefix.clone(), 3),
+            plan(
+                Some("entity".into()),
+                IndexFilter::Range(None, Some(3)),
+                None,
+                Some(5)
+            )
+        );
+        assert_eq!(
+            Scan::Aev(prefix),
+            plan(
+                Some("entity".into()),
+                IndexFilter::LatestByAttribute,
+                None,
+                None
+            )
+        );
+
+        let plan = |index, tombstone, as_of| {
+            let mut query = EaviQuery::new(
+                EavFilter::default(),
+                EavFilter::default(),
+                EavFilter::default(),
+                index,
+                tombstone,
+            );
+            query.as_of = as_of;
+            eav_storage.plan(&query)
+        };
+        assert_eq!(
+            Scan::Indexes(Span {
+                start: Vec::new(),
+                end: key::index_end("", 5),
+            }),
+            plan(IndexFilter::LatestByAttribute, None, Some(5))
+        );
+        assert_eq!(
+            Scan::Indexes(Span {
+                start: key::encode_index(2).into_bytes(),
+                end: key::index_end("", 5),
+            }),
+            plan(IndexFilter::Range(Some(2), Some(7)), None, Some(5))
+        );
+        // tombstones before the range still count
+        assert_eq!(
+            Scan::Indexes(Span {
+                start: Vec::new(),
+                end: key::index_end("", 7),
+            }),
+            plan(
+                IndexFilter::Range(Some(2), Some(7)),
+                Some(EavFilter::single(tombstone)),
+                None
+            )
+        );
+        assert_eq!(Scan::All, plan(IndexFilter::LatestByAttribute, None, None));
+    }
+
+    #[test]
+    fn lmdb_eav_index_order_scans_match_full_scans() {
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let attributes = [
+            ExampleAttribute::default(),
+            ExampleAttribute::WithPayload("removed".into()),
+        ];
+        for index in -10..30 {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &format!("entity {}", index % 3).into(),
+                &attributes[(index % 5 == 0) as usize],
+                &format!("value {}", index % 4).into(),
+                index,
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap();
+        }
+        // stores written before the index order index existed have it built when opened
+        let index = eav_storage.indexes().index.clone().unwrap();
+        write_txn(&eav_storage.lmdb.manager, |writer| {
+            index.store.clear(writer)
+        })
+        .unwrap();
+        eav_storage.index_if_unindexed().unwrap();
+
+        for as_of in &[-11, -3, 0, 12, 29] {
+            for index in &[
+                IndexFilter::LatestByAttribute,
+                IndexFilter::Range(None, Some(5)),
+                IndexFilter::Range(Some(-5), None),
+            ] {
+                for tombstone in &[None, Some(attributes[1].clone())] {
+                    let query = |entity: EavFilter<'static, Address>| {
+                        let mut query = EaviQuery::new(
+                            entity,
+                            EavFilter::default(),
+                            EavFilter::default(),
+                            index.clone(),
+                            tombstone.clone().map(EavFilter::single),
+                        );
+                        query.as_of = Some(*as_of);
+                        query
+                    };
+                    let indexed = query(EavFilter::default());
+                    assert!(match eav_storage.plan(&indexed) {
+                        Scan::Indexes(_) => true,
+                        _ => false,
+                    });
+                    assert_eq!(
+                        eav_storage
+                            .fetch_eavi(&query(EavFilter::predicate(|_| true)))
+                            .unwrap(),
+                        eav_storage.fetch_eavi(&indexed).unwrap()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lmdb_eav_pages_of_index_order_scans_match_full_scans() {
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let attribute = ExampleAttribute::default();
+        for index in -10..30 {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &format!("entity {}", index % 2).into(),
+                &attribute,
+                &format!("value {}", index % 3).into(),
+                // rows of different entities share indexes
+                index / 2,
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap();
+        }
+        let in_order = |query: &EaviQuery<ExampleAttribute>| {
+            EavLmdbStorage::scans_in_order(&eav_storage.plan(query), query)
+        };
+        let pages = |query: &dyn Fn() -> EaviQuery<'static, ExampleAttribute>| {
+            let mut rows = Vec::new();
+            let mut page = eav_storage.fetch_eavi_page(&query()).unwrap();
+            rows.extend(page.rows);
+            while let Some(cursor) = page.next {
+                page = eav_storage.fetch_eavi_page(&query().after(cursor)).unwrap();
+                rows.extend(page.rows);
+            }
+            rows
+        };
+
+        for order in &[Order::Ascending, Order::Descending] {
+            let query = |entity: EavFilter<'static, Address>,
+                         attribute: EavFilter<'static, ExampleAttribute>| {
+                EaviQuery::new(
+                    entity,
+                    attribute,
+                    EavFilter::default(),
+                    IndexFilter::Range(Some(-3), Some(12)),
+                    None,
+                )
+                .with_order(*order)
+                .with_limit(3)
+            };
+            let indexed = || query(EavFilter::default(), EavFilter::default());
+            assert!(in_order(&indexed()));
+            let full = || query(EavFilter::predicate(|_| true), EavFilter::default());
+            assert_eq!(pages(&full), pages(&indexed));
+
+            let aev = || {
+                query(
+                    Some(Address::from("entity 0")).into(),
+                    Some(attribute.clone()).into(),
+                )
+            };
+            assert!(in_order(&aev()));
+            let full = || {
+                query(
+                    EavFilter::predicate(|entity| entity == Address::from("entity 0")),
+                    Some(attribute.clone()).into(),
+                )
+            };
+            assert_eq!(pages(&full), pages(&aev));
+        }
+    }
+
+    #[test]
+    fn lmdb_eav_index_scans_match_full_scans() {
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let entities: [Address; 3] = ["a".into(), "ab".into(), "b".into()];
+        let values: [Address; 2] = ["x".into(), "xy".into()];
+        let attributes = [
+            ExampleAttribute::WithPayload("link".into()),
+            ExampleAttribute::WithPayload("lin".into()),
+            ExampleAttribute::WithPayload("x".repeat(1000)),
+        ];
+        let mut index = -5;
+        for entity in entities.iter() {
+            for attribute in attributes.iter() {
+                for value in values.iter() {
+                    let eavi =
+                        EntityAttributeValueIndex::new_with_index(entity, attribute, value, index)
+                            .unwrap();
+                    eav_storage.add_eavi(&eavi).unwrap();
+                    index += 1;
+                }
+            }
+        }
+
+        let options = |xs: &[Address]| {
+            let mut options: Vec<Option<Address>> = xs.iter().cloned().map(Some).collect();
+            options.push(None);
+            options
+        };
+        let mut attribute_options: Vec<Option<ExampleAttribute>> =
+            attributes.iter().cloned().map(Some).collect();
+        attribute_options.push(None);
+        for entity in options(&entities) {
+            for attribute in attribute_options.iter() {
+                for value in options(&values) {
+                    let indexed = eav_storage
+                        .fetch_eavi(&query(entity.clone(), attribute.clone(), value.clone()))
+                        .unwrap();
+                    let scanned = eav_storage
+                        .fetch_eavi(&scanning_query(
+                            entity.clone(),
+                            attribute.clone(),
+                            value.clone(),
+                        ))
+                        .unwrap();
+                    assert!(!indexed.is_empty());
+                    assert_eq!(scanned, indexed);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lmdb_eav_indexes_stores_written_without_them() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        let mut eav = env.eav::<ExampleAttribute>("eav").unwrap();
+        let eavi = EntityAttributeValueIndex::new(
+            &"entity".into(),
+            &ExampleAttribute::default(),
+            &"value".into(),
+        )
+        .unwrap();
+        eav.add_eavi(&eavi).unwrap();
+
+        // empty the indexes, as if the eavi was written before they existed
+        let indexes = eav.indexes.clone().unwrap();
+        write_txn(&eav.lmdb.manager, |writer| {
+            indexes.aev.store.clear(writer)?;
+            indexes.vea.store.clear(writer)
+        })
+        .unwrap();
+        let by_value = query(None, None, Some(eavi.value()));
+        assert!(eav.fetch_eavi(&by_value).unwrap().is_empty());
+
+        let eav = env.eav::<ExampleAttribute>("eav").unwrap();
+        assert_eq!(
+            vec![eavi.clone()],
+            eav.fetch_eavi(&by_value)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            1,
+            eav.fetch_eavi(&query(None, Some(eavi.attribute()), None))
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn lmdb_eav_fetch_iter_reads_across_batches() {
+        let mut eav_storage: EavLmdbStorage<ExampleAttribute> = new_store();
+        let entities: [Address; 2] = ["even".into(), "odd".into()];
+        let rows = 2 * super::ITER_BATCH + 10;
+        for index in 0..rows {
+            let eavi = EntityAttributeValueIndex::new_with_index(
+                &entities[index % 2],
+                &ExampleAttribute::default(),
+                &"value".into(),
+                index as i64,
+            )
+            .unwrap();
+            eav_storage.add_eavi(&eavi).unwrap();
+        }
+
+        let count = |query: EaviQuery<ExampleAttribute>| {
+            eav_storage
+                .fetch_eavi_iter(&query)
+                .unwrap()
+                .collect::<PersistenceResult<BTreeSet<_>>>()
+                .unwrap()
+                .len()
+        };
+        assert_eq!(rows, count(query(None, None, None)));
+        assert_eq!(
+            rows / 2,
+            count(query(Some(entities[0].clone()), None, None))
+        );
+        assert_eq!(
+            rows / 2,
+            count(query(
+                Some(entities[1].clone()),
+                Some(ExampleAttribute::default()),
+                None
+            ))
+        );
+        assert_eq!(rows, count(query(None, None, Some("value".into()))));
+    }
 }
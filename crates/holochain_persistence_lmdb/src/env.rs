@@ -0,0 +1,455 @@
+//! A single LMDB environment holding several named stores.
+//!
+//! Every `LmdbStorage` and `EavLmdbStorage` built with `new` opens an environment (and a memory
+//! map) of its own. An `LmdbEnvironment` instead opens one environment and hands out typed CAS
+//! and EAV handles onto named databases inside it, so a CAS, an EAV and any auxiliary stores can
+//! share a single memory map and directory.
+//!
+//! Environments are shared through the rkv `Manager`, so opening the same path twice gives
+//! handles onto the same environment. The settings (map size, max_dbs) of whichever open came
+//! first are the ones in effect. LMDB allows a process only one handle on an environment, so
+//! one first opened read-only can't be opened for writing until the process restarts.
+
+use crate::{
+    cas::lmdb::LmdbStorage,
+    common::{context, store_error, write_txn, LmdbInstance},
+    eav::lmdb::{EavIndexes, EavLmdbStorage},
+    txn::LmdbTransactionalStorage,
+};
+use holochain_persistence_api::{
+    eav::Attribute,
+    error::{PersistenceError, PersistenceResult},
+};
+use lazy_static::lazy_static;
+use rkv::{DataError, EnvironmentFlags, Manager, Rkv, StoreError, Value};
+use std::{
+    collections::HashSet,
+    fmt::{Debug, Error, Formatter},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use uuid::Uuid;
+
+pub const DEFAULT_INITIAL_MAP_BYTES: usize = 100 * 1024 * 1024;
+/// enough for a CAS, an EAV and their auxiliary stores
+pub const DEFAULT_MAX_DBS: u32 = 16;
+/// holds the ids of the named stores in the environment, keyed by `<name>::id`
+const META_STORE: &str = "meta";
+
+lazy_static! {
+    /// the canonical paths of the environments the Manager holds open read-only
+    static ref READ_ONLY_PATHS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+#[derive(Clone)]
+pub struct LmdbEnvironment {
+    path: PathBuf,
+    manager: Arc<RwLock<Rkv>>,
+    read_only: bool,
+}
+
+impl Debug for LmdbEnvironment {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_struct("LmdbEnvironment")
+            .field("path", &self.path)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+impl LmdbEnvironment {
+    /// opens (creating if needed) the environment in the given directory
+    /// max_dbs is the maximum number of named stores the environment can hold
+    /// panics if the environment can't be opened, @see try_new
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        initial_map_bytes: Option<usize>,
+        max_dbs: Option<u32>,
+    ) -> LmdbEnvironment {
+        LmdbEnvironment::try_new(path, initial_map_bytes, max_dbs)
+            .expect("Could not create the environment")
+    }
+
+    /// opens (creating if needed) the environment in the given directory, failing if this
+    /// process already has it open read-only
+    pub fn try_new<P: AsRef<Path>>(
+        path: P,
+        initial_map_bytes: Option<usize>,
+        max_dbs: Option<u32>,
+    ) -> PersistenceResult<LmdbEnvironment> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::create_dir_all(path.clone())
+            .map_err(|e| PersistenceError::io(context("open"), &e))?;
+
+        let mut environments = Manager::singleton()
+            .write()
+            .map_err(|e| PersistenceError::poisoned(context("open"), &e))?;
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| PersistenceError::io(context("open"), &e))?;
+        if READ_ONLY_PATHS
+            .lock()
+            .map_err(|e| PersistenceError::poisoned(context("open"), &e))?
+            .contains(&canonical)
+        {
+            return Err(PersistenceError::ReadOnlyError(
+                context("open").with_message(format!(
+                    "the LMDB environment at {} is already open read-only in this process",
+                    path.display()
+                )),
+            ));
+        }
+        let manager = environments
+            .get_or_create(path.as_path(), |path: &Path| {
+                let mut env_builder = Rkv::environment_builder();
+                env_builder
+                    // max size of memory map, can be changed later
+                    .set_map_size(initial_map_bytes.unwrap_or(DEFAULT_INITIAL_MAP_BYTES))
+                    // max number of DBs in this environment
+                    .set_max_dbs(max_dbs.unwrap_or(DEFAULT_MAX_DBS))
+                    // Thes flags make writes waaaaay faster by async writing to disk rather than blocking
+                    // There is some loss of data integrity guarantees that comes with this
+                    .set_flags(EnvironmentFlags::WRITE_MAP | EnvironmentFlags::MAP_ASYNC);
+                Rkv::from_env(path, env_builder)
+            })
+            .map_err(|e| store_error(context("open"), e))?;
+
+        Ok(LmdbEnvironment {
+            path,
+            manager,
+            read_only: false,
+        })
+    }
+
+    /// opens an existing environment with LMDB's read-only flag
+    /// stores opened from it must already exist and refuse every write with a ReadOnlyError
+    /// if this process already has the environment open for writing that environment is shared,
+    /// otherwise it is opened read-only through the Manager and later opens for writing fail
+    pub fn new_read_only<P: AsRef<Path>>(
+        path: P,
+        max_dbs: Option<u32>,
+    ) -> PersistenceResult<LmdbEnvironment> {
+        let path = path.as_ref().to_path_buf();
+        if !path.is_dir() {
+            return Err(PersistenceError::NotFound(context("open").with_message(
+                format!("no LMDB environment at {}", path.display()),
+            )));
+        }
+
+        let manager = Manager::singleton()
+            .write()
+            .map_err(|e| PersistenceError::poisoned(context("open"), &e))?
+            .get_or_create(path.as_path(), |path: &Path| {
+                let mut env_builder = Rkv::environment_builder();
+                env_builder
+                    .set_max_dbs(max_dbs.unwrap_or(DEFAULT_MAX_DBS))
+                    .set_flags(EnvironmentFlags::READ_ONLY);
+                let env = Rkv::from_env(path, env_builder)?;
+                // the path is canonical here, as the Manager keys it, and an insert can't leave
+                // the set half updated, so a poisoned lock is still safe to use
+                READ_ONLY_PATHS
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(path.to_path_buf());
+                Ok(env)
+            })
+            .map_err(|e| store_error(context("open"), e))?;
+
+        Ok(LmdbEnvironment {
+            path,
+            manager,
+            read_only: true,
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// a CAS handle onto the store with the given name
+    /// content added with a ttl keeps its expiry times in `<name>_ttl`
+    pub fn cas(&self, name: &str) -> PersistenceResult<LmdbStorage> {
+        let lmdb = self.open(name)?;
+        let ttl_lmdb = self.auxiliary(&format!("{}_ttl", name))?;
+        Ok(LmdbStorage::from_instances(
+            lmdb,
+            ttl_lmdb,
+            self.store_id(name)?,
+        ))
+    }
+
+    /// an EAV handle onto the store with the given name
+    /// retracted eavis are kept in `<name>_retracted` and the attribute and value indexes in
+    /// `<name>_aev` and `<name>_vea`, which are built on first open for stores without them,
+    /// the source index in `<name>_sea` and the index order index in `<name>_iea`, which is
+    /// also built on first open
+    pub fn eav<A>(&self, name: &str) -> PersistenceResult<EavLmdbStorage<A>>
+    where
+        A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+    {
+        let lmdb = self.open(name)?;
+        let retracted_lmdb = self.auxiliary(&format!("{}_retracted", name))?;
+        let indexes = match (
+            self.auxiliary(&format!("{}_aev", name))?,
+            self.auxiliary(&format!("{}_vea", name))?,
+        ) {
+            (Some(aev), Some(vea)) => Some(EavIndexes {
+                aev,
+                vea,
+                source: self.auxiliary(&format!("{}_sea", name))?,
+                index: self.auxiliary(&format!("{}_iea", name))?,
+            }),
+            _ => None,
+        };
+        let eav =
+            EavLmdbStorage::from_instances(lmdb, retracted_lmdb, indexes, self.store_id(name)?);
+        if !self.read_only {
+            eav.index_if_unindexed()
+                .map_err(|e| store_error(context("index").with_message(name.to_string()), e))?;
+        }
+        Ok(eav)
+    }
+
+    /// CAS and EAV handles onto the named stores that are written together atomically
+    pub fn transactional<A>(
+        &self,
+        cas_name: &str,
+        eav_name: &str,
+    ) -> PersistenceResult<LmdbTransactionalStorage<A>>
+    where
+        A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+    {
+        LmdbTransactionalStorage::new(self.cas(cas_name)?, self.eav(eav_name)?)
+    }
+
+    pub fn info(&self) -> PersistenceResult<rkv::Info> {
+        self.manager
+            .read()
+            .unwrap()
+            .info()
+            .map_err(|e| store_error(context("info"), e))
+    }
+
+    pub(crate) fn manager(&self) -> Arc<RwLock<Rkv>> {
+        self.manager.clone()
+    }
+
+    /// the id of the named store, persisted in the meta store the first time it is asked for
+    /// read-only environments without one give an id that lasts as long as the handle
+    pub(crate) fn store_id(&self, name: &str) -> PersistenceResult<Uuid> {
+        let key = format!("{}::id", name);
+        let read_id = |value: Option<Value>| match value {
+            Some(Value::Str(id)) => Uuid::parse_str(id)
+                .map(Some)
+                .map_err(|_| StoreError::DataError(DataError::InvalidUuid)),
+            Some(_) => Err(StoreError::DataError(DataError::InvalidUuid)),
+            None => Ok(None),
+        };
+        let id_error = |e: StoreError| {
+            store_error(
+                context("store_id").with_message(format!("Could not get id of {}", name)),
+                e,
+            )
+        };
+
+        if self.read_only {
+            let meta = match self.open(META_STORE) {
+                Ok(meta) => meta,
+                Err(_) => return Ok(Uuid::new_v4()),
+            };
+            let env = self
+                .manager
+                .read()
+                .map_err(|e| PersistenceError::poisoned(context("store_id"), &e))?;
+            let reader = env.read().map_err(id_error)?;
+            let id = meta
+                .store
+                .get(&reader, &key)
+                .and_then(read_id)
+                .map_err(id_error)?;
+            return Ok(id.unwrap_or_else(Uuid::new_v4));
+        }
+
+        let meta = self.open(META_STORE)?;
+        write_txn(&self.manager, |writer| {
+            match read_id(meta.store.get(writer, &key)?)? {
+                Some(id) => Ok(id),
+                None => {
+                    let id = Uuid::new_v4();
+                    meta.store.put(writer, &key, &Value::Str(&id.to_string()))?;
+                    Ok(id)
+                }
+            }
+        })
+        .map_err(id_error)
+    }
+
+    /// opens a store kept alongside a CAS or EAV
+    /// read-only environments may have been written before the store existed and get None
+    fn auxiliary(&self, name: &str) -> PersistenceResult<Option<LmdbInstance>> {
+        if self.read_only {
+            Ok(self.open(name).ok())
+        } else {
+            self.open(name).map(Some)
+        }
+    }
+
+    pub(crate) fn open(&self, name: &str) -> PersistenceResult<LmdbInstance> {
+        LmdbInstance::open(self, name).map_err(|e: StoreError| {
+            store_error(
+                context("open").with_message(format!("Could not open store {}", name)),
+                e,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::{
+            content::{AddressableContent, Content},
+            storage::ContentAddressableStorage,
+        },
+        eav::{
+            EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage, ExampleAttribute,
+        },
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn named_stores_share_one_environment() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+
+        let mut cas = env.cas("cas").unwrap();
+        let mut other_cas = env.cas("other_cas").unwrap();
+        let mut eav = env.eav::<ExampleAttribute>("eav").unwrap();
+
+        let content = Content::from(RawString::from("foo"));
+        cas.add(&content).unwrap();
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        // named stores are kept apart
+        assert_eq!(Ok(None), other_cas.fetch(&content.address()));
+        other_cas.add(&content).unwrap();
+        assert_eq!(
+            Ok(Some(content.clone())),
+            other_cas.fetch(&content.address())
+        );
+
+        let eavi = EntityAttributeValueIndex::new(
+            &content.address(),
+            &ExampleAttribute::default(),
+            &content.address(),
+        )
+        .unwrap();
+        eav.add_eavi(&eavi).unwrap();
+        assert_eq!(1, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+
+        // everything lives in the one environment directory
+        assert!(dir.path().join("data.mdb").exists());
+
+        // reopening the same path gives handles onto the same data
+        let reopened = LmdbEnvironment::new(dir.path(), None, None);
+        assert_eq!(
+            Ok(Some(content.clone())),
+            reopened.cas("cas").unwrap().fetch(&content.address())
+        );
+    }
+
+    #[test]
+    fn opening_more_stores_than_max_dbs_is_an_error() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, Some(9));
+
+        // a cas handle takes two stores, one for content and one for expiry times, an eav
+        // handle six, for eavis, retractions and four indexes, and the ids of all stores are
+        // kept in one more
+        assert!(env.cas("cas").is_ok());
+        assert!(env.eav::<ExampleAttribute>("eav").is_ok());
+        assert!(env.eav::<ExampleAttribute>("one_too_many").is_err());
+    }
+
+    #[test]
+    fn read_only_environment_opens_stores_written_elsewhere() {
+        let dir = tempdir().unwrap();
+        let content = Content::from(RawString::from("foo"));
+        {
+            // written outside the Manager, as another process would, and without a ttl store
+            let mut env_builder = Rkv::environment_builder();
+            env_builder.set_max_dbs(1);
+            let env = Rkv::from_env(dir.path(), env_builder).unwrap();
+            let store = env.open_single("cas", rkv::StoreOptions::create()).unwrap();
+            let mut writer = env.write().unwrap();
+            store
+                .put(
+                    &mut writer,
+                    content.address(),
+                    &rkv::Value::Json(&content.content().to_string()),
+                )
+                .unwrap();
+            writer.commit().unwrap();
+        }
+
+        let env = LmdbEnvironment::new_read_only(dir.path(), None).unwrap();
+        assert!(env.is_read_only());
+        let mut cas = env.cas("cas").unwrap();
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        match cas.add(&Content::from(RawString::from("bar"))) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+        // stores are never created in a read-only environment
+        match env.eav::<ExampleAttribute>("eav") {
+            Err(PersistenceError::NotFound(_)) => (),
+            result => panic!("expected a not found error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn opening_for_writing_after_read_only_is_an_error() {
+        let dir = tempdir().unwrap();
+        {
+            // created outside the Manager, as another process would
+            let mut env_builder = Rkv::environment_builder();
+            env_builder.set_max_dbs(DEFAULT_MAX_DBS);
+            let env = Rkv::from_env(dir.path(), env_builder).unwrap();
+            env.open_single("cas", rkv::StoreOptions::create()).unwrap();
+        }
+
+        let read_only = LmdbEnvironment::new_read_only(dir.path(), None).unwrap();
+        match LmdbEnvironment::try_new(dir.path(), None, None) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+        // opening it read-only again shares the environment
+        let shared = LmdbEnvironment::new_read_only(dir.path(), None).unwrap();
+        assert!(Arc::ptr_eq(&read_only.manager(), &shared.manager()));
+    }
+
+    #[test]
+    fn opening_read_only_after_writing_shares_the_environment() {
+        let dir = tempdir().unwrap();
+        let content = Content::from(RawString::from("foo"));
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        env.cas("cas").unwrap().add(&content).unwrap();
+
+        let shared = LmdbEnvironment::new_read_only(dir.path(), None).unwrap();
+        assert!(Arc::ptr_eq(&env.manager(), &shared.manager()));
+        let mut shared_cas = shared.cas("cas").unwrap();
+        assert_eq!(
+            Ok(Some(content.clone())),
+            shared_cas.fetch(&content.address())
+        );
+        match shared_cas.add(&content) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+    }
+}
@@ -12,3 +12,7 @@ extern crate test;
 pub mod cas;
 mod common;
 pub mod eav;
+pub mod env;
+#[cfg(feature = "registry")]
+pub mod registry;
+pub mod txn;
@@ -0,0 +1,106 @@
+//! Registers the LMDB stores under the `lmdb://` scheme.
+//! The URI path is the directory the environment lives in, e.g.
+//! `lmdb:///data?initial_map_bytes=104857600`, and `initial_map_bytes` is passed on to `new`.
+//! Setting `read_only=true` opens an existing store with `new_read_only`.
+
+use crate::{cas::lmdb::LmdbStorage, eav::lmdb::EavLmdbStorage};
+use holochain_persistence_api::{
+    cas::storage::ContentAddressableStorage,
+    eav::{Attribute, EntityAttributeValueStorage},
+    registry::{Registry, StoreConfig},
+};
+
+pub const SCHEME: &str = "lmdb";
+pub const INITIAL_MAP_BYTES: &str = "initial_map_bytes";
+
+pub fn register<A>(registry: &mut Registry<A>)
+where
+    A: Attribute + Send + Sync + serde::de::DeserializeOwned + 'static,
+{
+    registry
+        .register_cas(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let cas = if config.read_only()? {
+                LmdbStorage::new_read_only(path)?
+            } else {
+                LmdbStorage::new(path, config.param(INITIAL_MAP_BYTES)?)
+            };
+            Ok(Box::new(cas) as Box<dyn ContentAddressableStorage>)
+        })
+        .register_eav(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let eav = if config.read_only()? {
+                EavLmdbStorage::new_read_only(path)?
+            } else {
+                EavLmdbStorage::new(path, config.param(INITIAL_MAP_BYTES)?)
+            };
+            Ok(Box::new(eav) as Box<dyn EntityAttributeValueStorage<A>>)
+        });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, Content},
+        eav::{EaviQuery, EntityAttributeValueIndex, ExampleAttribute},
+        error::PersistenceError,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn lmdb_stores_open_from_uris() {
+        let dir = tempdir().unwrap();
+        let mut registry = Registry::<ExampleAttribute>::new();
+        register(&mut registry);
+        let config = StoreConfig::new(SCHEME)
+            .with_path(dir.path())
+            .with_param(INITIAL_MAP_BYTES, 1024 * 1024);
+
+        let content = Content::from(RawString::from("foo"));
+        let mut cas = registry.open_cas_uri(&config.to_string()).unwrap();
+        cas.add(&content).unwrap();
+        let mut eav = registry.open_eav(&config).unwrap();
+        eav.add_eavi(
+            &EntityAttributeValueIndex::new(
+                &content.address(),
+                &ExampleAttribute::default(),
+                &content.address(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let mut read_only = registry
+            .open_cas(
+                &StoreConfig::new(SCHEME)
+                    .with_path(dir.path())
+                    .with_param("read_only", true),
+            )
+            .unwrap();
+        assert_eq!(cas.get_id(), read_only.get_id());
+        assert_eq!(
+            Ok(Some(content.clone())),
+            read_only.fetch(&content.address())
+        );
+        match read_only.add(&content) {
+            Err(PersistenceError::ReadOnlyError(_)) => (),
+            result => panic!("expected a read-only error, got {:?}", result),
+        }
+        let read_only_eav = registry
+            .open_eav_uri(&format!("lmdb://{}?read_only=true", dir.path().display()))
+            .unwrap();
+        assert_eq!(
+            1,
+            read_only_eav
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .len()
+        );
+
+        assert!(registry
+            .open_cas_uri("lmdb:///data?initial_map_bytes=lots")
+            .is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Atomic transactions across a CAS and an EAV kept in the same LMDB environment.
+//! All staged content and metadata is written in a single LMDB write transaction, so either
+//! everything is stored or nothing is.
+
+use crate::{
+    cas::lmdb::LmdbStorage,
+    common::{context, store_error, write_txn},
+    eav::lmdb::EavLmdbStorage,
+};
+use holochain_persistence_api::{
+    eav::{Attribute, EntityAttributeValueIndex, EntityAttributeValueStorage},
+    error::{PersistenceError, PersistenceResult},
+    txn::{StagedWrites, TransactionalStorage},
+};
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct LmdbTransactionalStorage<A: Attribute> {
+    cas: LmdbStorage,
+    eav: EavLmdbStorage<A>,
+}
+
+impl<A> LmdbTransactionalStorage<A>
+where
+    A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+{
+    /// the stores must have been opened from the same LmdbEnvironment
+    /// stores created with their own `new` each have an environment of their own and can only
+    /// be used together through a JournalStorage
+    pub fn new(
+        cas: LmdbStorage,
+        eav: EavLmdbStorage<A>,
+    ) -> PersistenceResult<LmdbTransactionalStorage<A>> {
+        if !Arc::ptr_eq(cas.manager(), eav.manager()) {
+            return Err(PersistenceError::ErrorGeneric(
+                "CAS and EAV must share an LMDB environment for atomic transactions".to_string(),
+            ));
+        }
+        Ok(LmdbTransactionalStorage { cas, eav })
+    }
+
+    pub fn cas(&self) -> &LmdbStorage {
+        &self.cas
+    }
+
+    pub fn eav(&self) -> &EavLmdbStorage<A> {
+        &self.eav
+    }
+}
+
+impl<A> TransactionalStorage<A> for LmdbTransactionalStorage<A>
+where
+    A: Attribute + Sync + Send + serde::de::DeserializeOwned,
+{
+    fn commit_writes(
+        &mut self,
+        writes: StagedWrites<A>,
+    ) -> PersistenceResult<Vec<EntityAttributeValueIndex<A>>> {
+        self.cas.check_writable()?;
+        self.eav.check_writable()?;
+        let stored: Vec<_> = write_txn(self.cas.manager(), |writer| {
+            for content in writes.contents.iter() {
+                self.cas.put_content(writer, content)?;
+            }
+            writes
+                .eavis
+                .iter()
+                .map(|eavi| self.eav.put_eavi(writer, eavi))
+                .collect()
+        })
+        .map_err(|e| store_error(context("commit"), e))?;
+        let clock = self.eav.clock();
+        for eavi in stored.iter() {
+            clock.observe(eavi.index());
+        }
+        // the eavis bypass add_eavi, so their live queries are updated once they are committed
+        if let Some(live) = self.eav.live_registry() {
+            for eavi in stored.iter() {
+                live.update(&self.eav, eavi);
+            }
+        }
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::env::LmdbEnvironment;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::{
+            content::{Address, AddressableContent, Content},
+            storage::ContentAddressableStorage,
+        },
+        eav::{EaviQuery, EaviQuerySpec, ExampleAttribute, LiveEavStorage},
+        txn::TransactionTestSuite,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn lmdb_commit_and_rollback() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        let storage = env.transactional::<ExampleAttribute>("cas", "eav").unwrap();
+        let (cas, eav) = (storage.cas().clone(), storage.eav().clone());
+        TransactionTestSuite::test_commit_and_rollback(storage, cas, eav);
+    }
+
+    #[test]
+    fn failed_commit_writes_nothing() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        let mut storage = env.transactional::<ExampleAttribute>("cas", "eav").unwrap();
+
+        let content = Content::from(RawString::from("foo"));
+        // entity addresses end up in the key, which LMDB limits to 511 bytes
+        let too_long = Address::from("x".repeat(600));
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &too_long,
+            &ExampleAttribute::default(),
+            &content.address(),
+            1,
+        )
+        .unwrap();
+
+        let mut txn = storage.begin();
+        txn.add_content(&content).add_eavi(&eavi);
+        match txn.commit() {
+            Err(PersistenceError::Backend(context)) => assert_eq!("commit", context.operation),
+            result => panic!("expected a backend error, got {:?}", result),
+        }
+
+        // the content staged before the failing eavi was not written either
+        assert_eq!(Ok(false), storage.cas().contains(&content.address()));
+        assert_eq!(
+            0,
+            storage
+                .eav()
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn commits_to_read_only_stores_are_refused() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        let writable = env.transactional::<ExampleAttribute>("cas", "eav").unwrap();
+        // shares the environment opened for writing above
+        let read_only = LmdbEnvironment::new_read_only(dir.path(), None).unwrap();
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &Address::from("entity"),
+            &ExampleAttribute::default(),
+            &Address::from("value"),
+            1,
+        )
+        .unwrap();
+
+        for storage in &mut [
+            LmdbTransactionalStorage::new(writable.cas().clone(), read_only.eav("eav").unwrap())
+                .unwrap(),
+            LmdbTransactionalStorage::new(read_only.cas("cas").unwrap(), writable.eav().clone())
+                .unwrap(),
+        ] {
+            let mut txn = storage.begin();
+            txn.add_content(&Content::from(RawString::from("foo")))
+                .add_eavi(&eavi);
+            match txn.commit() {
+                Err(PersistenceError::ReadOnlyError(_)) => (),
+                result => panic!("expected a read-only error, got {:?}", result),
+            }
+        }
+        assert_eq!(
+            0,
+            writable
+                .eav()
+                .fetch_eavi(&EaviQuery::default())
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn committed_eavis_reach_live_queries() {
+        let dir = tempdir().unwrap();
+        let env = LmdbEnvironment::new(dir.path(), None, None);
+        let mut storage = env.transactional::<ExampleAttribute>("cas", "eav").unwrap();
+        let live = LiveEavStorage::new(storage.eav().clone());
+        let query = live.subscribe(&EaviQuerySpec::range(None, None)).unwrap();
+        assert!(query.changes().try_recv().unwrap().is_empty());
+
+        let eavi = EntityAttributeValueIndex::new_with_index(
+            &Address::from("entity"),
+            &ExampleAttribute::default(),
+            &Address::from("value"),
+            1,
+        )
+        .unwrap();
+        let mut txn = storage.begin();
+        txn.add_eavi(&eavi);
+        txn.commit().unwrap();
+        assert_eq!(
+            vec![eavi],
+            query
+                .changes()
+                .try_recv()
+                .unwrap()
+                .added
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn stores_must_share_an_environment() {
+        let (cas_dir, eav_dir) = (tempdir().unwrap(), tempdir().unwrap());
+        let cas = LmdbStorage::new(cas_dir.path(), None);
+        let eav = EavLmdbStorage::<ExampleAttribute>::new(eav_dir.path(), None);
+        assert!(LmdbTransactionalStorage::new(cas, eav).is_err());
+    }
+}
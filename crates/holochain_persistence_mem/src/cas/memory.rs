@@ -2,20 +2,26 @@ use holochain_persistence_api::{
     cas::{
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
+        ttl::{expires_at, is_expired, SystemTimeSource, TimeSource},
     },
-    error::PersistenceResult,
+    error::{InBackend, PersistenceResult},
     reporting::ReportStorage,
 };
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 use uuid::Uuid;
 
+const BACKEND: &str = "mem";
+
 #[derive(Clone, Debug)]
 pub struct MemoryStorage {
     storage: Arc<RwLock<HashMap<Address, Content>>>,
+    expiries: Arc<RwLock<HashMap<Address, SystemTime>>>,
+    time_source: Arc<dyn TimeSource>,
     id: Uuid,
 }
 
@@ -28,6 +34,8 @@ impl Default for MemoryStorage {
     fn default() -> MemoryStorage {
         MemoryStorage {
             storage: Arc::new(RwLock::new(HashMap::new())),
+            expiries: Arc::new(RwLock::new(HashMap::new())),
+            time_source: Arc::new(SystemTimeSource),
             id: Uuid::new_v4(),
         }
     }
@@ -37,25 +45,75 @@ impl MemoryStorage {
     pub fn new() -> MemoryStorage {
         Default::default()
     }
+
+    /// replaces the time source used to expire content added with a ttl
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> MemoryStorage {
+        self.time_source = time_source;
+        self
+    }
 }
 
 impl ContentAddressableStorage for MemoryStorage {
     fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
-        let mut map = self.storage.write()?;
+        let mut map = self.storage.write().in_backend(BACKEND, "add")?;
+        let mut expiries = self.expiries.write().in_backend(BACKEND, "add")?;
         map.insert(content.address(), content.content());
+        expiries.remove(&content.address());
         Ok(())
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
-        let map = self.storage.read()?;
+        let map = self.storage.read().in_backend(BACKEND, "contains")?;
         Ok(map.contains_key(address))
     }
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
-        let map = self.storage.read()?;
+        let map = self.storage.read().in_backend(BACKEND, "fetch")?;
         Ok(map.get(address).cloned())
     }
 
+    fn add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> PersistenceResult<()> {
+        let mut map = self.storage.write().in_backend(BACKEND, "add_with_ttl")?;
+        let mut expiries = self.expiries.write().in_backend(BACKEND, "add_with_ttl")?;
+        let address = content.address();
+        if map.contains_key(&address) && !expiries.contains_key(&address) {
+            // already stored without a ttl so it must not expire
+            return Ok(());
+        }
+        map.insert(address.clone(), content.content());
+        expiries.insert(address, expires_at(&*self.time_source, ttl));
+        Ok(())
+    }
+
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        let mut map = self.storage.write().in_backend(BACKEND, "sweep_expired")?;
+        let mut expiries = self.expiries.write().in_backend(BACKEND, "sweep_expired")?;
+        let expired: Vec<Address> = expiries
+            .iter()
+            .filter(|(_, expiry)| is_expired(&*self.time_source, **expiry))
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in expired.iter() {
+            map.remove(address);
+            expiries.remove(address);
+        }
+        Ok(expired.len())
+    }
+
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        Ok(self
+            .storage
+            .read()
+            .in_backend(BACKEND, "addresses")?
+            .keys()
+            .cloned()
+            .collect())
+    }
+
     fn get_id(&self) -> Uuid {
         self.id
     }
@@ -65,12 +123,19 @@ impl ReportStorage for MemoryStorage {}
 
 #[cfg(test)]
 pub mod tests {
-    use crate::cas::memory::MemoryStorage;
+    use crate::{cas::memory::MemoryStorage, eav::memory::EavMemoryStorage};
     use holochain_json_api::json::RawString;
-    use holochain_persistence_api::cas::{
-        content::{ExampleAddressableContent, OtherExampleAddressableContent},
-        storage::StorageTestSuite,
+    use holochain_persistence_api::{
+        cas::{
+            content::{ExampleAddressableContent, OtherExampleAddressableContent},
+            storage::StorageTestSuite,
+            ttl::MockTimeSource,
+        },
+        eav::ExampleAttribute,
+        txn::{JournalStorage, TransactionTestSuite},
     };
+    use std::sync::Arc;
+    use tempfile::tempdir;
 
     pub fn test_memory_storage() -> MemoryStorage {
         MemoryStorage::new()
@@ -84,4 +149,40 @@ pub mod tests {
             RawString::from("bar").into(),
         );
     }
+
+    #[test]
+    fn memory_addresses() {
+        StorageTestSuite::new(test_memory_storage())
+            .addresses_test(RawString::from("foo").into(), RawString::from("bar").into());
+    }
+
+    #[test]
+    fn memory_ttl() {
+        let time_source = MockTimeSource::default();
+        let cas = test_memory_storage().with_time_source(Arc::new(time_source.clone()));
+        StorageTestSuite::new(cas).ttl_test(
+            time_source,
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
+    #[test]
+    fn memory_transaction() {
+        let dir = tempdir().unwrap();
+        let storage = JournalStorage::new(
+            test_memory_storage(),
+            EavMemoryStorage::<ExampleAttribute>::new(),
+            dir.path().join("journal"),
+        )
+        .unwrap();
+        let (cas, eav) = (storage.cas().clone(), storage.eav().clone());
+        TransactionTestSuite::test_commit_and_rollback(storage, cas, eav);
+    }
+
+    #[test]
+    fn memory_id_test() {
+        let cas = test_memory_storage();
+        StorageTestSuite::new(cas.clone()).id_test(cas);
+    }
 }
@@ -1,22 +1,27 @@
 use holochain_persistence_api::{
     eav::{
-        increment_key_till_no_collision, Attribute, EaviQuery, EntityAttributeValueIndex,
-        EntityAttributeValueStorage,
+        count_by, Attribute, Clock, EaviPage, EaviQuery, Entity, EntityAttributeValueIndex,
+        EntityAttributeValueStorage, LiveRegistry, WallClock,
     },
-    error::PersistenceResult,
+    error::{InBackend, PersistenceResult},
     reporting::ReportStorage,
 };
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, RwLock},
 };
 
 use uuid::Uuid;
 
+const BACKEND: &str = "mem";
+
 #[derive(Clone, Debug)]
 pub struct EavMemoryStorage<A: Attribute> {
     storage: Arc<RwLock<BTreeSet<EntityAttributeValueIndex<A>>>>,
+    retracted: Arc<RwLock<BTreeSet<EntityAttributeValueIndex<A>>>>,
+    live: LiveRegistry<A>,
     id: Uuid,
+    clock: Arc<dyn Clock>,
 }
 
 impl<A: Attribute> PartialEq for EavMemoryStorage<A> {
@@ -29,7 +34,10 @@ impl<A: Attribute> Default for EavMemoryStorage<A> {
     fn default() -> EavMemoryStorage<A> {
         EavMemoryStorage {
             storage: Arc::new(RwLock::new(BTreeSet::new())),
+            retracted: Arc::new(RwLock::new(BTreeSet::new())),
+            live: LiveRegistry::new(),
             id: Uuid::new_v4(),
+            clock: Arc::new(WallClock),
         }
     }
 }
@@ -38,6 +46,33 @@ impl<A: Attribute> EavMemoryStorage<A> {
     pub fn new() -> EavMemoryStorage<A> {
         Default::default()
     }
+
+    /// replaces the clock that stamps new eavis, the wall clock by default
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// counts the rows the query selects in place, by a key of each
+    fn count_eavi_by<K: Ord, F>(
+        &self,
+        query: &EaviQuery<A>,
+        key: F,
+    ) -> PersistenceResult<BTreeMap<K, usize>>
+    where
+        A: Send + Sync,
+        F: Fn(&EntityAttributeValueIndex<A>) -> K,
+    {
+        if !query.can_stream() {
+            return count_by(self.fetch_eavi(query)?.into_iter().map(Ok), key);
+        }
+        let map = self.storage.read().in_backend(BACKEND, "count_eavi_by")?;
+        let retracted = self.retracted.read().in_backend(BACKEND, "count_eavi_by")?;
+        let selected = map.iter().filter(|eavi| {
+            query.retraction().keeps(retracted.contains(eavi)) && query.selects(eavi)
+        });
+        count_by(selected.map(Ok), key)
+    }
 }
 
 impl<A: Attribute> EntityAttributeValueStorage<A> for EavMemoryStorage<A>
@@ -48,19 +83,75 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
-        let mut map = self.storage.write()?;
-        let new_eav = increment_key_till_no_collision(eav.clone(), map.clone())?;
-        map.insert(new_eav.clone());
-        Ok(Some(new_eav))
+        // eavis are kept whole, so they only collide with themselves, @see resolve_collision
+        self.storage
+            .write()
+            .in_backend(BACKEND, "add_eavi")?
+            .insert(eav.clone());
+        self.clock.observe(eav.index());
+        self.live.update(self, eav);
+        Ok(Some(eav.clone()))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
-        let map = self.storage.read()?;
+        let page = self.fetch_eavi_page(query)?;
+        Ok(page.rows.into_iter().collect())
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        let map = self.storage.read().in_backend(BACKEND, "fetch_eavi_page")?;
+        let retracted = self
+            .retracted
+            .read()
+            .in_backend(BACKEND, "fetch_eavi_page")?;
         let iter = map.iter().cloned();
-        Ok(query.run(iter))
+        Ok(query.run_page_with_retractions(iter, |eavi| retracted.contains(eavi)))
+    }
+
+    fn count_eavi(&self, query: &EaviQuery<A>) -> PersistenceResult<usize> {
+        self.count_eavi_by(query, |_| ())
+            .map(|counts| counts.values().sum())
+    }
+
+    fn count_eavi_by_attribute(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<A, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::attribute)
+    }
+
+    fn count_eavi_by_entity(
+        &self,
+        query: &EaviQuery<A>,
+    ) -> PersistenceResult<BTreeMap<Entity, usize>> {
+        self.count_eavi_by(query, EntityAttributeValueIndex::entity)
+    }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        let retracted = {
+            let map = self.storage.read().in_backend(BACKEND, "retract_eavi")?;
+            let mut retracted = self.retracted.write().in_backend(BACKEND, "retract_eavi")?;
+            map.contains(eav) && retracted.insert(eav.clone())
+        };
+        if retracted {
+            self.live.update(self, eav);
+        }
+        Ok(retracted)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
     }
 }
 
@@ -75,8 +166,9 @@ pub mod tests {
             content::{AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::ExampleAttribute,
+        eav::{ExampleAttribute, LamportClock},
     };
+    use std::sync::Arc;
 
     #[test]
     fn memory_eav_round_trip() {
@@ -113,6 +205,13 @@ pub mod tests {
         >(eav_storage, &ExampleAttribute::default())
     }
 
+    #[test]
+    fn memory_eav_id() {
+        let eav_storage = EavMemoryStorage::new();
+        // memory storages can only be reopened through their clones
+        EavTestSuite::test_id(eav_storage.clone(), eav_storage, EavMemoryStorage::new());
+    }
+
     #[test]
     fn example_eav_range() {
         let eav_storage = EavMemoryStorage::new();
@@ -144,4 +243,74 @@ pub mod tests {
         let eav_storage = EavMemoryStorage::new();
         EavTestSuite::test_tombstone::<ExampleAddressableContent, EavMemoryStorage<_>>(eav_storage)
     }
+
+    #[test]
+    fn memory_retraction() {
+        EavTestSuite::test_retraction(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_pagination() {
+        EavTestSuite::test_pagination(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_fetch_iter() {
+        EavTestSuite::test_fetch_iter(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_counts() {
+        EavTestSuite::test_counts(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_key_filters() {
+        EavTestSuite::test_key_filters(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_traversal() {
+        EavTestSuite::test_traversal(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_join() {
+        EavTestSuite::test_join(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_range_tombstone() {
+        EavTestSuite::test_range_tombstone(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_source() {
+        EavTestSuite::test_source(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_clock() {
+        EavTestSuite::test_clock(EavMemoryStorage::new().with_clock(Arc::new(LamportClock::new())));
+    }
+
+    #[test]
+    fn memory_collisions() {
+        EavTestSuite::test_collisions(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_merge() {
+        EavTestSuite::test_merge(EavMemoryStorage::new(), EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_live() {
+        EavTestSuite::test_live(EavMemoryStorage::new());
+    }
+
+    #[test]
+    fn memory_as_of() {
+        EavTestSuite::test_as_of(EavMemoryStorage::new());
+    }
 }
@@ -0,0 +1,52 @@
+//! Registers the in memory stores under the `mem://` scheme.
+//! Every store opened from the registry is a new, empty store.
+
+use crate::{cas::memory::MemoryStorage, eav::memory::EavMemoryStorage};
+use holochain_persistence_api::{
+    cas::storage::ContentAddressableStorage,
+    eav::{Attribute, EntityAttributeValueStorage},
+    registry::{Registry, StoreConfig},
+};
+
+pub const SCHEME: &str = "mem";
+
+pub fn register<A>(registry: &mut Registry<A>)
+where
+    A: Attribute + Send + Sync + 'static,
+{
+    registry
+        .register_cas(SCHEME, |_: &StoreConfig| {
+            Ok(Box::new(MemoryStorage::new()) as Box<dyn ContentAddressableStorage>)
+        })
+        .register_eav(SCHEME, |_: &StoreConfig| {
+            Ok(Box::new(EavMemoryStorage::new()) as Box<dyn EntityAttributeValueStorage<A>>)
+        });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, Content},
+        eav::{EaviQuery, ExampleAttribute},
+    };
+
+    #[test]
+    fn memory_stores_open_from_uris() {
+        let mut registry = Registry::<ExampleAttribute>::new();
+        register(&mut registry);
+
+        let mut cas = registry.open_cas_uri("mem://").unwrap();
+        let content = Content::from(RawString::from("foo"));
+        cas.add(&content).unwrap();
+        assert_eq!(Ok(Some(content.clone())), cas.fetch(&content.address()));
+        // each open is a store of its own
+        let other = registry.open_cas_uri("mem://").unwrap();
+        assert_eq!(Ok(None), other.fetch(&content.address()));
+        assert_ne!(cas.get_id(), other.get_id());
+
+        let eav = registry.open_eav_uri("mem://").unwrap();
+        assert_eq!(0, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+    }
+}
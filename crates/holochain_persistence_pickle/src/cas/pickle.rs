@@ -1,15 +1,21 @@
-use holochain_json_api::error::JsonError;
 use holochain_persistence_api::{
     cas::{
         content::{Address, AddressableContent, Content},
         storage::ContentAddressableStorage,
+        ttl::{expires_at, from_millis, is_expired, to_millis, SystemTimeSource, TimeSource},
     },
-    error::PersistenceResult,
+    error::{InBackend, PersistenceError, PersistenceResult},
+    identity::{load_id_or_ephemeral, load_or_create_id},
     reporting::{ReportStorage, StorageReport},
 };
 
-use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use crate::common::{
+    corruption_error, load_error, load_or_create, load_read_only_or_empty, read_only_error,
+    write_error, BACKEND,
+};
+use pickledb::{PickleDb, SerializationMethod};
 use std::{
+    collections::BTreeSet,
     fmt::{Debug, Error, Formatter},
     path::Path,
     sync::{Arc, RwLock},
@@ -17,12 +23,20 @@ use std::{
 };
 use uuid::Uuid;
 
-const PERSISTENCE_INTERVAL: Duration = Duration::from_millis(5000);
-
 #[derive(Clone)]
 pub struct PickleStorage {
     id: Uuid,
     db: Arc<RwLock<PickleDb>>,
+    /// expiry times (in ms since the unix epoch) of content added with a ttl, keyed by address
+    ttl_db: Arc<RwLock<PickleDb>>,
+    time_source: Arc<dyn TimeSource>,
+    read_only: bool,
+}
+
+impl PartialEq for PickleStorage {
+    fn eq(&self, other: &PickleStorage) -> bool {
+        self.id == other.id
+    }
 }
 
 impl Debug for PickleStorage {
@@ -34,51 +48,143 @@ impl Debug for PickleStorage {
 }
 
 impl PickleStorage {
-    pub fn new<P: AsRef<Path> + Clone>(db_path: P) -> PickleStorage {
+    pub fn new<P: AsRef<Path> + Clone>(db_path: P) -> PersistenceResult<PickleStorage> {
+        let cas_db = db_path.as_ref().join("cas").with_extension("db");
+        let ttl_db = db_path.as_ref().join("cas_ttl").with_extension("db");
+        Ok(PickleStorage {
+            id: load_or_create_id(&db_path.as_ref().join("cas").with_extension("id"))
+                .in_backend(BACKEND, "new")?,
+            db: Arc::new(RwLock::new(load_or_create(cas_db))),
+            ttl_db: Arc::new(RwLock::new(load_or_create(ttl_db))),
+            time_source: Arc::new(SystemTimeSource),
+            read_only: false,
+        })
+    }
+
+    /// loads existing content that is never dumped back to disk
+    /// adding content returns a ReadOnlyError
+    pub fn new_read_only<P: AsRef<Path>>(db_path: P) -> PersistenceResult<PickleStorage> {
         let cas_db = db_path.as_ref().join("cas").with_extension("db");
-        PickleStorage {
-            id: Uuid::new_v4(),
-            db: Arc::new(RwLock::new(
-                PickleDb::load(
-                    cas_db.clone(),
-                    PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
-                    SerializationMethod::Cbor,
-                )
-                .unwrap_or_else(|_| {
-                    PickleDb::new(
-                        cas_db,
-                        PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
-                        SerializationMethod::Cbor,
-                    )
-                }),
-            )),
+        let ttl_db = db_path.as_ref().join("cas_ttl").with_extension("db");
+        let db = PickleDb::load_read_only(&cas_db, SerializationMethod::Cbor)
+            .map_err(|e| load_error(&cas_db, e))?;
+        // stores written before ttl support have no expiry times
+        let ttl_db = load_read_only_or_empty(ttl_db);
+        Ok(PickleStorage {
+            id: load_id_or_ephemeral(&db_path.as_ref().join("cas").with_extension("id"))
+                .in_backend(BACKEND, "new_read_only")?,
+            db: Arc::new(RwLock::new(db)),
+            ttl_db: Arc::new(RwLock::new(ttl_db)),
+            time_source: Arc::new(SystemTimeSource),
+            read_only: true,
+        })
+    }
+
+    fn check_writable(&self) -> PersistenceResult<()> {
+        if self.read_only {
+            Err(read_only_error("write", "CAS"))
+        } else {
+            Ok(())
         }
     }
+
+    /// replaces the time source used to expire content added with a ttl
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> PickleStorage {
+        self.time_source = time_source;
+        self
+    }
 }
 
 impl ContentAddressableStorage for PickleStorage {
     fn add(&mut self, content: &dyn AddressableContent) -> PersistenceResult<()> {
-        let mut inner = self.db.write().unwrap();
+        self.check_writable()?;
+        let mut inner = self.db.write().in_backend(BACKEND, "add")?;
+        let mut ttl_inner = self.ttl_db.write().in_backend(BACKEND, "add")?;
 
+        let address = content.address();
+        let key = address.to_string();
         inner
-            .set(&content.address().to_string(), &content.content())
-            .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
+            .set(&key, &content.content())
+            .map_err(|e| write_error("add", &address, e))?;
+        if ttl_inner.exists(&key) {
+            ttl_inner
+                .rem(&key)
+                .map_err(|e| write_error("add", &address, e))?;
+        }
 
         Ok(())
     }
 
     fn contains(&self, address: &Address) -> PersistenceResult<bool> {
-        let inner = self.db.read().unwrap();
+        let inner = self.db.read().in_backend(BACKEND, "contains")?;
 
         Ok(inner.exists(&address.to_string()))
     }
 
     fn fetch(&self, address: &Address) -> PersistenceResult<Option<Content>> {
-        let inner = self.db.read().unwrap();
+        let inner = self.db.read().in_backend(BACKEND, "fetch")?;
 
         Ok(inner.get(&address.to_string()))
     }
 
+    fn add_with_ttl(
+        &mut self,
+        content: &dyn AddressableContent,
+        ttl: Duration,
+    ) -> PersistenceResult<()> {
+        self.check_writable()?;
+        let mut inner = self.db.write().in_backend(BACKEND, "add_with_ttl")?;
+        let mut ttl_inner = self.ttl_db.write().in_backend(BACKEND, "add_with_ttl")?;
+
+        let address = content.address();
+        let key = address.to_string();
+        if inner.exists(&key) && !ttl_inner.exists(&key) {
+            // already stored without a ttl so it must not expire
+            return Ok(());
+        }
+        inner
+            .set(&key, &content.content())
+            .map_err(|e| write_error("add_with_ttl", &address, e))?;
+        ttl_inner
+            .set(&key, &to_millis(expires_at(&*self.time_source, ttl)))
+            .map_err(|e| write_error("add_with_ttl", &address, e))?;
+
+        Ok(())
+    }
+
+    fn sweep_expired(&mut self) -> PersistenceResult<usize> {
+        self.check_writable()?;
+        let mut inner = self.db.write().in_backend(BACKEND, "sweep_expired")?;
+        let mut ttl_inner = self.ttl_db.write().in_backend(BACKEND, "sweep_expired")?;
+
+        let expired: Vec<String> = ttl_inner
+            .iter()
+            .filter(|kv| {
+                kv.get_value::<u64>()
+                    .map(|expiry| is_expired(&*self.time_source, from_millis(expiry)))
+                    .unwrap_or_default()
+            })
+            .map(|kv| kv.get_key().to_string())
+            .collect();
+        for key in expired.iter() {
+            let address = Address::from(key.clone());
+            inner
+                .rem(key)
+                .map_err(|e| write_error("sweep", &address, e))?;
+            ttl_inner
+                .rem(key)
+                .map_err(|e| write_error("sweep", &address, e))?;
+        }
+
+        Ok(expired.len())
+    }
+
+    fn addresses(&self) -> PersistenceResult<BTreeSet<Address>> {
+        let inner = self.db.read().in_backend(BACKEND, "addresses")?;
+
+        Ok(inner.get_all().into_iter().map(Address::from).collect())
+    }
+
     fn get_id(&self) -> Uuid {
         self.id
     }
@@ -86,31 +192,37 @@ impl ContentAddressableStorage for PickleStorage {
 
 impl ReportStorage for PickleStorage {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
-        let db = self.db.read()?;
-        let bytes_total = db.iter().fold(0, |total_bytes, kv| {
-            let value = kv.get_value::<Content>().unwrap();
-            total_bytes + value.to_string().bytes().len()
-        });
+        let db = self.db.read().in_backend(BACKEND, "get_storage_report")?;
+        let bytes_total = db.iter().try_fold(0, |total_bytes, kv| {
+            let value = kv
+                .get_value::<Content>()
+                .ok_or_else(|| corruption_error("get_storage_report", kv.get_key()))?;
+            Ok::<_, PersistenceError>(total_bytes + value.to_string().len())
+        })?;
         Ok(StorageReport::new(bytes_total))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cas::pickle::PickleStorage;
+    use crate::{cas::pickle::PickleStorage, eav::pickle::EavPickleStorage};
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
             content::{Content, ExampleAddressableContent, OtherExampleAddressableContent},
             storage::{CasBencher, ContentAddressableStorage, StorageTestSuite},
+            ttl::MockTimeSource,
         },
+        eav::ExampleAttribute,
         reporting::{ReportStorage, StorageReport},
+        txn::{JournalStorage, TransactionTestSuite},
     };
+    use std::sync::Arc;
     use tempfile::{tempdir, TempDir};
 
     pub fn test_pickle_cas() -> (PickleStorage, TempDir) {
         let dir = tempdir().expect("Could not create a tempdir for CAS testing");
-        (PickleStorage::new(dir.path()), dir)
+        (PickleStorage::new(dir.path()).unwrap(), dir)
     }
 
     #[bench]
@@ -137,6 +249,25 @@ mod tests {
         );
     }
 
+    #[test]
+    fn pickle_addresses_test() {
+        let (cas, _dir) = test_pickle_cas();
+        StorageTestSuite::new(cas)
+            .addresses_test(RawString::from("foo").into(), RawString::from("bar").into());
+    }
+
+    #[test]
+    fn pickle_ttl_test() {
+        let (cas, _dir) = test_pickle_cas();
+        let time_source = MockTimeSource::default();
+        let cas = cas.with_time_source(Arc::new(time_source.clone()));
+        StorageTestSuite::new(cas).ttl_test(
+            time_source,
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+        );
+    }
+
     #[test]
     fn pickle_report_storage_test() {
         let (mut cas, _) = test_pickle_cas();
@@ -153,4 +284,50 @@ mod tests {
             StorageReport::new(10 + 10),
         );
     }
+
+    #[test]
+    fn pickle_transaction() {
+        let (cas, dir) = test_pickle_cas();
+        let storage = JournalStorage::new(
+            cas,
+            EavPickleStorage::<ExampleAttribute>::new(dir.path()).unwrap(),
+            dir.path().join("journal"),
+        )
+        .unwrap();
+        let (cas, eav) = (storage.cas().clone(), storage.eav().clone());
+        TransactionTestSuite::test_commit_and_rollback(storage, cas, eav);
+    }
+
+    #[test]
+    fn pickle_read_only_test() {
+        let (cas, dir) = test_pickle_cas();
+        StorageTestSuite::new(cas).read_only_test(
+            RawString::from("foo").into(),
+            RawString::from("bar").into(),
+            || PickleStorage::new_read_only(dir.path()).unwrap(),
+        );
+        assert!(PickleStorage::new_read_only(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn pickle_id_test() {
+        let (cas, dir) = test_pickle_cas();
+        let (other, _other_dir) = test_pickle_cas();
+        assert_ne!(cas.get_id(), other.get_id());
+        let id = cas.get_id();
+        StorageTestSuite::new(cas).id_test(PickleStorage::new(dir.path()).unwrap());
+        assert_eq!(
+            id,
+            PickleStorage::new_read_only(dir.path()).unwrap().get_id()
+        );
+    }
+
+    #[test]
+    fn pickle_id_that_cannot_be_persisted_is_an_error() {
+        let dir = tempdir().unwrap();
+        // the id would have to be kept inside a file
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(PickleStorage::new(&file).is_err());
+    }
 }
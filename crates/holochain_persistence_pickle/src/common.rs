@@ -0,0 +1,86 @@
+use holochain_persistence_api::{
+    cas::content::Address,
+    error::{ErrorContext, PersistenceError},
+};
+use pickledb::{
+    error::{Error as PickleError, ErrorType},
+    PickleDb, PickleDbDumpPolicy, SerializationMethod,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub(crate) const BACKEND: &str = "pickle";
+const PERSISTENCE_INTERVAL: Duration = Duration::from_millis(5000);
+
+/// loads the database, or creates an empty one if there is none, dumping it periodically
+pub(crate) fn load_or_create(db_file: PathBuf) -> PickleDb {
+    PickleDb::load(
+        db_file.clone(),
+        PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
+        SerializationMethod::Cbor,
+    )
+    .unwrap_or_else(|_| {
+        PickleDb::new(
+            db_file,
+            PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_INTERVAL),
+            SerializationMethod::Cbor,
+        )
+    })
+}
+
+/// loads an auxiliary database that is never dumped, which is empty for stores written
+/// before it existed
+pub(crate) fn load_read_only_or_empty(db_file: PathBuf) -> PickleDb {
+    PickleDb::load_read_only(db_file.clone(), SerializationMethod::Cbor).unwrap_or_else(|_| {
+        PickleDb::new(
+            db_file,
+            PickleDbDumpPolicy::NeverDump,
+            SerializationMethod::Cbor,
+        )
+    })
+}
+
+/// maps a failure to load the database at the given path onto a PersistenceError
+pub(crate) fn load_error(path: &Path, error: PickleError) -> PersistenceError {
+    let context = ErrorContext::new(BACKEND, "open").with_message(format!(
+        "could not load {}: {}",
+        path.display(),
+        error
+    ));
+    match error.get_type() {
+        ErrorType::Io if !path.exists() => PersistenceError::NotFound(context),
+        ErrorType::Io => PersistenceError::Backend(context),
+        ErrorType::Serialization => PersistenceError::Corruption(context),
+    }
+}
+
+/// the error for a write to a store opened read-only
+pub(crate) fn read_only_error(operation: &str, store: &str) -> PersistenceError {
+    PersistenceError::ReadOnlyError(
+        ErrorContext::new(BACKEND, operation).with_message(format!("{} is read-only", store)),
+    )
+}
+
+/// the error for a stored value that cannot be read back as what was written
+pub(crate) fn corruption_error(operation: &str, key: &str) -> PersistenceError {
+    PersistenceError::Corruption(
+        ErrorContext::new(BACKEND, operation).with_message(format!("could not read {}", key)),
+    )
+}
+
+/// maps a failure to write the given address onto a PersistenceError
+pub(crate) fn write_error(
+    operation: &str,
+    address: &Address,
+    error: PickleError,
+) -> PersistenceError {
+    let context = ErrorContext::new(BACKEND, operation)
+        .with_address(address)
+        .with_message(error.to_string());
+    match error.get_type() {
+        ErrorType::Io => PersistenceError::Backend(context),
+        ErrorType::Serialization => PersistenceError::Corruption(context),
+    }
+}
@@ -1,51 +1,89 @@
-use holochain_json_api::error::JsonError;
 use holochain_persistence_api::{
     cas::content::AddressableContent,
-    eav::{Attribute, EaviQuery, EntityAttributeValueIndex, EntityAttributeValueStorage},
-    error::PersistenceResult,
+    eav::{
+        resolve_collision, Attribute, Clock, EaviIter, EaviPage, EaviQuery,
+        EntityAttributeValueIndex, EntityAttributeValueStorage, LiveRegistry, Placement, Slot,
+        WallClock,
+    },
+    error::{InBackend, PersistenceError, PersistenceResult},
+    identity::{load_id_or_ephemeral, load_or_create_id},
     reporting::{ReportStorage, StorageReport},
 };
 
-use pickledb::{PickleDb, PickleDbDumpPolicy, SerializationMethod};
+use crate::common::{
+    corruption_error, load_error, load_or_create, load_read_only_or_empty, read_only_error,
+    write_error, BACKEND,
+};
+use pickledb::{PickleDb, SerializationMethod};
 use std::{
     collections::BTreeSet,
     fmt::{Debug, Error, Formatter},
     marker::{PhantomData, Send, Sync},
     path::Path,
     sync::{Arc, RwLock},
-    time::Duration,
 };
 use uuid::Uuid;
-const PERSISTENCE_PERIODICITY_MS: Duration = Duration::from_millis(5000);
 
 #[derive(Clone)]
 pub struct EavPickleStorage<A: Attribute> {
     db: Arc<RwLock<PickleDb>>,
+    /// retracted eavis, keyed by index like the eavis themselves
+    retracted_db: Arc<RwLock<PickleDb>>,
     id: Uuid,
     attribute: PhantomData<A>,
+    read_only: bool,
+    live: LiveRegistry<A>,
+    clock: Arc<dyn Clock>,
 }
 
 impl<A: Attribute> EavPickleStorage<A> {
-    pub fn new<P: AsRef<Path> + Clone>(db_path: P) -> EavPickleStorage<A> {
+    pub fn new<P: AsRef<Path> + Clone>(db_path: P) -> PersistenceResult<EavPickleStorage<A>> {
         let eav_db = db_path.as_ref().join("eav").with_extension("db");
-        EavPickleStorage {
-            id: Uuid::new_v4(),
-            db: Arc::new(RwLock::new(
-                PickleDb::load(
-                    eav_db.clone(),
-                    PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_PERIODICITY_MS),
-                    SerializationMethod::Cbor,
-                )
-                .unwrap_or_else(|_| {
-                    PickleDb::new(
-                        eav_db,
-                        PickleDbDumpPolicy::PeriodicDump(PERSISTENCE_PERIODICITY_MS),
-                        SerializationMethod::Cbor,
-                    )
-                }),
-            )),
+        let retracted_db = db_path.as_ref().join("eav_retracted").with_extension("db");
+        Ok(EavPickleStorage {
+            id: load_or_create_id(&db_path.as_ref().join("eav").with_extension("id"))
+                .in_backend(BACKEND, "new")?,
+            db: Arc::new(RwLock::new(load_or_create(eav_db))),
+            retracted_db: Arc::new(RwLock::new(load_or_create(retracted_db))),
             attribute: PhantomData,
-        }
+            read_only: false,
+            live: LiveRegistry::new(),
+            clock: Arc::new(WallClock),
+        })
+    }
+
+    /// loads existing metadata that is never dumped back to disk
+    /// adding or retracting eavis returns a ReadOnlyError
+    pub fn new_read_only<P: AsRef<Path>>(db_path: P) -> PersistenceResult<EavPickleStorage<A>> {
+        let eav_db = db_path.as_ref().join("eav").with_extension("db");
+        let retracted_db = db_path.as_ref().join("eav_retracted").with_extension("db");
+        let db = PickleDb::load_read_only(&eav_db, SerializationMethod::Cbor)
+            .map_err(|e| load_error(&eav_db, e))?;
+        Ok(EavPickleStorage {
+            id: load_id_or_ephemeral(&db_path.as_ref().join("eav").with_extension("id"))
+                .in_backend(BACKEND, "new_read_only")?,
+            db: Arc::new(RwLock::new(db)),
+            // stores written before retraction support have no retractions
+            retracted_db: Arc::new(RwLock::new(load_read_only_or_empty(retracted_db))),
+            attribute: PhantomData,
+            read_only: true,
+            live: LiveRegistry::new(),
+            clock: Arc::new(WallClock),
+        })
+    }
+
+    /// replaces the clock that stamps new eavis, the wall clock by default
+    /// a logical clock should start after the indexes already stored, @see
+    /// LamportClock::starting_after
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl<A: Attribute> PartialEq for EavPickleStorage<A> {
+    fn eq(&self, other: &EavPickleStorage<A>) -> bool {
+        self.id == other.id
     }
 }
 
@@ -65,40 +103,161 @@ where
         &mut self,
         eav: &EntityAttributeValueIndex<A>,
     ) -> PersistenceResult<Option<EntityAttributeValueIndex<A>>> {
-        let mut inner = self.db.write().unwrap();
-
-        //hate to introduce mutability but it is saved by the immutable clones at the end
-        let mut index_str = eav.index().to_string();
-        let mut value = inner.get::<EntityAttributeValueIndex<A>>(&index_str);
-        let mut new_eav = eav.clone();
-        while value.is_some() {
-            new_eav =
-                EntityAttributeValueIndex::new(&eav.entity(), &eav.attribute(), &eav.value())?;
-            index_str = new_eav.index().to_string();
-            value = inner.get::<EntityAttributeValueIndex<A>>(&index_str);
+        if self.read_only {
+            return Err(read_only_error("add_eavi", "EAV"));
+        }
+        let mut inner = self.db.write().in_backend(BACKEND, "add_eavi")?;
+        let added = stored_key(&inner, eav).is_none();
+        if added {
+            let placement = resolve_collision::<_, PersistenceError, _>(
+                vec![row_key(eav), collision_key(eav)],
+                |key| {
+                    let stored = inner.get::<EntityAttributeValueIndex<A>>(key);
+                    Ok(Slot::of(stored.as_ref(), eav))
+                },
+            )?;
+            if let Placement::Add(key) = placement {
+                inner
+                    .set(&key, eav)
+                    .map_err(|e| write_error("add_eavi", &eav.address(), e))?;
+            }
         }
-        inner
-            .set(&*index_str, &new_eav)
-            .map_err(|e| JsonError::ErrorGeneric(e.to_string()))?;
-        Ok(Some(new_eav))
+        drop(inner);
+        self.clock.observe(eav.index());
+        if added {
+            self.live.update(self, eav);
+        }
+        Ok(Some(eav.clone()))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
     }
 
     fn fetch_eavi(
         &self,
         query: &EaviQuery<A>,
     ) -> PersistenceResult<BTreeSet<EntityAttributeValueIndex<A>>> {
-        let inner = self.db.read()?;
+        let page = self.fetch_eavi_page(query)?;
+        Ok(page.rows.into_iter().collect())
+    }
+
+    fn fetch_eavi_page(&self, query: &EaviQuery<A>) -> PersistenceResult<EaviPage<A>> {
+        let inner = self.db.read().in_backend(BACKEND, "fetch_eavi_page")?;
+
+        let retracted = self
+            .retracted_db
+            .read()
+            .in_backend(BACKEND, "fetch_eavi_page")?;
 
         //this not too bad because it is lazy evaluated
         let entries = inner
             .iter()
-            .map(|item| item.get_value())
-            .filter(|filter| filter.is_some())
-            .map(|y| y.unwrap())
+            .filter_map(|item| item.get_value())
             .collect::<BTreeSet<EntityAttributeValueIndex<A>>>();
         let entries_iter = entries.iter().cloned();
-        Ok(query.run(entries_iter))
+        Ok(query
+            .run_page_with_retractions(entries_iter, |eavi| stored_key(&retracted, eavi).is_some()))
+    }
+
+    fn fetch_eavi_iter<'s>(
+        &'s self,
+        query: &'s EaviQuery<A>,
+    ) -> PersistenceResult<EaviIter<'s, A>> {
+        if !query.can_stream() {
+            let page = self.fetch_eavi_page(query)?;
+            return Ok(Box::new(page.rows.into_iter().map(Ok)));
+        }
+        // only the keys are read up front, each eavi is deserialized when it is reached
+        let keys = self
+            .db
+            .read()
+            .in_backend(BACKEND, "fetch_eavi_iter")?
+            .get_all();
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            let eavi = match self.db.read() {
+                Ok(db) => db.get::<EntityAttributeValueIndex<A>>(&key)?,
+                Err(e) => {
+                    return Some(Err(
+                        PersistenceError::from(e).in_backend(BACKEND, "fetch_eavi_iter")
+                    ))
+                }
+            };
+            let retracted = match self.retracted_db.read() {
+                Ok(retracted) => {
+                    retracted.get::<EntityAttributeValueIndex<A>>(&key) == Some(eavi.clone())
+                }
+                Err(e) => {
+                    return Some(Err(
+                        PersistenceError::from(e).in_backend(BACKEND, "fetch_eavi_iter")
+                    ))
+                }
+            };
+            if query.retraction().keeps(retracted) && query.selects(&eavi) {
+                Some(Ok(eavi))
+            } else {
+                None
+            }
+        })))
     }
+
+    fn retract_eavi(&mut self, eav: &EntityAttributeValueIndex<A>) -> PersistenceResult<bool> {
+        if self.read_only {
+            return Err(read_only_error("retract_eavi", "EAV"));
+        }
+        let inner = self.db.read().in_backend(BACKEND, "retract_eavi")?;
+        let mut retracted = self
+            .retracted_db
+            .write()
+            .in_backend(BACKEND, "retract_eavi")?;
+        let key = match stored_key(&inner, eav) {
+            Some(key) if !retracted.exists(&key) => key,
+            _ => return Ok(false),
+        };
+        retracted
+            .set(&key, eav)
+            .map_err(|e| write_error("retract_eavi", &eav.address(), e))?;
+        drop(retracted);
+        drop(inner);
+        self.live.update(self, eav);
+        Ok(true)
+    }
+
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn live_registry(&self) -> Option<&LiveRegistry<A>> {
+        Some(&self.live)
+    }
+}
+
+/// eavis are keyed by entity and index, @see resolve_collision
+fn row_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> String {
+    format!("{}::{}", eavi.entity(), eavi.index())
+}
+
+/// the key of an eavi added once another eavi had its entity and index
+fn collision_key<A>(eavi: &EntityAttributeValueIndex<A>) -> String
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    format!("{}::{}::{}", eavi.entity(), eavi.index(), eavi.address())
+}
+
+/// stores written before that keyed eavis by index alone, so those keys are still read
+fn legacy_key<A: Attribute>(eavi: &EntityAttributeValueIndex<A>) -> String {
+    eavi.index().to_string()
+}
+
+/// the key the eavi is stored under, if it is stored
+fn stored_key<A>(db: &PickleDb, eavi: &EntityAttributeValueIndex<A>) -> Option<String>
+where
+    A: Attribute + serde::de::DeserializeOwned,
+{
+    vec![row_key(eavi), collision_key(eavi), legacy_key(eavi)]
+        .into_iter()
+        .find(|key| db.get::<EntityAttributeValueIndex<A>>(key).as_ref() == Some(eavi))
 }
 
 impl<A: Attribute> ReportStorage for EavPickleStorage<A>
@@ -106,32 +265,38 @@ where
     A: Sync + Send + serde::de::DeserializeOwned,
 {
     fn get_storage_report(&self) -> PersistenceResult<StorageReport> {
-        let db = self.db.read()?;
-        let total_bytes = db.iter().fold(0, |total_bytes, kv| {
-            let value = kv.get_value::<EntityAttributeValueIndex<A>>().unwrap();
-            total_bytes + value.content().to_string().bytes().len()
-        });
+        let db = self.db.read().in_backend(BACKEND, "get_storage_report")?;
+        let total_bytes = db.iter().try_fold(0, |total_bytes, kv| {
+            let value = kv
+                .get_value::<EntityAttributeValueIndex<A>>()
+                .ok_or_else(|| corruption_error("get_storage_report", kv.get_key()))?;
+            Ok::<_, PersistenceError>(total_bytes + value.content().to_string().len())
+        })?;
         Ok(StorageReport::new(total_bytes))
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::eav::pickle::EavPickleStorage;
+    use crate::eav::pickle::{legacy_key, EavPickleStorage};
     use holochain_json_api::json::RawString;
     use holochain_persistence_api::{
         cas::{
             content::{AddressableContent, ExampleAddressableContent},
             storage::EavTestSuite,
         },
-        eav::{Attribute, EavBencher, ExampleAttribute},
+        eav::{
+            Attribute, EavBencher, EaviQuery, EntityAttributeValueIndex,
+            EntityAttributeValueStorage, ExampleAttribute, LamportClock, RetractionFilter,
+        },
     };
+    use std::sync::Arc;
     use tempfile::tempdir;
 
     fn new_store<A: Attribute>() -> EavPickleStorage<A> {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        EavPickleStorage::new(temp_path)
+        EavPickleStorage::new(temp_path).unwrap()
     }
 
     #[bench]
@@ -164,7 +329,7 @@ pub mod tests {
             ExampleAddressableContent::try_from_content(&RawString::from("blue").into()).unwrap();
 
         EavTestSuite::test_round_trip(
-            EavPickleStorage::new(temp_path),
+            EavPickleStorage::new(temp_path).unwrap(),
             entity_content,
             attribute,
             value_content,
@@ -175,7 +340,7 @@ pub mod tests {
     fn pickle_eav_one_to_many() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_one_to_many::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -187,7 +352,7 @@ pub mod tests {
     fn pickle_eav_many_to_one() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_many_to_one::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -199,7 +364,7 @@ pub mod tests {
     fn pickle_eav_range() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_range::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -211,7 +376,7 @@ pub mod tests {
     fn pickle_eav_prefixes() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_multiple_attributes::<
             ExampleAddressableContent,
             ExampleAttribute,
@@ -229,7 +394,164 @@ pub mod tests {
     fn pickle_tombstone() {
         let temp = tempdir().expect("test was supposed to create temp dir");
         let temp_path = String::from(temp.path().to_str().expect("temp dir could not be string"));
-        let eav_storage = EavPickleStorage::new(temp_path);
+        let eav_storage = EavPickleStorage::new(temp_path).unwrap();
         EavTestSuite::test_tombstone::<ExampleAddressableContent, EavPickleStorage<_>>(eav_storage)
     }
+
+    #[test]
+    fn pickle_eav_read_only() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_read_only(EavPickleStorage::new(temp.path()).unwrap(), || {
+            EavPickleStorage::new_read_only(temp.path()).unwrap()
+        });
+    }
+
+    #[test]
+    fn pickle_eav_id_is_persisted() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        let other = tempdir().expect("test was supposed to create temp dir");
+        let eav_storage: EavPickleStorage<ExampleAttribute> =
+            EavPickleStorage::new(temp.path()).unwrap();
+        assert!(eav_storage == EavPickleStorage::new(temp.path()).unwrap());
+        assert!(eav_storage != EavPickleStorage::new(other.path()).unwrap());
+        EavTestSuite::test_id(
+            eav_storage,
+            EavPickleStorage::new(temp.path()).unwrap(),
+            EavPickleStorage::new(other.path()).unwrap(),
+        );
+        assert!(
+            EavPickleStorage::<ExampleAttribute>::new(temp.path()).unwrap()
+                == EavPickleStorage::new_read_only(temp.path()).unwrap()
+        );
+        // the id would have to be kept inside a file
+        let file = temp.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(EavPickleStorage::<ExampleAttribute>::new(&file).is_err());
+    }
+
+    #[test]
+    fn pickle_eav_retraction() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_retraction(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_pagination() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_pagination(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_fetch_iter() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_fetch_iter(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_counts() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_counts(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_key_filters() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_key_filters(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_traversal() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_traversal(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_join() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_join(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_range_tombstone() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_range_tombstone(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_source() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_source(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_clock() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_clock(
+            EavPickleStorage::new(temp.path())
+                .unwrap()
+                .with_clock(Arc::new(LamportClock::new())),
+        );
+    }
+
+    #[test]
+    fn pickle_eav_collisions() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_collisions(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_merge() {
+        let a = tempdir().expect("test was supposed to create temp dir");
+        let b = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_merge(
+            EavPickleStorage::new(a.path()).unwrap(),
+            EavPickleStorage::new(b.path()).unwrap(),
+        );
+    }
+
+    #[test]
+    fn pickle_eav_live() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_live(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_as_of() {
+        let temp = tempdir().expect("test was supposed to create temp dir");
+        EavTestSuite::test_as_of(EavPickleStorage::new(temp.path()).unwrap());
+    }
+
+    #[test]
+    fn pickle_eav_reads_rows_keyed_by_index() {
+        let mut eav_storage: EavPickleStorage<ExampleAttribute> = new_store();
+        let row = |value: &str| {
+            EntityAttributeValueIndex::new_with_index(
+                &"e".into(),
+                &ExampleAttribute::default(),
+                &value.into(),
+                5,
+            )
+            .unwrap()
+        };
+        // as stores written before rows were keyed by entity have it
+        eav_storage
+            .db
+            .write()
+            .unwrap()
+            .set(&legacy_key(&row("a")), &row("a"))
+            .unwrap();
+
+        assert_eq!(Ok(Some(row("a"))), eav_storage.add_eavi(&row("a")));
+        assert_eq!(Ok(Some(row("b"))), eav_storage.add_eavi(&row("b")));
+        assert_eq!(Ok(true), eav_storage.retract_eavi(&row("a")));
+        let query = EaviQuery::default().with_retraction(RetractionFilter::Only);
+        assert_eq!(
+            vec![row("a")],
+            eav_storage
+                .fetch_eavi(&query)
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
 }
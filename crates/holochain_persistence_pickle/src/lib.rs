@@ -10,4 +10,7 @@
 extern crate test;
 
 pub mod cas;
+mod common;
 pub mod eav;
+#[cfg(feature = "registry")]
+pub mod registry;
@@ -0,0 +1,77 @@
+//! Registers the pickledb stores under the `pickle://` scheme.
+//! The URI path is the directory the database files are kept in, e.g. `pickle:///data`.
+//! Setting `read_only=true` opens an existing store with `new_read_only`.
+
+use crate::{cas::pickle::PickleStorage, eav::pickle::EavPickleStorage};
+use holochain_persistence_api::{
+    cas::storage::ContentAddressableStorage,
+    eav::{Attribute, EntityAttributeValueStorage},
+    registry::{Registry, StoreConfig},
+};
+
+pub const SCHEME: &str = "pickle";
+
+pub fn register<A>(registry: &mut Registry<A>)
+where
+    A: Attribute + Send + Sync + serde::de::DeserializeOwned + 'static,
+{
+    registry
+        .register_cas(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let cas = if config.read_only()? {
+                PickleStorage::new_read_only(path)?
+            } else {
+                PickleStorage::new(path)?
+            };
+            Ok(Box::new(cas) as Box<dyn ContentAddressableStorage>)
+        })
+        .register_eav(SCHEME, |config: &StoreConfig| {
+            let path = config.required_path()?;
+            let eav = if config.read_only()? {
+                EavPickleStorage::new_read_only(path)?
+            } else {
+                EavPickleStorage::new(path)?
+            };
+            Ok(Box::new(eav) as Box<dyn EntityAttributeValueStorage<A>>)
+        });
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use holochain_json_api::json::RawString;
+    use holochain_persistence_api::{
+        cas::content::{AddressableContent, Content},
+        eav::{EaviQuery, ExampleAttribute},
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn pickle_stores_open_from_uris() {
+        let dir = tempdir().unwrap();
+        let mut registry = Registry::<ExampleAttribute>::new();
+        register(&mut registry);
+        let config = StoreConfig::new(SCHEME).with_path(dir.path());
+
+        let content = Content::from(RawString::from("foo"));
+        let id = {
+            let mut cas = registry.open_cas(&config).unwrap();
+            cas.add(&content).unwrap();
+            let eav = registry.open_eav(&config).unwrap();
+            assert_eq!(0, eav.fetch_eavi(&EaviQuery::default()).unwrap().len());
+            cas.get_id()
+            // dropping the stores dumps them to disk
+        };
+
+        let read_only = registry
+            .open_cas(&config.clone().with_param("read_only", true))
+            .unwrap();
+        assert_eq!(id, read_only.get_id());
+        assert_eq!(
+            Ok(Some(content.clone())),
+            read_only.fetch(&content.address())
+        );
+
+        assert!(registry.open_eav_uri("pickle://").is_err());
+    }
+}